@@ -0,0 +1,734 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use poise::serenity_prelude::{self as serenity, UserId};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::OwnedRwLockReadGuard;
+
+use crate::{
+    metrics::Metrics,
+    money::Money,
+    notifications::{fire_alerts, notify_big_move},
+    prediction_market::{ApiToken, Market, MarketId, ShareKind, TransactionKind},
+    share_quantity::ShareQuantity,
+    shared_economy::{SharedEconomy, Update},
+    shutdown::Shutdown,
+    Economy,
+};
+
+/// Users listed by `/leaderboard` unless the request asks for a different number
+const DEFAULT_LEADERBOARD_SIZE: usize = 10;
+
+/// HTTP server with JSON endpoints for reading the economy, for dashboards and scripts, and for
+/// trading with a personal API token
+pub struct Api {
+    pub address: SocketAddr,
+    pub economy: Arc<SharedEconomy>,
+    pub http: Arc<serenity::Http>,
+    pub shutdown: Shutdown,
+    pub metrics: Metrics,
+    /// Authenticated requests each token can make per minute
+    pub requests_per_minute: u32,
+}
+
+#[derive(Clone)]
+struct ApiState {
+    economy: Arc<SharedEconomy>,
+    http: Arc<serenity::Http>,
+    shutdown: Shutdown,
+    metrics: Metrics,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl Api {
+    /// Start listening on the address, then serve in the background until the bot shuts down
+    pub async fn spawn(self) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind(self.address)
+            .await
+            .with_context(|| format!("failed listening on {}", self.address))?;
+        tracing::info!("serving the API on http://{}", self.address);
+        let router = Router::new()
+            .route("/markets", get(markets))
+            .route("/markets/{id}", get(market))
+            .route("/markets/{id}/history", get(market_history))
+            .route("/balances", get(balances))
+            .route("/users/{id}/portfolio", get(portfolio))
+            .route("/leaderboard", get(leaderboard))
+            .route("/metrics", get(metrics))
+            .route("/buy", post(buy))
+            .route("/sell", post(sell))
+            .route("/tip", post(tip))
+            .with_state(ApiState {
+                economy: self.economy,
+                http: self.http,
+                shutdown: self.shutdown.clone(),
+                metrics: self.metrics,
+                rate_limiter: Arc::new(RateLimiter::new(self.requests_per_minute)),
+            });
+        let shutdown = self.shutdown;
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, router)
+                .with_graceful_shutdown(async move { shutdown.started().await })
+                .await
+            {
+                tracing::error!("API server failed: {e}");
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Make a new API token for `user`, returning the token to give them and what to store
+pub fn generate_token(user: UserId) -> (String, ApiToken) {
+    // The thread RNG is cryptographically secure
+    let secret: [u8; 32] = rand::random();
+    let token = format!("{user}.{}", hex(&secret));
+    let stored = ApiToken {
+        hash: hash_token(&token),
+        created: Utc::now(),
+    };
+    (token, stored)
+}
+
+fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The user a token belongs to and the token's hash, if it's the user's current token
+fn verify_token(economy: &Economy, token: &str) -> Option<(UserId, String)> {
+    // Tokens start with their user's ID, so checking one is a single lookup
+    let user = token
+        .split_once('.')
+        .and_then(|(user, _)| user.parse::<NonZeroU64>().ok())
+        .map(UserId::from)?;
+    let hash = hash_token(token);
+    (economy.api_token(&user)?.hash == hash).then_some((user, hash))
+}
+
+/// Limits how often each token can make requests. Every token has an allowance that refills
+/// continuously up to a minute's worth of requests, so short bursts are fine.
+struct RateLimiter {
+    per_minute: u32,
+    allowances: Mutex<Allowances>,
+}
+
+struct Allowances {
+    /// Allowance left for each token hash, and when it was last updated
+    tokens: HashMap<String, (f64, Instant)>,
+    /// When full allowances were last dropped
+    pruned: Instant,
+}
+
+impl RateLimiter {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            allowances: Mutex::new(Allowances {
+                tokens: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Use up one request from the token's allowance, or say how long until one is available
+    fn check(&self, token_hash: &str) -> Result<(), Duration> {
+        self.check_at(token_hash, Instant::now())
+    }
+
+    fn check_at(&self, token_hash: &str, now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(self.per_minute);
+        let per_second = capacity / 60.0;
+        let mut allowances = self.allowances.lock().expect("rate limiter lock poisoned");
+        // An allowance that has refilled is the same as a missing one, so drop them once a minute
+        // to keep tokens that stopped being used from piling up
+        if now - allowances.pruned >= Duration::from_secs(60) {
+            allowances.tokens.retain(|_, (allowance, updated)| {
+                *allowance + (now - *updated).as_secs_f64() * per_second < capacity
+            });
+            allowances.pruned = now;
+        }
+        let (allowance, updated) = allowances
+            .tokens
+            .entry(token_hash.to_owned())
+            .or_insert((capacity, now));
+        *allowance = (*allowance + (now - *updated).as_secs_f64() * per_second).min(capacity);
+        *updated = now;
+        if *allowance >= 1.0 {
+            *allowance -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - *allowance) / per_second))
+        }
+    }
+}
+
+/// An error response, with a body like `{"error": "market 3 does not exist"}`
+struct ApiError {
+    status: StatusCode,
+    message: String,
+    /// Seconds to wait before trying again, for rate limited requests
+    retry_after: Option<u64>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, Json(json!({ "error": self.message }))).into_response();
+        if let Some(retry_after) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+        }
+        response
+    }
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    ApiError {
+        status,
+        message: message.into(),
+        retry_after: None,
+    }
+}
+
+fn not_found(message: String) -> ApiError {
+    error(StatusCode::NOT_FOUND, message)
+}
+
+/// An error from the economy, like a trade the user can't afford
+fn bad_request(e: anyhow::Error) -> ApiError {
+    error(StatusCode::BAD_REQUEST, format!("{e:#}"))
+}
+
+impl ApiState {
+    /// The user whose token is in the request's `Authorization: Bearer` header, if they haven't
+    /// used up their rate limit
+    fn authenticate(&self, headers: &HeaderMap) -> Result<UserId, ApiError> {
+        let unauthorized = || {
+            error(
+                StatusCode::UNAUTHORIZED,
+                "missing or invalid API token, get one with /api_token",
+            )
+        };
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(unauthorized)?;
+        let (user, hash) =
+            verify_token(&self.economy.snapshot(), token).ok_or_else(unauthorized)?;
+        self.rate_limiter
+            .check(&hash)
+            .map_err(|retry_after| ApiError {
+                retry_after: Some(retry_after.as_secs().max(1)),
+                ..error(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded")
+            })?;
+        Ok(user)
+    }
+
+    /// Mark a request as running, like a command, so shutdown waits for it to finish
+    async fn track_request(&self) -> Result<OwnedRwLockReadGuard<()>, ApiError> {
+        let guard = self.shutdown.track_command().await;
+        if self.shutdown.is_started() {
+            return Err(error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "the bot is shutting down, try again in a moment",
+            ));
+        }
+        Ok(guard)
+    }
+
+    /// Count a trade and tell watchers and alerts about it, like after a trading command
+    async fn after_trade(
+        &self,
+        user: UserId,
+        before: &Economy,
+        after: &Economy,
+        market: MarketId,
+        kind: TransactionKind,
+        money: Money,
+    ) {
+        self.metrics.record_trade(kind, money);
+        if let Err(e) =
+            notify_big_move(&self.http, &self.economy, before, after, market, user).await
+        {
+            tracing::warn!("failed notifying about a big move in market {market}: {e:#}");
+        }
+        fire_alerts(&self.http, &self.economy, market).await;
+    }
+}
+
+#[derive(Serialize)]
+struct MarketSummary {
+    id: MarketId,
+    question: String,
+    creator: UserId,
+    /// Percent chance of YES
+    probability: u8,
+    open: bool,
+    close_time: Option<DateTime<Utc>>,
+    creation_time: DateTime<Utc>,
+    /// Total money that changed hands in trades
+    volume: Money,
+    trades: usize,
+}
+
+impl From<&Market<UserId>> for MarketSummary {
+    fn from(market: &Market<UserId>) -> Self {
+        Self {
+            id: market.id,
+            question: market.question.clone(),
+            creator: market.creator,
+            probability: market.probability(),
+            open: market.is_open(),
+            close_time: market
+                .close_timestamp
+                .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0)),
+            creation_time: market.creation_time,
+            volume: Money(
+                market
+                    .transaction_history
+                    .iter()
+                    .map(|transaction| transaction.money.0)
+                    .sum(),
+            ),
+            trades: market.transaction_history.len(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MarketDetail {
+    #[serde(flatten)]
+    summary: MarketSummary,
+    description: String,
+    /// Shares held by the market maker
+    pool: Pool,
+    positions: Vec<Position>,
+}
+
+#[derive(Serialize)]
+struct Pool {
+    yes: ShareQuantity,
+    no: ShareQuantity,
+}
+
+#[derive(Serialize)]
+struct Position {
+    user: UserId,
+    kind: ShareKind,
+    shares: ShareQuantity,
+}
+
+#[derive(Serialize)]
+struct Transaction {
+    user: UserId,
+    kind: TransactionKind,
+    share_kind: ShareKind,
+    shares: ShareQuantity,
+    money: Money,
+    /// Percent chance of YES after the trade
+    probability: u8,
+    time: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct UserAmount {
+    user: UserId,
+    amount: Money,
+}
+
+#[derive(Serialize)]
+struct Portfolio {
+    user: UserId,
+    cash: Money,
+    positions: Vec<PortfolioPosition>,
+}
+
+#[derive(Serialize)]
+struct PortfolioPosition {
+    market: MarketId,
+    question: String,
+    kind: ShareKind,
+    shares: ShareQuantity,
+    /// What the shares would pay out if the market resolved at its current probability
+    value: Money,
+}
+
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    limit: Option<usize>,
+}
+
+/// Every unresolved market, newest first
+async fn markets(State(state): State<ApiState>) -> Json<Vec<MarketSummary>> {
+    let economy = state.economy.snapshot();
+    let mut markets = economy
+        .list_markets()
+        .map(MarketSummary::from)
+        .collect::<Vec<_>>();
+    markets.reverse();
+    Json(markets)
+}
+
+async fn market(
+    State(state): State<ApiState>,
+    Path(id): Path<MarketId>,
+) -> Result<Json<MarketDetail>, ApiError> {
+    let economy = state.economy.snapshot();
+    let market = economy
+        .market(id)
+        .map_err(|_| not_found(format!("market {id} does not exist")))?;
+    let (yes, no) = market.pool();
+    Ok(Json(MarketDetail {
+        summary: market.into(),
+        description: market.description.clone(),
+        pool: Pool { yes, no },
+        positions: market
+            .num_user_shares
+            .iter()
+            .map(|(user, shares)| Position {
+                user: *user,
+                kind: shares.kind,
+                shares: shares.quantity,
+            })
+            .collect(),
+    }))
+}
+
+/// Every trade in a market, oldest first
+async fn market_history(
+    State(state): State<ApiState>,
+    Path(id): Path<MarketId>,
+) -> Result<Json<Vec<Transaction>>, ApiError> {
+    let economy = state.economy.snapshot();
+    let market = economy
+        .market(id)
+        .map_err(|_| not_found(format!("market {id} does not exist")))?;
+    Ok(Json(
+        market
+            .transaction_history
+            .iter()
+            .map(|transaction| Transaction {
+                user: transaction.user,
+                kind: transaction.kind,
+                share_kind: transaction.shares.kind,
+                shares: transaction.shares.quantity,
+                money: transaction.money,
+                probability: transaction.new_probability,
+                time: transaction.time,
+            })
+            .collect(),
+    ))
+}
+
+/// Every user's money, richest first
+async fn balances(State(state): State<ApiState>) -> Json<Vec<UserAmount>> {
+    Json(
+        state
+            .economy
+            .snapshot()
+            .balances()
+            .into_iter()
+            .map(|(user, amount)| UserAmount { user, amount })
+            .collect(),
+    )
+}
+
+/// There is no server to take settings from, so a user new to the economy is shown the default
+/// start balance, like in a direct message with the bot
+async fn portfolio(State(state): State<ApiState>, Path(user): Path<NonZeroU64>) -> Json<Portfolio> {
+    let user = UserId::from(user);
+    let economy = state.economy.snapshot();
+    Json(Portfolio {
+        user,
+        cash: economy.balance(user, None),
+        positions: economy
+            .list_markets()
+            .filter_map(|market| {
+                let shares = market.num_user_shares.get(&user)?;
+                Some(PortfolioPosition {
+                    market: market.id,
+                    question: market.question.clone(),
+                    kind: shares.kind,
+                    shares: shares.quantity,
+                    value: Money(shares.quantity.0 * market.share_price(shares.kind)),
+                })
+            })
+            .collect(),
+    })
+}
+
+/// Everything in `Metrics`, for Prometheus to scrape
+async fn metrics(State(state): State<ApiState>) -> impl IntoResponse {
+    crate::metrics::response(&state.metrics, &state.economy.snapshot())
+}
+
+/// Users with the highest net worth, counting their shares at each market's current probability
+async fn leaderboard(
+    State(state): State<ApiState>,
+    Query(query): Query<LeaderboardQuery>,
+) -> Json<Vec<UserAmount>> {
+    Json(
+        state
+            .economy
+            .snapshot()
+            .net_worths()
+            .into_iter()
+            .take(query.limit.unwrap_or(DEFAULT_LEADERBOARD_SIZE))
+            .map(|(user, amount)| UserAmount { user, amount })
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+struct BuyRequest {
+    market: MarketId,
+    /// Money to spend
+    amount: f64,
+    kind: ShareKind,
+}
+
+#[derive(Deserialize)]
+struct SellRequest {
+    market: MarketId,
+    /// Shares to sell, or all of them if missing
+    shares: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct TipRequest {
+    to: UserId,
+    amount: f64,
+}
+
+#[derive(Serialize)]
+struct TradeResponse {
+    kind: ShareKind,
+    shares: ShareQuantity,
+    money: Money,
+    probability_before: u8,
+    probability_after: u8,
+    /// The trader's money after the trade
+    balance: Money,
+}
+
+#[derive(Serialize)]
+struct TipResponse {
+    balance: Money,
+}
+
+fn trade_response(
+    user: UserId,
+    before: &Economy,
+    after: &Economy,
+    market: MarketId,
+    kind: ShareKind,
+    shares: ShareQuantity,
+    money: Money,
+) -> Result<TradeResponse, ApiError> {
+    let after_market = after.market(market).map_err(bad_request)?;
+    Ok(TradeResponse {
+        kind,
+        shares,
+        money,
+        probability_before: before.market(market).map_err(bad_request)?.probability(),
+        probability_after: after_market.probability(),
+        balance: after.balance(user, after_market.guild),
+    })
+}
+
+async fn buy(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(request): Json<BuyRequest>,
+) -> Result<Json<TradeResponse>, ApiError> {
+    let user = state.authenticate(&headers)?;
+    let _running = state.track_request().await?;
+    let Update {
+        before,
+        after,
+        value: shares,
+    } = state
+        .economy
+        .update(|economy| economy.buy(user, request.market, Money(request.amount), request.kind))
+        .map_err(bad_request)?;
+    let response = trade_response(
+        user,
+        &before,
+        &after,
+        request.market,
+        request.kind,
+        shares,
+        Money(request.amount),
+    )?;
+    state
+        .after_trade(
+            user,
+            &before,
+            &after,
+            request.market,
+            TransactionKind::Buy,
+            Money(request.amount),
+        )
+        .await;
+    Ok(Json(response))
+}
+
+async fn sell(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(request): Json<SellRequest>,
+) -> Result<Json<TradeResponse>, ApiError> {
+    let user = state.authenticate(&headers)?;
+    let _running = state.track_request().await?;
+    let Update {
+        before,
+        after,
+        value: (shares, money),
+    } = state
+        .economy
+        .update(|economy| {
+            economy
+                .sell(user, request.market, request.shares.map(ShareQuantity))
+                .map(|(economy, shares, money)| (economy, (shares, money)))
+        })
+        .map_err(bad_request)?;
+    let response = trade_response(
+        user,
+        &before,
+        &after,
+        request.market,
+        shares.kind,
+        shares.quantity,
+        money,
+    )?;
+    state
+        .after_trade(
+            user,
+            &before,
+            &after,
+            request.market,
+            TransactionKind::Sell,
+            money,
+        )
+        .await;
+    Ok(Json(response))
+}
+
+async fn tip(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(request): Json<TipRequest>,
+) -> Result<Json<TipResponse>, ApiError> {
+    let user = state.authenticate(&headers)?;
+    let _running = state.track_request().await?;
+    // Like a tip in a direct message, users new to the economy start with the default balance
+    // rather than that of any server
+    let Update { after, .. } = state
+        .economy
+        .update(|economy| {
+            economy
+                .tip(user, request.to, Money(request.amount), None)
+                .map(|economy| (economy, ()))
+        })
+        .map_err(bad_request)?;
+    Ok(Json(TipResponse {
+        balance: after.balance(user, None),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_the_users_current_token() {
+        let user = UserId::new(42);
+        let (token, stored) = generate_token(user);
+        assert!(token.starts_with("42."));
+        assert_ne!(stored.hash, token);
+        let economy = Economy::new().set_api_token(user, stored);
+        assert_eq!(
+            verify_token(&economy, &token),
+            Some((user, hash_token(&token)))
+        );
+
+        let (other, _) = generate_token(user);
+        assert_eq!(verify_token(&economy, &other), None);
+        // Someone else's ID with this user's secret
+        let stolen = token.replacen("42.", "43.", 1);
+        assert_eq!(verify_token(&economy, &stolen), None);
+        for malformed in ["", "42", "0.abc", "me.abc"] {
+            assert_eq!(verify_token(&economy, malformed), None);
+        }
+
+        let (new_token, new_stored) = generate_token(user);
+        let economy = economy.set_api_token(user, new_stored);
+        assert_eq!(verify_token(&economy, &token), None);
+        assert!(verify_token(&economy, &new_token).is_some());
+        let economy = economy.revoke_api_token(&user).unwrap();
+        assert_eq!(verify_token(&economy, &new_token), None);
+    }
+
+    #[test]
+    fn limits_each_token_separately() {
+        let limiter = RateLimiter::new(3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at("a", start).is_ok());
+        }
+        let wait = limiter.check_at("a", start).unwrap_err();
+        // One request refills every 20 seconds
+        assert_eq!(wait.as_secs(), 20);
+        assert!(limiter.check_at("b", start).is_ok());
+        assert!(limiter
+            .check_at("a", start + Duration::from_secs(19))
+            .is_err());
+        assert!(limiter
+            .check_at("a", start + Duration::from_secs(21))
+            .is_ok());
+    }
+
+    #[test]
+    fn drops_refilled_allowances() {
+        let limiter = RateLimiter::new(60);
+        let start = Instant::now();
+        limiter.check_at("idle", start).unwrap();
+        for _ in 0..30 {
+            limiter
+                .check_at("busy", start + Duration::from_secs(50))
+                .unwrap();
+        }
+        let tokens = |limiter: &RateLimiter| {
+            let allowances = limiter.allowances.lock().unwrap();
+            let mut tokens: Vec<_> = allowances.tokens.keys().cloned().collect();
+            tokens.sort();
+            tokens
+        };
+        assert_eq!(tokens(&limiter), ["busy", "idle"]);
+        // After a minute "idle" is full again but "busy" isn't
+        limiter
+            .check_at("new", start + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(tokens(&limiter), ["busy", "new"]);
+    }
+}
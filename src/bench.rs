@@ -0,0 +1,126 @@
+use anyhow::Result;
+use poise::serenity_prelude::UserId;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{money::Money, prediction_market::ShareKind, shared_economy::SharedEconomy, Economy};
+
+/// Users trading at the same time
+const USERS: u64 = 64;
+/// Trades each user makes
+const TRADES_PER_USER: u64 = 50;
+const MARKETS: u64 = 16;
+
+fn market_economy() -> Result<Economy> {
+    let mut economy = Economy::new();
+    for i in 0..MARKETS {
+        let (new_economy, _) = economy.create_market(
+            UserId::new(1_000_000 + i),
+            format!("Benchmark market {i}"),
+            String::new(),
+            None,
+            0.5,
+            Money(0.0),
+            None,
+            None,
+        )?;
+        economy = new_economy;
+    }
+    Ok(economy)
+}
+
+fn trade(economy: &Economy, user: u64, i: u64) -> Result<(Economy, ())> {
+    let market = (user + i) % MARKETS;
+    let share_kind = if i.is_multiple_of(2) {
+        ShareKind::Yes
+    } else {
+        ShareKind::No
+    };
+    // Alternate between the markets' sides without ever holding both kinds of share
+    let (economy, _) = economy.buy(UserId::new(user + 1), market, Money(1.0), share_kind)?;
+    let (economy, _, _) = economy.sell(UserId::new(user + 1), market, None)?;
+    Ok((economy, ()))
+}
+
+/// Pretend to send a reply to Discord
+async fn send(latency: Duration) {
+    // Even a zero-length sleep waits for the timer's next millisecond tick
+    if !latency.is_zero() {
+        tokio::time::sleep(latency).await;
+    }
+}
+
+/// The old design: one lock around the economy, held while replying to Discord
+async fn global_lock(economy: Economy, send_latency: Duration) -> Result<u64> {
+    let economy = Arc::new(tokio::sync::Mutex::new(economy));
+    let tasks = (0..USERS).map(|user| {
+        let economy = economy.clone();
+        tokio::spawn(async move {
+            for i in 0..TRADES_PER_USER {
+                let mut economy = economy.lock().await;
+                let (new_economy, ()) = trade(&economy, user, i)?;
+                send(send_latency).await;
+                *economy = new_economy;
+            }
+            anyhow::Ok(())
+        })
+    });
+    for task in tasks.collect::<Vec<_>>() {
+        task.await??;
+    }
+    Ok(USERS * TRADES_PER_USER)
+}
+
+/// The current design: optimistic commits, replying to Discord after the lock is released
+async fn optimistic(economy: Economy, send_latency: Duration) -> Result<u64> {
+    let economy = Arc::new(SharedEconomy::new(economy, None));
+    let tasks = (0..USERS).map(|user| {
+        let economy = economy.clone();
+        tokio::spawn(async move {
+            for i in 0..TRADES_PER_USER {
+                economy.update(|economy| trade(economy, user, i))?;
+                send(send_latency).await;
+            }
+            anyhow::Ok(())
+        })
+    });
+    for task in tasks.collect::<Vec<_>>() {
+        task.await??;
+    }
+    Ok(USERS * TRADES_PER_USER)
+}
+
+/// Measure how many trades per second each locking design handles with many users trading at
+/// once, with and without a simulated delay for sending the reply to Discord
+pub async fn run() -> Result<()> {
+    println!(
+        "{USERS} users each making {TRADES_PER_USER} trades (a buy and a sell) in {MARKETS} markets"
+    );
+    println!(
+        "{:<14} {:>14} {:>14}",
+        "Send latency", "Global lock", "Optimistic"
+    );
+    for send_latency in [
+        Duration::ZERO,
+        Duration::from_millis(1),
+        Duration::from_millis(10),
+    ] {
+        let start = Instant::now();
+        let trades = global_lock(market_economy()?, send_latency).await?;
+        let global_lock_rate = trades as f64 / start.elapsed().as_secs_f64();
+
+        let start = Instant::now();
+        let trades = optimistic(market_economy()?, send_latency).await?;
+        let optimistic_rate = trades as f64 / start.elapsed().as_secs_f64();
+
+        println!(
+            "{:<14} {:>10.0}/sec {:>10.0}/sec",
+            format!("{send_latency:?}"),
+            global_lock_rate,
+            optimistic_rate
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+const WIDTH: usize = 800;
+const HEIGHT: usize = 300;
+/// Blank space around the plot
+const MARGIN: usize = 12;
+/// Thickness of the probability line in pixels
+const LINE_WIDTH: usize = 3;
+
+// Colors chosen to read well on Discord's dark theme
+const BACKGROUND: [u8; 3] = [49, 51, 56];
+const GRID: [u8; 3] = [78, 80, 88];
+const FILL: [u8; 3] = [38, 70, 110];
+const LINE: [u8; 3] = [88, 166, 255];
+
+/// RGB image being drawn on
+struct Canvas {
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new() -> Self {
+        Self {
+            pixels: BACKGROUND.repeat(WIDTH * HEIGHT),
+        }
+    }
+
+    /// Fill the rectangle spanning columns `x0..x1` and rows `y0..y1`, clipped to the image
+    fn fill(&mut self, x0: usize, x1: usize, y0: usize, y1: usize, color: [u8; 3]) {
+        for y in y0.min(HEIGHT)..y1.min(HEIGHT) {
+            for x in x0.min(WIDTH)..x1.min(WIDTH) {
+                let i = (y * WIDTH + x) * 3;
+                self.pixels[i..i + 3].copy_from_slice(&color);
+            }
+        }
+    }
+
+    fn encode_png(&self) -> Result<Vec<u8>> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, WIDTH as u32, HEIGHT as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(png)
+    }
+}
+
+/// Render a PNG chart of probability over time from `start` to `end`. `history` is the
+/// probability after each change, oldest first, and holds until the next change. Gridlines mark
+/// 25%, 50% and 75%.
+pub fn render(
+    history: &[(DateTime<Utc>, u8)],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<u8>> {
+    let mut canvas = Canvas::new();
+    let plot_width = WIDTH - 2 * MARGIN;
+    let plot_height = HEIGHT - 2 * MARGIN;
+    let bottom = HEIGHT - MARGIN;
+    let row = |probability: u8| bottom - usize::from(probability.min(100)) * plot_height / 100;
+
+    for probability in [0, 25, 50, 75, 100] {
+        let y = row(probability);
+        canvas.fill(MARGIN, WIDTH - MARGIN, y, y + 1, GRID);
+    }
+
+    let span = (end - start).num_milliseconds().max(1);
+    // Probability at `start` is the last one set before it, or the first one if it's later
+    let mut next = history.partition_point(|&(time, _)| time <= start);
+    let mut probability = match next.checked_sub(1) {
+        Some(i) => history[i].1,
+        None => history.first().map_or(50, |&(_, probability)| probability),
+    };
+    let mut previous_y = row(probability);
+    for column in 0..plot_width {
+        let time = start + chrono::Duration::milliseconds(span * column as i64 / plot_width as i64);
+        while next < history.len() && history[next].0 <= time {
+            probability = history[next].1;
+            next += 1;
+        }
+        let x = MARGIN + column;
+        let y = row(probability);
+        canvas.fill(x, x + 1, y, bottom, FILL);
+        // Cover the jump from the previous column so changes draw as vertical lines
+        let (from, to) = (previous_y.min(y), previous_y.max(y));
+        let half = LINE_WIDTH / 2;
+        canvas.fill(
+            x.saturating_sub(half),
+            x + half + 1,
+            from.saturating_sub(half),
+            to + half + 1,
+            LINE,
+        );
+        previous_y = y;
+    }
+
+    canvas.encode_png()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode a rendered chart into its RGB pixels
+    fn decode(png: &[u8]) -> Vec<u8> {
+        let decoder = png::Decoder::new(std::io::Cursor::new(png));
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (WIDTH as u32, HEIGHT as u32));
+        pixels
+    }
+
+    fn pixel(pixels: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let i = (y * WIDTH + x) * 3;
+        pixels[i..i + 3].try_into().unwrap()
+    }
+
+    /// Row the line is drawn on for `probability`
+    fn row(probability: usize) -> usize {
+        HEIGHT - MARGIN - probability * (HEIGHT - 2 * MARGIN) / 100
+    }
+
+    #[test]
+    fn draws_the_probability_over_time() {
+        let start = DateTime::from_timestamp(1_000_000, 0).unwrap();
+        let end = start + chrono::Duration::hours(2);
+        let history = [
+            // Before the chart starts, so it sets the probability at the start
+            (start - chrono::Duration::hours(1), 20),
+            (start + chrono::Duration::hours(1), 80),
+        ];
+        let pixels = decode(&render(&history, start, end).unwrap());
+
+        let left = MARGIN + 100;
+        assert_eq!(pixel(&pixels, left, row(20)), LINE);
+        assert_eq!(pixel(&pixels, left, row(20) + 20), FILL);
+        assert_eq!(pixel(&pixels, left, row(50)), GRID);
+        assert_eq!(pixel(&pixels, left, row(60)), BACKGROUND);
+
+        let right = WIDTH - MARGIN - 100;
+        assert_eq!(pixel(&pixels, right, row(80)), LINE);
+        assert_eq!(pixel(&pixels, right, row(50)), FILL);
+        // The change draws as a vertical line in the middle
+        assert_eq!(pixel(&pixels, WIDTH / 2, row(50) + 1), LINE);
+
+        assert_eq!(pixel(&pixels, 0, 0), BACKGROUND);
+    }
+
+    #[test]
+    fn starts_at_the_first_probability_if_its_after_the_start() {
+        let start = DateTime::from_timestamp(1_000_000, 0).unwrap();
+        let end = start + chrono::Duration::hours(2);
+        let history = [(start + chrono::Duration::hours(1), 70)];
+        let pixels = decode(&render(&history, start, end).unwrap());
+        assert_eq!(pixel(&pixels, MARGIN + 100, row(70)), LINE);
+    }
+}
@@ -1,12 +1,23 @@
 use crate::{
+    api,
+    export::{self, ExportFormat, TradeRow},
+    import,
     money::Money,
-    prediction_market::{Market, MarketId, ResolveOutcome, ShareKind, TransactionInfo},
+    notifications::{fire_alerts, notify_big_move, notify_watchers},
+    pagination::{self, DEFAULT_PAGE_SIZE},
+    prediction_market::{
+        AlertDirection, AlertId, DateDialect, Market, MarketId, Recurrence, Resolution,
+        ResolveOutcome, ShareKind, Template, TemplateId, TemplatePayer, TransactionInfo,
+        TransactionKind,
+    },
     share_quantity::ShareQuantity,
+    shared_economy::Update,
     Context, Economy,
 };
 use anyhow::{Context as AnyhowContext, Result};
 use poise::serenity_prelude::{
-    AutocompleteChoice, Color, CreateEmbed, Mention, Mentionable, User, UserId,
+    self as serenity, AutocompleteChoice, Color, CreateAttachment, CreateEmbed, CreateMessage,
+    Mention, Mentionable, User, UserId,
 };
 
 impl ShareKind {
@@ -18,6 +29,15 @@ impl ShareKind {
     }
 }
 
+impl DateDialect {
+    fn to_chrono_english(self) -> chrono_english::Dialect {
+        match self {
+            Self::Us => chrono_english::Dialect::Us,
+            Self::Uk => chrono_english::Dialect::Uk,
+        }
+    }
+}
+
 impl ResolveOutcome {
     fn color(&self) -> Color {
         match self {
@@ -28,6 +48,88 @@ impl ResolveOutcome {
     }
 }
 
+/// Order to list markets in
+#[derive(Copy, Clone, poise::ChoiceParameter)]
+pub enum MarketSort {
+    #[name = "Newest"]
+    Newest,
+    #[name = "Closing soonest"]
+    ClosingSoonest,
+    #[name = "Most traded"]
+    MostTraded,
+    #[name = "Probability"]
+    Probability,
+}
+
+impl MarketSort {
+    fn sort(&self, markets: &mut [&Market<UserId>]) {
+        match self {
+            Self::Newest => markets.sort_by_key(|market| std::cmp::Reverse(market.id)),
+            // Markets without a close time go last
+            Self::ClosingSoonest => {
+                markets.sort_by_key(|market| market.close_timestamp.unwrap_or(i64::MAX))
+            }
+            Self::MostTraded => {
+                markets.sort_by_key(|market| std::cmp::Reverse(market.transaction_history.len()))
+            }
+            Self::Probability => {
+                markets.sort_by_key(|market| std::cmp::Reverse(market.probability()))
+            }
+        }
+    }
+}
+
+/// How far back a chart goes
+#[derive(Copy, Clone, poise::ChoiceParameter)]
+pub enum ChartWindow {
+    #[name = "Last day"]
+    Day,
+    #[name = "Last week"]
+    Week,
+    #[name = "Last month"]
+    Month,
+    #[name = "All time"]
+    AllTime,
+}
+
+impl ChartWindow {
+    fn duration(&self) -> Option<chrono::Duration> {
+        match self {
+            Self::Day => Some(chrono::Duration::days(1)),
+            Self::Week => Some(chrono::Duration::weeks(1)),
+            Self::Month => Some(chrono::Duration::days(30)),
+            Self::AllTime => None,
+        }
+    }
+}
+
+/// Name of chart attachments, which embeds refer to
+const CHART_FILENAME: &str = "chart.png";
+
+/// Chart a market's probability up to now, or until it closed, returning the image to attach
+/// and a description of the time it covers
+fn market_chart(
+    market: &Market<UserId>,
+    window: ChartWindow,
+) -> Result<(CreateAttachment, String)> {
+    let now = chrono::Utc::now();
+    let end = market
+        .close_timestamp
+        .and_then(|close_timestamp| chrono::DateTime::from_timestamp(close_timestamp, 0))
+        .map_or(now, |close_time| close_time.min(now));
+    let start = match window.duration() {
+        Some(duration) => (now - duration).max(market.creation_time).min(end),
+        None => market.creation_time,
+    };
+    let history = market.probability_history().collect::<Vec<_>>();
+    let png = crate::chart::render(&history, start, end)?;
+    let (start, end) = (start.timestamp(), end.timestamp());
+    Ok((
+        CreateAttachment::bytes(png, CHART_FILENAME),
+        format!("Probability from <t:{start}:f> to <t:{end}:f>, with lines every 25%"),
+    ))
+}
+
 fn market_to_brief_field(market: &Market<UserId>) -> (String, String, bool) {
     let creator = Mention::User(market.creator);
     let close_text = match market.close_timestamp {
@@ -38,27 +140,101 @@ fn market_to_brief_field(market: &Market<UserId>) -> (String, String, bool) {
         ),
     };
     (
-        format!(
-            "__{}__   {}   **{}**_%_",
-            market.id,
-            market.question,
-            market.probability()
+        truncate(
+            &format!(
+                "__{}__   {}   **{}**_%_",
+                market.id,
+                market.question,
+                market.probability()
+            ),
+            FIELD_NAME_LIMIT,
         ),
         format!("{creator}{close_text}"),
         false,
     )
 }
 
-fn market_positions_string(market: &Market<UserId>) -> String {
+/// Most characters Discord allows in an autocomplete choice's name
+const AUTOCOMPLETE_NAME_LIMIT: usize = 100;
+/// Most characters Discord allows in an embed's title
+const TITLE_LIMIT: usize = 256;
+/// Most characters Discord allows in an embed field's name
+const FIELD_NAME_LIMIT: usize = 256;
+/// Most characters Discord allows in an embed field's value
+const FIELD_VALUE_LIMIT: usize = 1024;
+
+/// Shorten `text` to at most `limit` characters, marking where it was cut
+fn truncate(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        text.to_string()
+    } else {
+        let mut truncated = text.chars().take(limit - 1).collect::<String>();
+        truncated.push('…');
+        truncated
+    }
+}
+
+/// Join as many of `lines` as fit in an embed field, keeping the first ones, or the last ones if
+/// `keep_last`, and saying how many were left out and which command shows them all
+fn lines_to_field_value(lines: &[String], keep_last: bool, see_also: Option<&str>) -> String {
+    if lines.is_empty() {
+        // Discord rejects empty fields
+        return "None".into();
+    }
+    let note = |omitted| match see_also {
+        Some(command) => format!("… and {omitted} more, see `{command}`"),
+        None => format!("… and {omitted} more"),
+    };
+    let ordered: Box<dyn Iterator<Item = &String>> = if keep_last {
+        Box::new(lines.iter().rev())
+    } else {
+        Box::new(lines.iter())
+    };
+    let mut kept = Vec::new();
+    let mut length = 0;
+    // Room for the note, which is never longer than when every line is left out
+    let note_length = note(lines.len()).chars().count() + 1;
+    for line in ordered {
+        let reserved = if kept.len() + 1 == lines.len() {
+            0
+        } else {
+            note_length
+        };
+        let line_length = line.chars().count() + usize::from(!kept.is_empty());
+        if length + line_length + reserved > FIELD_VALUE_LIMIT {
+            break;
+        }
+        length += line_length;
+        kept.push(line.as_str());
+    }
+    if keep_last {
+        kept.reverse();
+    }
+    let omitted = lines.len() - kept.len();
+    let note = note(omitted);
+    if omitted > 0 {
+        // Put the note on the side the lines were left out from
+        if keep_last {
+            kept.insert(0, &note);
+        } else {
+            kept.push(&note);
+        }
+    }
+    kept.join("\n")
+}
+
+fn market_position_lines(market: &Market<UserId>, decimal_places: u8) -> Vec<String> {
     market
         .num_user_shares
         .iter()
-        .map(|(user_id, kind_quantity)| format!("{} - {kind_quantity}", Mention::User(*user_id)))
-        .collect::<Vec<String>>()
-        .join("\n")
+        .map(|(user_id, kind_quantity)| {
+            let shares = kind_quantity.with_precision(decimal_places);
+            format!("{} - {shares}", Mention::User(*user_id))
+        })
+        .collect()
 }
 
-fn market_transactions_string(market: &Market<UserId>) -> String {
+fn market_transaction_lines(market: &Market<UserId>, decimal_places: u8) -> Vec<String> {
     market
         .transaction_history
         .iter()
@@ -73,26 +249,70 @@ fn market_transactions_string(market: &Market<UserId>) -> String {
              }| {
                 let timestamp = time.timestamp();
                 let user = Mention::User(*user);
+                let shares = shares.with_precision(decimal_places);
+                let money = money.with_precision(decimal_places);
                 format!("<t:{timestamp}:R> {user} {kind} {shares} for {money} | {new_probability}%")
             },
         )
-        .collect::<Vec<String>>()
-        .join("\n")
+        .collect()
 }
 
-fn market_to_descriptive_fields(market: &Market<UserId>) -> [(String, String, bool); 4] {
+/// Fields summarizing a market. Long descriptions are cut short, and only the positions and most
+/// recent transactions that fit are listed, since Discord rejects oversized embeds. Amounts are
+/// shown with `decimal_places` digits, from the preferences of the user who asked.
+fn market_to_descriptive_fields(
+    market: &Market<UserId>,
+    decimal_places: u8,
+) -> [(String, String, bool); 4] {
     [
         market_to_brief_field(market),
-        ("Description".into(), market.description.clone(), false),
-        ("Positions".into(), market_positions_string(market), false),
+        (
+            "Description".into(),
+            truncate(&market.description, FIELD_VALUE_LIMIT),
+            false,
+        ),
+        (
+            "Positions".into(),
+            lines_to_field_value(
+                &market_position_lines(market, decimal_places),
+                false,
+                Some("/market_history"),
+            ),
+            false,
+        ),
         (
             "Transactions".into(),
-            market_transactions_string(market),
+            lines_to_field_value(
+                &market_transaction_lines(market, decimal_places),
+                true,
+                Some("/market_history"),
+            ),
             false,
         ),
     ]
 }
 
+/// The creation cost, total subsidy and how much each user put in, biggest first
+fn market_subsidy_field_value(market: &Market<UserId>, decimal_places: u8) -> String {
+    let mut contributions = std::collections::BTreeMap::<UserId, Money>::new();
+    for subsidy in &market.subsidies {
+        *contributions.entry(subsidy.user).or_insert(Money(0.0)) += subsidy.money;
+    }
+    let mut contributions = contributions.into_iter().collect::<Vec<_>>();
+    contributions.sort_by(|(_, a), (_, b)| b.0.total_cmp(&a.0));
+    let lines = std::iter::once(format!(
+        "{} in all, on top of the {} creation cost",
+        market.total_subsidy().with_precision(decimal_places),
+        market.creation_cost.with_precision(decimal_places)
+    ))
+    .chain(contributions.into_iter().map(|(user, money)| {
+        let money = money.with_precision(decimal_places);
+        format!("{} - {money}", Mention::User(user))
+    }))
+    .collect::<Vec<_>>();
+    lines_to_field_value(&lines, false, None)
+}
+
 fn make_matcher() -> impl fuzzy_matcher::FuzzyMatcher {
     fuzzy_matcher::skim::SkimMatcherV2::default().ignore_case()
 }
@@ -100,13 +320,13 @@ fn make_matcher() -> impl fuzzy_matcher::FuzzyMatcher {
 async fn autocomplete_market(ctx: Context<'_>, prefix: &str) -> Vec<AutocompleteChoice> {
     use fuzzy_matcher::FuzzyMatcher;
     let matcher = make_matcher();
-    let economy = ctx.data().lock().await;
+    let economy = ctx.data().economy.snapshot();
     economy
         .list_markets()
         .filter_map(|Market { id, question, .. }| {
             matcher
                 .fuzzy_match(question, prefix)
-                .map(|_| AutocompleteChoice::new(question, *id))
+                .map(|_| AutocompleteChoice::new(truncate(question, AUTOCOMPLETE_NAME_LIMIT), *id))
         })
         .collect()
 }
@@ -114,7 +334,7 @@ async fn autocomplete_market(ctx: Context<'_>, prefix: &str) -> Vec<Autocomplete
 async fn autocomplete_users_markets(ctx: Context<'_>, prefix: &str) -> Vec<AutocompleteChoice> {
     use fuzzy_matcher::FuzzyMatcher;
     let matcher = make_matcher();
-    let economy = ctx.data().lock().await;
+    let economy = ctx.data().economy.snapshot();
     economy
         .list_markets()
         .filter_map(
@@ -125,9 +345,9 @@ async fn autocomplete_users_markets(ctx: Context<'_>, prefix: &str) -> Vec<Autoc
                  ..
              }| {
                 if *creator == ctx.author().id {
-                    matcher
-                        .fuzzy_match(question, prefix)
-                        .map(|_| AutocompleteChoice::new(question, *id))
+                    matcher.fuzzy_match(question, prefix).map(|_| {
+                        AutocompleteChoice::new(truncate(question, AUTOCOMPLETE_NAME_LIMIT), *id)
+                    })
                 } else {
                     None
                 }
@@ -155,24 +375,32 @@ pub async fn help(
 
 /// Get the balances of all users
 #[poise::command(slash_command, prefix_command)]
-pub async fn balances(ctx: Context<'_>) -> Result<()> {
-    let economy = ctx.data().lock().await;
-    ctx.send(
-        poise::CreateReply::default().embed(
-            CreateEmbed::new()
-                .color(Color::DARK_GOLD)
-                .title("User balances")
-                .fields(economy.balances().into_iter().enumerate().map(
-                    |(i, (user_id, balance))| {
-                        let num = i + 1;
-                        let mention = Mention::User(user_id);
-                        (format!("{num}"), format!("{mention} {balance}"), true)
-                    },
-                )),
-        ),
-    )
-    .await?;
-    Ok(())
+pub async fn balances(
+    ctx: Context<'_>,
+    #[description = "Number of users on each page (default is 10)"]
+    #[min = 1]
+    #[max = 25]
+    page_size: Option<usize>,
+) -> Result<()> {
+    let economy = ctx.data().economy.snapshot();
+    let decimal_places = economy.preferences(&ctx.author().id).decimal_places;
+    let fields = economy
+        .balances()
+        .into_iter()
+        .enumerate()
+        .map(|(i, (user_id, balance))| {
+            let num = i + 1;
+            let mention = Mention::User(user_id);
+            let balance = balance.with_precision(decimal_places);
+            (format!("{num}"), format!("{mention} {balance}"), true)
+        })
+        .collect();
+    let pages = pagination::field_pages(fields, page_size.unwrap_or(DEFAULT_PAGE_SIZE), || {
+        CreateEmbed::new()
+            .color(Color::DARK_GOLD)
+            .title("User balances")
+    });
+    pagination::paginate(ctx, pages).await
 }
 
 /// Get the balance of a user
@@ -182,11 +410,13 @@ pub async fn balance(
     #[description = "User to get the balance of (default is you)"] user: Option<User>,
 ) -> Result<()> {
     let user = user.as_ref().unwrap_or_else(|| ctx.author());
-    let economy = ctx.data().lock().await;
+    let economy = ctx.data().economy.snapshot();
     let response = format!(
         "{}'s balance is {}",
         user.mention(),
-        economy.balance(user.id)
+        economy
+            .balance(user.id, ctx.guild_id())
+            .with_precision(economy.preferences(&ctx.author().id).decimal_places)
     );
     ctx.say(response).await?;
     Ok(())
@@ -199,20 +429,22 @@ pub async fn portfolio(
     #[description = "User to get the portfolio of (default is you)"] user: Option<User>,
 ) -> Result<()> {
     let user = user.as_ref().unwrap_or_else(|| ctx.author());
-    let economy = ctx.data().lock().await;
-    let portfolio = economy.portfolio(user.id);
+    let economy = ctx.data().economy.snapshot();
+    let portfolio = economy.portfolio(user.id, ctx.guild_id());
+    let decimal_places = economy.preferences(&ctx.author().id).decimal_places;
     ctx.send(
         poise::CreateReply::default().embed(
             CreateEmbed::new()
                 .color(Color::TEAL)
                 .title(format!("{}'s portfolio", user.name))
-                .field("Cash", format!("{}", portfolio.cash), true)
+                .field("Cash", portfolio.cash.with_precision(decimal_places), true)
                 .fields(
                     portfolio
                         .market_positions
                         .into_iter()
                         .map(|(question, kind_quantity)| {
-                            (question, format!("{kind_quantity} shares"), false)
+                            let shares = kind_quantity.with_precision(decimal_places);
+                            (question, format!("{shares} shares"), false)
                         }),
                 ),
         ),
@@ -221,7 +453,30 @@ pub async fn portfolio(
     Ok(())
 }
 
-/// Create a market (costs $50)
+/// Parse a date and time the author entered, in `time_zone` or else their preferred time zone or
+/// else the server's, and with their preferred date dialect
+fn parse_date_time(
+    ctx: Context<'_>,
+    text: &str,
+    time_zone: Option<String>,
+) -> Result<chrono::DateTime<chrono_tz::Tz>> {
+    let economy = ctx.data().economy.snapshot();
+    let preferences = economy.preferences(&ctx.author().id);
+    let time_zone = time_zone
+        .or(preferences.time_zone)
+        .unwrap_or_else(|| economy.settings(ctx.guild_id()).default_time_zone);
+    let time_zone = time_zone
+        .parse::<chrono_tz::Tz>()
+        .ok()
+        .context("invalid time zone")?;
+    Ok(chrono_english::parse_date_string(
+        text,
+        chrono::Local::now().with_timezone(&time_zone),
+        preferences.date_dialect.to_chrono_english(),
+    )?)
+}
+
+/// Create a market (costs $50 unless the server changed it)
 #[poise::command(slash_command, prefix_command)]
 pub async fn create_market(
     ctx: Context<'_>,
@@ -230,82 +485,459 @@ pub async fn create_market(
     description: String,
     #[description = "Date and/or time the market closes (default is none)"]
     close_date_and_time: Option<String>,
-    #[description = "Time zone to use for market close time (default is US/Eastern)"]
+    #[description = "Time zone to use for market close time (default is yours or the server's)"]
+    #[autocomplete = "autocomplete_tz"]
     time_zone: Option<String>,
+    #[description = "Percent chance of YES the market starts at (default is 50)"]
+    #[min = 1]
+    #[max = 99]
+    initial_probability: Option<u8>,
+    #[description = "Money to add to the pool on top of the creation cost, so trades move it less"]
+    #[min = 0]
+    subsidy: Option<f64>,
 ) -> Result<()> {
-    let time_zone = match time_zone {
-        Some(time_zone) => time_zone
-            .parse::<chrono_tz::Tz>()
-            .ok()
-            .context("invalid time zone")?,
-        None => chrono_tz::US::Eastern,
-    };
+    let initial_probability = initial_probability.unwrap_or(50);
     let close_date_and_time = close_date_and_time
-        .map(|s| {
-            chrono_english::parse_date_string(
-                &s,
-                chrono::Local::now().with_timezone(&time_zone),
-                chrono_english::Dialect::Us,
-            )
-        })
+        .map(|s| parse_date_time(ctx, &s, time_zone))
         .transpose()
         .context("failed parsing close date and time")?;
     let close_timestamp = close_date_and_time.map(|date_time| date_time.timestamp());
-    let mut economy = ctx.data().lock().await;
-    let (new_economy, market_id) =
-        economy.create_market(ctx.author().id, question, description, close_timestamp)?;
-    let market = new_economy.market(market_id)?;
+    let Update {
+        after: economy,
+        value: market_id,
+        ..
+    } = ctx.data().economy.update(|economy| {
+        economy.create_market(
+            ctx.author().id,
+            question.clone(),
+            description.clone(),
+            close_timestamp,
+            f64::from(initial_probability) / 100.0,
+            Money(subsidy.unwrap_or(0.0)),
+            Some(ctx.channel_id()),
+            ctx.guild_id(),
+        )
+    })?;
+    let market = economy.market(market_id)?;
+    let decimal_places = economy.preferences(&ctx.author().id).decimal_places;
     ctx.send(
         poise::CreateReply::default().embed(
             CreateEmbed::new()
                 .color(Color::GOLD)
                 .title("Created market:")
-                .fields(market_to_descriptive_fields(market)),
+                .fields(market_to_descriptive_fields(market, decimal_places)),
         ),
     )
     .await?;
-    *economy = new_economy;
     Ok(())
 }
 
 /// Display a list of active markets
 #[poise::command(slash_command, prefix_command)]
-pub async fn list_markets(ctx: Context<'_>) -> Result<()> {
-    let economy = ctx.data().lock().await;
+pub async fn list_markets(
+    ctx: Context<'_>,
+    #[description = "Order to list markets in (default is newest)"] sort: Option<MarketSort>,
+    #[description = "Number of markets on each page (default is 10)"]
+    #[min = 1]
+    #[max = 25]
+    page_size: Option<usize>,
+) -> Result<()> {
+    let economy = ctx.data().economy.snapshot();
+    let mut markets = economy.list_markets().collect::<Vec<_>>();
+    sort.unwrap_or(MarketSort::Newest).sort(&mut markets);
+    let pages = pagination::field_pages(
+        markets.into_iter().map(market_to_brief_field).collect(),
+        page_size.unwrap_or(DEFAULT_PAGE_SIZE),
+        || CreateEmbed::new().color(Color::DARK_BLUE).title("Markets"),
+    );
+    pagination::paginate(ctx, pages).await
+}
+
+/// Show a market
+#[poise::command(slash_command, prefix_command)]
+pub async fn show_market(
+    ctx: Context<'_>,
+    #[description = "Market to show"]
+    #[autocomplete = "autocomplete_market"]
+    market: MarketId,
+) -> Result<()> {
+    let economy = ctx.data().economy.snapshot();
+    let market = economy.market(market)?;
+    let (chart, _) = market_chart(market, ChartWindow::AllTime)?;
+    let decimal_places = economy.preferences(&ctx.author().id).decimal_places;
+    ctx.send(
+        poise::CreateReply::default()
+            .embed(
+                CreateEmbed::new()
+                    .color(Color::DARK_BLUE)
+                    .title("Market")
+                    .fields(market_to_descriptive_fields(market, decimal_places))
+                    .field(
+                        "Subsidy",
+                        market_subsidy_field_value(market, decimal_places),
+                        false,
+                    )
+                    .image(format!("attachment://{CHART_FILENAME}")),
+            )
+            .attachment(chart),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Subsidize a market, so trades move its probability less
+#[poise::command(slash_command, prefix_command)]
+pub async fn subsidize(
+    ctx: Context<'_>,
+    #[description = "Market to subsidize"]
+    #[autocomplete = "autocomplete_market"]
+    market: MarketId,
+    #[description = "Money to add to the market's pool, which you won't get back"] amount: f64,
+) -> Result<()> {
+    let amount = Money(amount);
+    let Update { after, .. } = ctx.data().economy.update(|economy| {
+        economy
+            .subsidize(ctx.author().id, market, amount)
+            .map(|economy| (economy, ()))
+    })?;
+    let market = after.market(market)?;
+    let decimal_places = after.preferences(&ctx.author().id).decimal_places;
     ctx.send(
         poise::CreateReply::default().embed(
             CreateEmbed::new()
-                .color(Color::DARK_BLUE)
-                .title("Markets")
-                .fields(economy.list_markets().map(market_to_brief_field)),
+                .color(Color::GOLD)
+                .title(truncate(
+                    &format!("Subsidized {}", market.question),
+                    TITLE_LIMIT,
+                ))
+                .description(format!(
+                    "{} added to the pool, for {} in subsidies in all",
+                    amount.with_precision(decimal_places),
+                    market.total_subsidy().with_precision(decimal_places)
+                )),
         ),
     )
     .await?;
     Ok(())
 }
 
-/// Show a market
+/// Show a chart of a market's probability over time
 #[poise::command(slash_command, prefix_command)]
-pub async fn show_market(
+pub async fn chart(
     ctx: Context<'_>,
-    #[description = "Market to show"]
+    #[description = "Market to chart"]
     #[autocomplete = "autocomplete_market"]
     market: MarketId,
+    #[description = "How far back to chart (default is all time)"] window: Option<ChartWindow>,
 ) -> Result<()> {
-    let economy = ctx.data().lock().await;
+    let economy = ctx.data().economy.snapshot();
     let market = economy.market(market)?;
+    let (chart, description) = market_chart(market, window.unwrap_or(ChartWindow::AllTime))?;
     ctx.send(
-        poise::CreateReply::default().embed(
+        poise::CreateReply::default()
+            .embed(
+                CreateEmbed::new()
+                    .color(Color::DARK_BLUE)
+                    .title(truncate(&market.question, TITLE_LIMIT))
+                    .description(description)
+                    .image(format!("attachment://{CHART_FILENAME}")),
+            )
+            .attachment(chart),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Show all of a market's positions and transactions
+#[poise::command(slash_command, prefix_command)]
+pub async fn market_history(
+    ctx: Context<'_>,
+    #[description = "Market to show the history of"]
+    #[autocomplete = "autocomplete_market"]
+    market: MarketId,
+    #[description = "Number of lines on each page (default is 10)"]
+    #[min = 1]
+    #[max = 25]
+    page_size: Option<usize>,
+) -> Result<()> {
+    let economy = ctx.data().economy.snapshot();
+    let market = economy.market(market)?;
+    let page_size = page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+    let decimal_places = economy.preferences(&ctx.author().id).decimal_places;
+    // Newest transactions first
+    let mut transactions = market_transaction_lines(market, decimal_places);
+    transactions.reverse();
+    let transactions_title = truncate(&format!("Transactions in {}", market.question), TITLE_LIMIT);
+    let mut pages = pagination::line_pages(transactions, page_size, || {
+        CreateEmbed::new()
+            .color(Color::DARK_BLUE)
+            .title(&transactions_title)
+    });
+    let positions_title = truncate(&format!("Positions in {}", market.question), TITLE_LIMIT);
+    pages.extend(pagination::line_pages(
+        market_position_lines(market, decimal_places),
+        page_size,
+        || {
+            CreateEmbed::new()
+                .color(Color::TEAL)
+                .title(&positions_title)
+        },
+    ));
+    pagination::paginate(ctx, pages).await
+}
+
+async fn autocomplete_watched_market(ctx: Context<'_>, prefix: &str) -> Vec<AutocompleteChoice> {
+    use fuzzy_matcher::FuzzyMatcher;
+    let matcher = make_matcher();
+    let economy = ctx.data().economy.snapshot();
+    economy
+        .watchlist(&ctx.author().id)
+        .filter_map(|Market { id, question, .. }| {
+            matcher
+                .fuzzy_match(question, prefix)
+                .map(|_| AutocompleteChoice::new(truncate(question, AUTOCOMPLETE_NAME_LIMIT), *id))
+        })
+        .collect()
+}
+
+/// Watch a market to hear when it moves a lot, closes or resolves
+#[poise::command(slash_command, prefix_command, ephemeral)]
+pub async fn watch(
+    ctx: Context<'_>,
+    #[description = "Market to watch"]
+    #[autocomplete = "autocomplete_market"]
+    market: MarketId,
+) -> Result<()> {
+    let Update { after, .. } = ctx
+        .data()
+        .economy
+        .update(|economy| Ok((economy.watch(ctx.author().id, market)?, ())))?;
+    let market = after.market(market)?;
+    let big_move_points = after.settings(market.guild).big_move_points;
+    ctx.say(format!(
+        "Watching **{}**. You'll get a direct message when its probability moves by \
+         {big_move_points} points or more in one trade, and when it closes or resolves.",
+        market.question
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Stop watching a market
+#[poise::command(slash_command, prefix_command, ephemeral)]
+pub async fn unwatch(
+    ctx: Context<'_>,
+    #[description = "Market to stop watching"]
+    #[autocomplete = "autocomplete_watched_market"]
+    market: MarketId,
+) -> Result<()> {
+    let Update { before, .. } = ctx
+        .data()
+        .economy
+        .update(|economy| Ok((economy.unwatch(ctx.author().id, market)?, ())))?;
+    let market = before.market(market)?;
+    ctx.say(format!("Stopped watching **{}**", market.question))
+        .await?;
+    Ok(())
+}
+
+/// Show the markets you're watching
+#[poise::command(slash_command, prefix_command, ephemeral)]
+pub async fn watchlist(
+    ctx: Context<'_>,
+    #[description = "Number of markets on each page (default is 10)"]
+    #[min = 1]
+    #[max = 25]
+    page_size: Option<usize>,
+) -> Result<()> {
+    let economy = ctx.data().economy.snapshot();
+    let pages = pagination::field_pages(
+        economy
+            .watchlist(&ctx.author().id)
+            .map(market_to_brief_field)
+            .collect(),
+        page_size.unwrap_or(DEFAULT_PAGE_SIZE),
+        || {
             CreateEmbed::new()
                 .color(Color::DARK_BLUE)
-                .title("Market")
-                .fields(market_to_descriptive_fields(market)),
-        ),
-    )
+                .title("Your watchlist")
+        },
+    );
+    pagination::paginate(ctx, pages).await
+}
+
+/// Get told when a market's probability goes above or below a threshold
+#[poise::command(slash_command, prefix_command, ephemeral)]
+pub async fn alert(
+    ctx: Context<'_>,
+    #[description = "Market to watch the probability of"]
+    #[autocomplete = "autocomplete_market"]
+    market: MarketId,
+    #[description = "Whether to alert when the probability goes above or below the threshold"]
+    direction: AlertDirection,
+    #[description = "Probability to alert at, in percent"]
+    #[min = 1]
+    #[max = 99]
+    percent: u8,
+    #[description = "Ping you in this channel instead of sending a direct message (default is no)"]
+    ping_here: Option<bool>,
+) -> Result<()> {
+    let channel = ping_here.unwrap_or(false).then(|| ctx.channel_id());
+    let Update {
+        after,
+        value: alert_id,
+        ..
+    } = ctx.data().economy.update(|economy| {
+        economy.create_alert(ctx.author().id, market, direction, percent, channel)
+    })?;
+    let market = after.market(market)?;
+    ctx.say(format!(
+        "Alert {alert_id} set for when **{}** goes {direction} {percent}%. It's at {}% now.",
+        market.question,
+        market.probability()
+    ))
     .await?;
     Ok(())
 }
 
+async fn autocomplete_alert(ctx: Context<'_>, _: &str) -> Vec<AutocompleteChoice> {
+    let economy = ctx.data().economy.snapshot();
+    economy
+        .user_alerts(&ctx.author().id)
+        .filter_map(|alert| {
+            let market = economy.market(alert.market_id).ok()?;
+            let name = truncate(
+                &format!(
+                    "{} {} {}%",
+                    market.question, alert.direction, alert.threshold
+                ),
+                AUTOCOMPLETE_NAME_LIMIT,
+            );
+            Some(AutocompleteChoice::new(name, alert.id))
+        })
+        .collect()
+}
+
+/// List your alerts, or cancel one
+#[poise::command(slash_command, prefix_command, ephemeral)]
+pub async fn alerts(
+    ctx: Context<'_>,
+    #[description = "Alert to cancel"]
+    #[autocomplete = "autocomplete_alert"]
+    cancel: Option<AlertId>,
+) -> Result<()> {
+    if let Some(alert_id) = cancel {
+        ctx.data()
+            .economy
+            .update(|economy| Ok((economy.cancel_alert(ctx.author().id, alert_id)?, ())))?;
+        ctx.say(format!("Canceled alert {alert_id}")).await?;
+        return Ok(());
+    }
+    let economy = ctx.data().economy.snapshot();
+    let fields = economy
+        .user_alerts(&ctx.author().id)
+        .filter_map(|alert| {
+            let market = economy.market(alert.market_id).ok()?;
+            let delivery = match alert.channel {
+                Some(channel) => format!("ping in {}", Mention::Channel(channel)),
+                None => "direct message".into(),
+            };
+            Some((
+                format!("Alert {}", alert.id),
+                format!(
+                    "{} {} {}% (now {}%), by {delivery}",
+                    truncate(&market.question, FIELD_NAME_LIMIT),
+                    alert.direction,
+                    alert.threshold,
+                    market.probability()
+                ),
+                false,
+            ))
+        })
+        .collect();
+    let pages = pagination::field_pages(fields, DEFAULT_PAGE_SIZE, || {
+        CreateEmbed::new()
+            .color(Color::DARK_BLUE)
+            .title("Your alerts")
+    });
+    pagination::paginate(ctx, pages).await
+}
+
+/// Lines listing what each user was paid, largest payout first, and what went to the server
+/// treasury if that paid for the market
+fn payout_lines(
+    resolution: &Resolution<UserId>,
+    outcome: ResolveOutcome,
+    decimal_places: u8,
+) -> Vec<String> {
+    let mut payouts = resolution.payouts.iter().collect::<Vec<_>>();
+    payouts.sort_by(|(_, a), (_, b)| b.partial_cmp(a).expect("failed comparing payouts"));
+    let creator_pool = resolution.creator_pool.with_precision(decimal_places);
+    let treasury_line = resolution.market.treasury.map(|_| {
+        format!(
+            "Server treasury {creator_pool} {}",
+            creator_pool_description(outcome)
+        )
+    });
+    payouts
+        .into_iter()
+        .map(|(user, payout)| {
+            let mention = Mention::User(*user);
+            let payout = payout.with_precision(decimal_places);
+            if *user == resolution.market.creator && resolution.market.treasury.is_none() {
+                format!(
+                    "{mention} {payout} (including {creator_pool} {})",
+                    creator_pool_description(outcome)
+                )
+            } else {
+                format!("{mention} {payout}")
+            }
+        })
+        .chain(treasury_line)
+        .collect()
+}
+
+fn creator_pool_description(outcome: ResolveOutcome) -> &'static str {
+    match outcome {
+        ResolveOutcome::Yes | ResolveOutcome::No => "left in the market's pool",
+        ResolveOutcome::Undo => "refunded creation cost",
+    }
+}
+
+/// Direct message telling `user` what they got from a resolved market
+fn payout_message(
+    resolution: &Resolution<UserId>,
+    outcome: ResolveOutcome,
+    user: UserId,
+    payout: Money,
+    decimal_places: u8,
+) -> String {
+    let payout = payout.with_precision(decimal_places);
+    let market = &resolution.market;
+    let mut message = format!(
+        "Market __{}__ **{}** resolved {outcome}.",
+        market.id, market.question
+    );
+    match (outcome, market.num_user_shares.get(&user)) {
+        (ResolveOutcome::Undo, _) => {
+            message += &format!(" Your trades were undone, for a net refund of {payout}.")
+        }
+        (_, Some(shares)) => {
+            let shares = shares.with_precision(decimal_places);
+            message += &format!(" You held {shares} shares, so you received {payout}.")
+        }
+        (_, None) => message += &format!(" You received {payout}."),
+    }
+    if user == market.creator && market.treasury.is_none() {
+        message += &format!(
+            " As the creator, that includes {} {}.",
+            resolution.creator_pool.with_precision(decimal_places),
+            creator_pool_description(outcome)
+        );
+    }
+    message + "\nTurn these messages off with `/settings`."
+}
+
 /// Resolve one of your markets
 #[poise::command(slash_command, prefix_command)]
 pub async fn resolve_market(
@@ -315,18 +947,67 @@ pub async fn resolve_market(
     market: MarketId,
     #[description = "Outcome to resolve to"] outcome: ResolveOutcome,
 ) -> Result<()> {
-    let mut economy = ctx.data().lock().await;
-    let (new_economy, market) = economy.resolve_market(ctx.author().id, market, outcome)?;
+    let Update {
+        before,
+        value: resolution,
+        ..
+    } = ctx
+        .data()
+        .economy
+        .update(|economy| economy.resolve_market(ctx.author().id, market, outcome))?;
+    let decimal_places = before.preferences(&ctx.author().id).decimal_places;
     ctx.send(
         poise::CreateReply::default().embed(
             CreateEmbed::new()
                 .color(outcome.color())
                 .title(format!("Resolved market {outcome}:"))
-                .fields(market_to_descriptive_fields(&market)),
+                .fields(market_to_descriptive_fields(
+                    &resolution.market,
+                    decimal_places,
+                ))
+                .field(
+                    "Payouts",
+                    lines_to_field_value(
+                        &payout_lines(&resolution, outcome, decimal_places),
+                        false,
+                        None,
+                    ),
+                    false,
+                ),
         ),
     )
     .await?;
-    *economy = new_economy;
+
+    // Let holders who weren't watching know how they did. The resolver already saw the embed.
+    let economy = ctx.data().economy.snapshot();
+    for (user, payout) in &resolution.payouts {
+        let preferences = economy.preferences(user);
+        if *user == ctx.author().id || !preferences.resolution_dms {
+            continue;
+        }
+        let message = payout_message(
+            &resolution,
+            outcome,
+            *user,
+            *payout,
+            preferences.decimal_places,
+        );
+        if let Err(e) = user
+            .direct_message(ctx, CreateMessage::new().content(message))
+            .await
+        {
+            tracing::debug!("failed messaging {user} about market {market}: {e}");
+        }
+    }
+
+    // Watchers were removed from the market when it resolved, and holders already heard above
+    let mut skip = resolution.payouts.keys().copied().collect::<Vec<_>>();
+    skip.push(ctx.author().id);
+    let content = format!(
+        "Market __{}__ **{}** that you were watching resolved {outcome}.",
+        resolution.market.id, resolution.market.question
+    );
+    notify_watchers(ctx, &before, market, &skip, &content).await;
     Ok(())
 }
 
@@ -351,16 +1032,34 @@ pub async fn sell(
     #[description = "Reason you are selling"] reason: Option<String>,
 ) -> Result<()> {
     let sell_amount = sell_amount.map(ShareQuantity);
-    let mut economy = ctx.data().lock().await;
-    let (new_economy, shares_sold, sale_price) =
-        economy.sell(ctx.author().id, market, sell_amount)?;
-    let prob_change = probability_change_string(&economy, &new_economy, market)?;
-    let market_name = &economy.market(market)?.question;
+    let Update {
+        before,
+        after,
+        value: (shares_sold, sale_price),
+    } = ctx.data().economy.update(|economy| {
+        economy
+            .sell(ctx.author().id, market, sell_amount)
+            .map(|(economy, shares_sold, sale_price)| (economy, (shares_sold, sale_price)))
+    })?;
+    ctx.data()
+        .metrics
+        .record_trade(TransactionKind::Sell, sale_price);
+    let prob_change = probability_change_string(&before, &after, market)?;
+    let market_name = &before.market(market)?.question;
+    let decimal_places = after.preferences(&ctx.author().id).decimal_places;
     let embed = CreateEmbed::new()
         .color(Color::BLITZ_BLUE)
         .title(format!("Sell {}", shares_sold.kind))
-        .field("Shares sold", shares_sold.to_string(), true)
-        .field("Sale price", sale_price.to_string(), true)
+        .field(
+            "Shares sold",
+            shares_sold.with_precision(decimal_places),
+            true,
+        )
+        .field(
+            "Sale price",
+            sale_price.with_precision(decimal_places),
+            true,
+        )
         .field("Probability change", prob_change, true)
         .field("Market", market_name, true);
     let embed = match reason {
@@ -368,7 +1067,12 @@ pub async fn sell(
         Some(reason) => embed.field("Reason", reason, true),
     };
     ctx.send(poise::CreateReply::default().embed(embed)).await?;
-    *economy = new_economy;
+    let economy = &ctx.data().economy;
+    // The trade already went through, so failing to tell watchers doesn't make it fail
+    if let Err(e) = notify_big_move(ctx, economy, &before, &after, market, ctx.author().id).await {
+        tracing::warn!("failed notifying about a big move in market {market}: {e:#}");
+    }
+    fire_alerts(ctx, economy, market).await;
     Ok(())
 }
 
@@ -386,22 +1090,39 @@ pub async fn buy(
     #[description = "Reason you are buying"] reason: Option<String>,
 ) -> Result<()> {
     let purchase_price = Money(purchase_price);
-    let mut economy = ctx.data().lock().await;
-    let (new_economy, shares_received) =
-        economy.buy(ctx.author().id, market, purchase_price, share_kind)?;
-    let prob_change = probability_change_string(&economy, &new_economy, market)?;
-    let market_name = &economy.market(market)?.question;
+    let Update {
+        before,
+        after,
+        value: shares_received,
+    } = ctx
+        .data()
+        .economy
+        .update(|economy| economy.buy(ctx.author().id, market, purchase_price, share_kind))?;
+    ctx.data()
+        .metrics
+        .record_trade(TransactionKind::Buy, purchase_price);
+    let prob_change = probability_change_string(&before, &after, market)?;
+    let market_name = &before.market(market)?.question;
+    let decimal_places = after.preferences(&ctx.author().id).decimal_places;
     let embed = CreateEmbed::new()
         .color(share_kind.color())
         .title(format!("Buy {share_kind}"))
-        .field("Shares bought", shares_received.to_string(), true)
-        .field("Buy price", purchase_price.to_string(), true)
+        .field(
+            "Shares bought",
+            shares_received.with_precision(decimal_places),
+            true,
+        )
+        .field(
+            "Buy price",
+            purchase_price.with_precision(decimal_places),
+            true,
+        )
         .field("Probability change", prob_change, true)
         .field(
             format!("Profit if {share_kind}"),
             format!(
                 "+{} (+{:.0}%)",
-                Money(shares_received.0 - purchase_price.0),
+                Money(shares_received.0 - purchase_price.0).with_precision(decimal_places),
                 (shares_received.0 / purchase_price.0 - 1.0) * 100.0,
             ),
             true,
@@ -412,7 +1133,12 @@ pub async fn buy(
         Some(reason) => embed.field("Reason", reason, true),
     };
     ctx.send(poise::CreateReply::default().embed(embed)).await?;
-    *economy = new_economy;
+    let economy = &ctx.data().economy;
+    // The trade already went through, so failing to tell watchers doesn't make it fail
+    if let Err(e) = notify_big_move(ctx, economy, &before, &after, market, ctx.author().id).await {
+        tracing::warn!("failed notifying about a big move in market {market}: {e:#}");
+    }
+    fire_alerts(ctx, economy, market).await;
     Ok(())
 }
 
@@ -425,10 +1151,14 @@ pub async fn tip(
     #[description = "Reason for tip"] reason: Option<String>,
 ) -> Result<()> {
     let amount = Money(amount);
-    let mut economy = ctx.data().lock().await;
-    let new_economy = economy.tip(ctx.author().id, user_to_tip.id, amount)?;
+    let Update { after, .. } = ctx.data().economy.update(|economy| {
+        economy
+            .tip(ctx.author().id, user_to_tip.id, amount, ctx.guild_id())
+            .map(|economy| (economy, ()))
+    })?;
     ctx.say(format!(
-        "Tipped {amount} to {}{}",
+        "Tipped {} to {}{}",
+        amount.with_precision(after.preferences(&ctx.author().id).decimal_places),
         user_to_tip.mention(),
         match reason {
             None => String::new(),
@@ -436,7 +1166,6 @@ pub async fn tip(
         }
     ))
     .await?;
-    *economy = new_economy;
     Ok(())
 }
 
@@ -453,6 +1182,631 @@ async fn autocomplete_tz(_: Context<'_>, prefix: &str) -> Vec<AutocompleteChoice
         .collect()
 }
 
+/// View or change this server's settings
+#[poise::command(
+    slash_command,
+    prefix_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    ephemeral
+)]
+pub async fn config(
+    ctx: Context<'_>,
+    #[description = "Money users start with"]
+    #[min = 0]
+    start_balance: Option<f64>,
+    #[description = "Cost of creating a market, which is also the starting size of its pools"]
+    market_creation_cost: Option<f64>,
+    #[description = "Time zone market close times are entered in by default"]
+    #[autocomplete = "autocomplete_tz"]
+    default_time_zone: Option<String>,
+    #[description = "Smallest move from one trade, in percentage points, that watchers hear about"]
+    #[min = 1]
+    #[max = 100]
+    big_move_points: Option<u8>,
+) -> Result<()> {
+    let guild = ctx.guild_id().context("settings only exist in servers")?;
+    let Update { after, .. } = ctx.data().economy.update(|economy| {
+        let mut settings = economy.settings(Some(guild));
+        if let Some(start_balance) = start_balance {
+            settings.start_balance = Money(start_balance);
+        }
+        if let Some(market_creation_cost) = market_creation_cost {
+            settings.market_creation_cost = Money(market_creation_cost);
+        }
+        if let Some(default_time_zone) = &default_time_zone {
+            settings.default_time_zone.clone_from(default_time_zone);
+        }
+        if let Some(big_move_points) = big_move_points {
+            settings.big_move_points = big_move_points;
+        }
+        Ok((economy.set_settings(guild, settings)?, ()))
+    })?;
+    let settings = after.settings(Some(guild));
+    let decimal_places = after.preferences(&ctx.author().id).decimal_places;
+    ctx.send(
+        poise::CreateReply::default().embed(
+            CreateEmbed::new()
+                .color(Color::DARK_GREY)
+                .title("Server settings")
+                .field(
+                    "Start balance",
+                    settings.start_balance.with_precision(decimal_places),
+                    true,
+                )
+                .field(
+                    "Market creation cost",
+                    settings.market_creation_cost.with_precision(decimal_places),
+                    true,
+                )
+                .field("Default time zone", settings.default_time_zone, true)
+                .field(
+                    "Big move",
+                    format!("{} points", settings.big_move_points),
+                    true,
+                ),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// View or change your own settings
+#[poise::command(slash_command, prefix_command, ephemeral)]
+pub async fn settings(
+    ctx: Context<'_>,
+    #[description = "Time zone you enter close times in, or \"default\" for the server's"]
+    #[autocomplete = "autocomplete_tz"]
+    time_zone: Option<String>,
+    #[description = "Whether a date like 3/4 means March 4th or April 3rd"] date_dialect: Option<
+        DateDialect,
+    >,
+    #[description = "Get a direct message when a market you hold shares in resolves"]
+    resolution_dms: Option<bool>,
+    #[description = "Get direct messages about markets you watch"] watchlist_dms: Option<bool>,
+    #[description = "Decimal places to show amounts of money and shares with"]
+    #[min = 0]
+    #[max = 6]
+    decimal_places: Option<u8>,
+) -> Result<()> {
+    let user = ctx.author().id;
+    let Update { after, .. } = ctx.data().economy.update(|economy| {
+        let mut preferences = economy.preferences(&user);
+        if let Some(time_zone) = &time_zone {
+            preferences.time_zone =
+                (!time_zone.eq_ignore_ascii_case("default")).then(|| time_zone.clone());
+        }
+        if let Some(date_dialect) = date_dialect {
+            preferences.date_dialect = date_dialect;
+        }
+        if let Some(resolution_dms) = resolution_dms {
+            preferences.resolution_dms = resolution_dms;
+        }
+        if let Some(watchlist_dms) = watchlist_dms {
+            preferences.watchlist_dms = watchlist_dms;
+        }
+        if let Some(decimal_places) = decimal_places {
+            preferences.decimal_places = decimal_places;
+        }
+        Ok((economy.set_preferences(user, preferences)?, ()))
+    })?;
+    let preferences = after.preferences(&user);
+    let on_off = |enabled| if enabled { "On" } else { "Off" };
+    ctx.send(
+        poise::CreateReply::default().embed(
+            CreateEmbed::new()
+                .color(Color::DARK_GREY)
+                .title("Your settings")
+                .field(
+                    "Time zone",
+                    preferences.time_zone.unwrap_or_else(|| {
+                        format!(
+                            "Server default ({})",
+                            after.settings(ctx.guild_id()).default_time_zone
+                        )
+                    }),
+                    true,
+                )
+                .field("Date dialect", preferences.date_dialect.to_string(), true)
+                .field(
+                    "Resolution messages",
+                    on_off(preferences.resolution_dms),
+                    true,
+                )
+                .field(
+                    "Watchlist messages",
+                    on_off(preferences.watchlist_dms),
+                    true,
+                )
+                .field(
+                    "Decimal places",
+                    preferences.decimal_places.to_string(),
+                    true,
+                ),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Whether the author can manage this server, which Discord only says for slash commands
+async fn author_manages_guild(ctx: Context<'_>) -> bool {
+    ctx.author_member()
+        .await
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.manage_guild())
+}
+
+async fn autocomplete_template(ctx: Context<'_>, prefix: &str) -> Vec<AutocompleteChoice> {
+    use fuzzy_matcher::FuzzyMatcher;
+    let matcher = make_matcher();
+    let economy = ctx.data().economy.snapshot();
+    economy
+        .list_templates()
+        .filter(|template| Some(template.guild) == ctx.guild_id())
+        .filter_map(|Template { id, question, .. }| {
+            matcher
+                .fuzzy_match(question, prefix)
+                .map(|_| AutocompleteChoice::new(truncate(question, AUTOCOMPLETE_NAME_LIMIT), *id))
+        })
+        .collect()
+}
+
+fn template_to_field(template: &Template<UserId>) -> (String, String, bool) {
+    let next_close = template.next_close.timestamp();
+    (
+        truncate(
+            &format!("__{}__   {}", template.id, template.question),
+            FIELD_NAME_LIMIT,
+        ),
+        format!(
+            "{} by {}, paid by the {}, in {}\n\
+             Next market <t:{}:R>, closing <t:{next_close}:F>\n\
+             {} markets so far",
+            template.recurrence,
+            Mention::User(template.creator),
+            template.payer,
+            Mention::Channel(template.channel),
+            template.next_open.timestamp(),
+            template.instances.len()
+        ),
+        false,
+    )
+}
+
+/// Create markets with the same question again and again, like every week
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands(
+        "template_create",
+        "template_list",
+        "template_history",
+        "template_delete"
+    ),
+    subcommand_required
+)]
+pub async fn template(_ctx: Context<'_>) -> Result<()> {
+    Ok(())
+}
+
+/// Create a template, whose markets are created in this channel as the one before closes
+#[poise::command(slash_command, guild_only, rename = "create")]
+#[allow(clippy::too_many_arguments)]
+pub async fn template_create(
+    ctx: Context<'_>,
+    #[description = "Question each market asks, where {date} is its close date and {number} its count"]
+    question: String,
+    #[description = "Description of each market, with the same placeholders as the question"]
+    description: String,
+    #[description = "Date and time the first market closes, which later ones close a period after"]
+    first_close: String,
+    #[description = "How often to create a market"] recurrence: Recurrence,
+    #[description = "Who pays the creation cost of each market (default is you)"] payer: Option<
+        TemplatePayer,
+    >,
+    #[description = "Time zone close times recur in (default is yours or the server's)"]
+    #[autocomplete = "autocomplete_tz"]
+    time_zone: Option<String>,
+) -> Result<()> {
+    let guild = ctx.guild_id().context("templates only exist in servers")?;
+    let payer = payer.unwrap_or(TemplatePayer::Creator);
+    if payer == TemplatePayer::Treasury && !author_manages_guild(ctx).await {
+        ctx.say("Only members who can manage the server can spend its treasury")
+            .await?;
+        return Ok(());
+    }
+    let first_close = parse_date_time(ctx, &first_close, time_zone)
+        .context("failed parsing first close date and time")?;
+    let Update {
+        after,
+        value: template_id,
+        ..
+    } = ctx.data().economy.update(|economy| {
+        economy.create_template(
+            ctx.author().id,
+            question.clone(),
+            description.clone(),
+            recurrence,
+            first_close.timezone().name().to_string(),
+            payer,
+            first_close.with_timezone(&chrono::Utc),
+            ctx.channel_id(),
+            guild,
+        )
+    })?;
+    let template = after.template(template_id)?;
+    ctx.send(
+        poise::CreateReply::default().embed(
+            CreateEmbed::new()
+                .color(Color::GOLD)
+                .title("Created template:")
+                .fields([template_to_field(template)]),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// List this server's templates
+#[poise::command(slash_command, guild_only, rename = "list")]
+pub async fn template_list(ctx: Context<'_>) -> Result<()> {
+    let economy = ctx.data().economy.snapshot();
+    let fields = economy
+        .list_templates()
+        .filter(|template| Some(template.guild) == ctx.guild_id())
+        .map(template_to_field)
+        .collect();
+    let pages = pagination::field_pages(fields, DEFAULT_PAGE_SIZE, || {
+        CreateEmbed::new()
+            .color(Color::DARK_BLUE)
+            .title("Templates")
+    });
+    pagination::paginate(ctx, pages).await
+}
+
+/// Show every market a template made and how it resolved
+#[poise::command(slash_command, guild_only, rename = "history")]
+pub async fn template_history(
+    ctx: Context<'_>,
+    #[description = "Template to show the markets of"]
+    #[autocomplete = "autocomplete_template"]
+    template: TemplateId,
+    #[description = "Number of lines on each page (default is 10)"]
+    #[min = 1]
+    #[max = 25]
+    page_size: Option<usize>,
+) -> Result<()> {
+    let economy = ctx.data().economy.snapshot();
+    let template = economy.template(template)?;
+    // Newest markets first
+    let lines = template
+        .instances
+        .iter()
+        .rev()
+        .map(|instance| {
+            let status = match (instance.outcome, economy.market(instance.market_id)) {
+                (Some(outcome), _) => format!("resolved {outcome}"),
+                (None, Ok(market)) if market.is_open() => {
+                    format!("open at {}%", market.probability())
+                }
+                (None, Ok(market)) => format!("closed at {}%", market.probability()),
+                (None, Err(_)) => "deleted".into(),
+            };
+            format!(
+                "__{}__ {} (closes <t:{}:d>): {status}",
+                instance.market_id,
+                instance.question,
+                instance.close_time.timestamp()
+            )
+        })
+        .collect();
+    let title = truncate(&format!("Markets from {}", template.question), TITLE_LIMIT);
+    let pages = pagination::line_pages(lines, page_size.unwrap_or(DEFAULT_PAGE_SIZE), || {
+        CreateEmbed::new().color(Color::DARK_BLUE).title(&title)
+    });
+    pagination::paginate(ctx, pages).await
+}
+
+/// Stop a template from creating markets, leaving the ones it already made alone
+#[poise::command(slash_command, guild_only, ephemeral, rename = "delete")]
+pub async fn template_delete(
+    ctx: Context<'_>,
+    #[description = "Template to delete"]
+    #[autocomplete = "autocomplete_template"]
+    template: TemplateId,
+) -> Result<()> {
+    let is_manager = author_manages_guild(ctx).await;
+    ctx.data().economy.update(|economy| {
+        Ok((
+            economy.delete_template(ctx.author().id, template, is_manager)?,
+            (),
+        ))
+    })?;
+    ctx.say(format!("Deleted template {template}")).await?;
+    Ok(())
+}
+
+/// See or change the money this server has put aside, for example to pay for template markets
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands("treasury_show", "treasury_deposit", "treasury_withdraw"),
+    subcommand_required
+)]
+pub async fn treasury(_ctx: Context<'_>) -> Result<()> {
+    Ok(())
+}
+
+/// Show how much is in this server's treasury
+#[poise::command(slash_command, guild_only, rename = "show")]
+pub async fn treasury_show(ctx: Context<'_>) -> Result<()> {
+    let guild = ctx.guild_id().context("treasuries only exist in servers")?;
+    let economy = ctx.data().economy.snapshot();
+    ctx.say(format!(
+        "The server treasury has {}",
+        economy
+            .treasury(guild)
+            .with_precision(economy.preferences(&ctx.author().id).decimal_places)
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Put some of your money in this server's treasury
+#[poise::command(slash_command, guild_only, rename = "deposit")]
+pub async fn treasury_deposit(
+    ctx: Context<'_>,
+    #[description = "Amount of money to put in"] amount: f64,
+) -> Result<()> {
+    let guild = ctx.guild_id().context("treasuries only exist in servers")?;
+    let amount = Money(amount);
+    let Update { after, .. } = ctx.data().economy.update(|economy| {
+        economy
+            .deposit_to_treasury(ctx.author().id, guild, amount)
+            .map(|economy| (economy, ()))
+    })?;
+    let decimal_places = after.preferences(&ctx.author().id).decimal_places;
+    ctx.say(format!(
+        "Deposited {} in the server treasury, which now has {}",
+        amount.with_precision(decimal_places),
+        after.treasury(guild).with_precision(decimal_places)
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Take money out of this server's treasury
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    ephemeral,
+    rename = "withdraw"
+)]
+pub async fn treasury_withdraw(
+    ctx: Context<'_>,
+    #[description = "Amount of money to take out"] amount: f64,
+) -> Result<()> {
+    let guild = ctx.guild_id().context("treasuries only exist in servers")?;
+    let amount = Money(amount);
+    let Update { after, .. } = ctx.data().economy.update(|economy| {
+        economy
+            .withdraw_from_treasury(ctx.author().id, guild, amount)
+            .map(|economy| (economy, ()))
+    })?;
+    let decimal_places = after.preferences(&ctx.author().id).decimal_places;
+    ctx.say(format!(
+        "Withdrew {} from the server treasury, which now has {}",
+        amount.with_precision(decimal_places),
+        after.treasury(guild).with_precision(decimal_places)
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Get a personal token for trading through the HTTP API, or revoke yours
+#[poise::command(slash_command, ephemeral)]
+pub async fn api_token(
+    ctx: Context<'_>,
+    #[description = "Revoke your token instead of getting a new one"] revoke: Option<bool>,
+) -> Result<()> {
+    let user = ctx.author().id;
+    if revoke.unwrap_or(false) {
+        ctx.data()
+            .economy
+            .update(|economy| Ok((economy.revoke_api_token(&user)?, ())))?;
+        ctx.say("Your API token was revoked").await?;
+        return Ok(());
+    }
+    let (token, stored) = api::generate_token(user);
+    ctx.data()
+        .economy
+        .update(|economy| Ok((economy.set_api_token(user, stored.clone()), ())))?;
+    ctx.say(format!(
+        "Your new API token is `{token}`\n\
+         It replaces any token you had before, and won't be shown again. \
+         Keep it secret, since anyone with it can trade and tip with your money."
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Download trades, markets and balances as CSV or JSON files
+#[poise::command(
+    slash_command,
+    subcommands("export_market", "export_trades", "export_economy"),
+    subcommand_required
+)]
+pub async fn export(_ctx: Context<'_>) -> Result<()> {
+    Ok(())
+}
+
+/// Download every trade in a market
+#[poise::command(slash_command, ephemeral, rename = "market")]
+pub async fn export_market(
+    ctx: Context<'_>,
+    #[description = "Market to export the trades of"]
+    #[autocomplete = "autocomplete_market"]
+    market: MarketId,
+    #[description = "File format (default is CSV)"] format: Option<ExportFormat>,
+) -> Result<()> {
+    let economy = ctx.data().economy.snapshot();
+    let trades = export::market_trades(economy.market(market)?);
+    send_trades(
+        ctx,
+        format.unwrap_or(ExportFormat::Csv),
+        &format!("market-{market}-trades"),
+        &trades,
+    )
+    .await
+}
+
+/// Download every trade you made, including in resolved markets
+#[poise::command(slash_command, ephemeral, rename = "trades")]
+pub async fn export_trades(
+    ctx: Context<'_>,
+    #[description = "File format (default is CSV)"] format: Option<ExportFormat>,
+) -> Result<()> {
+    let economy = ctx.data().economy.snapshot();
+    let trades = export::user_trades(&economy, ctx.author().id);
+    send_trades(
+        ctx,
+        format.unwrap_or(ExportFormat::Csv),
+        "my-trades",
+        &trades,
+    )
+    .await
+}
+
+/// Download the whole economy, across every server
+#[poise::command(slash_command, ephemeral, owners_only, rename = "economy")]
+pub async fn export_economy(
+    ctx: Context<'_>,
+    #[description = "File format (default is CSV, with one file per table)"] format: Option<
+        ExportFormat,
+    >,
+) -> Result<()> {
+    let economy = ctx.data().economy.snapshot();
+    let export = export::economy(&economy);
+    let attachments = match format.unwrap_or(ExportFormat::Csv) {
+        ExportFormat::Csv => export
+            .to_csv_files()?
+            .into_iter()
+            .map(|(table, data)| CreateAttachment::bytes(data, format!("{table}.csv")))
+            .collect(),
+        ExportFormat::Json => vec![CreateAttachment::bytes(
+            export::to_json(&export)?,
+            "economy.json",
+        )],
+    };
+    let reply = attachments.into_iter().fold(
+        poise::CreateReply::default().content("The whole economy, with times in UTC"),
+        poise::CreateReply::attachment,
+    );
+    ctx.send(reply).await?;
+    Ok(())
+}
+
+/// Reply with `trades` as a single file named `name`
+async fn send_trades(
+    ctx: Context<'_>,
+    format: ExportFormat,
+    name: &str,
+    trades: &[TradeRow],
+) -> Result<()> {
+    let data = match format {
+        ExportFormat::Csv => export::to_csv(trades)?,
+        ExportFormat::Json => export::to_json(trades)?,
+    };
+    ctx.send(
+        poise::CreateReply::default()
+            .content(format!("{} trades, with times in UTC", trades.len()))
+            .attachment(CreateAttachment::bytes(
+                data,
+                format!("{name}.{}", format.extension()),
+            )),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Create markets from a JSON file of Manifold markets
+#[poise::command(slash_command, ephemeral, owners_only)]
+pub async fn import_markets(
+    ctx: Context<'_>,
+    #[description = "JSON file of markets, optionally with a map of creators to Discord users"]
+    file: serenity::Attachment,
+) -> Result<()> {
+    let json = file
+        .download()
+        .await
+        .context("failed downloading the file")?;
+    let (markets, mut errors) = import::parse(&json)?;
+    let Update {
+        value: report,
+        after,
+        ..
+    } = ctx.data().economy.update(|economy| {
+        Ok(import::import(
+            economy,
+            ctx.author().id,
+            &markets,
+            Some(ctx.channel_id()),
+            ctx.guild_id(),
+        ))
+    })?;
+    errors.extend(report.errors);
+    errors.sort_by_key(|error| error.row);
+    let created_lines = report
+        .created
+        .iter()
+        .filter_map(|market_id| after.market(*market_id).ok())
+        .map(|market| {
+            format!(
+                "__{}__ {} **{}**_%_",
+                market.id,
+                market.question,
+                market.probability()
+            )
+        })
+        .collect::<Vec<_>>();
+    let error_lines = errors
+        .iter()
+        .map(|error| match &error.question {
+            Some(question) => format!("Row {} ({}): {}", error.row, question, error.error),
+            None => format!("Row {}: {}", error.row, error.error),
+        })
+        .collect::<Vec<_>>();
+    ctx.send(
+        poise::CreateReply::default().embed(
+            CreateEmbed::new()
+                .color(if errors.is_empty() {
+                    Color::GOLD
+                } else {
+                    Color::ORANGE
+                })
+                .title(format!(
+                    "Imported {} of {} markets",
+                    report.created.len(),
+                    report.created.len() + errors.len()
+                ))
+                .field(
+                    "Created",
+                    lines_to_field_value(&created_lines, false, Some("/list_markets")),
+                    false,
+                )
+                .field(
+                    "Failed",
+                    lines_to_field_value(&error_lines, false, None),
+                    false,
+                ),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
 /// Register slash commands
 #[poise::command(slash_command, prefix_command, owners_only)]
 pub async fn register(ctx: Context<'_>) -> Result<()> {
@@ -465,14 +1819,12 @@ pub async fn register(ctx: Context<'_>) -> Result<()> {
 pub async fn input_time(
     ctx: Context<'_>,
     date_time: String,
-    #[autocomplete = "autocomplete_tz"] timezone: String,
-) -> Result<()> {
-    let timezone = timezone.parse::<chrono_tz::Tz>().unwrap();
-    let date_time_parsed = chrono_english::parse_date_string(
-        &date_time,
-        chrono::Local::now().with_timezone(&timezone),
-        chrono_english::Dialect::Us,
-    )?;
+    #[description = "Time zone to use (default is yours or the server's)"]
+    #[autocomplete = "autocomplete_tz"]
+    timezone: Option<String>,
+) -> Result<()> {
+    let date_time_parsed = parse_date_time(ctx, &date_time, timezone)?;
+    let timezone = date_time_parsed.timezone();
     let timestamp = date_time_parsed.timestamp();
     ctx.send(
         poise::CreateReply::default().embed(
@@ -521,3 +1873,116 @@ pub async fn input_time(
     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use poise::serenity_prelude::{ChannelId, GuildId};
+
+    #[test]
+    fn truncates_long_text_with_a_marker() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("exactly10!", 10), "exactly10!");
+        assert_eq!(truncate("a bit too long", 10), "a bit too…");
+        // Counted in characters, not bytes
+        assert_eq!(truncate("ééééé", 3), "éé…");
+    }
+
+    #[test]
+    fn keeps_autocomplete_names_within_discords_limit() {
+        let name = truncate(&"?".repeat(500), AUTOCOMPLETE_NAME_LIMIT);
+        assert_eq!(name.chars().count(), AUTOCOMPLETE_NAME_LIMIT);
+    }
+
+    #[test]
+    fn field_values_fit_and_say_what_was_left_out() {
+        let lines = (0..200)
+            .map(|i| format!("Line number {i}"))
+            .collect::<Vec<_>>();
+        let first = lines_to_field_value(&lines, false, Some("/market_history"));
+        assert!(first.chars().count() <= FIELD_VALUE_LIMIT);
+        assert!(first.starts_with("Line number 0\n"));
+        assert!(first.ends_with("more, see `/market_history`"));
+
+        let last = lines_to_field_value(&lines, true, None);
+        assert!(last.chars().count() <= FIELD_VALUE_LIMIT);
+        assert!(last.starts_with("… and "));
+        assert!(last.ends_with("Line number 199"));
+    }
+
+    #[test]
+    fn field_values_keep_everything_that_fits() {
+        let lines = vec!["One".to_string(), "Two".to_string()];
+        assert_eq!(lines_to_field_value(&lines, false, None), "One\nTwo");
+        assert_eq!(lines_to_field_value(&[], false, None), "None");
+    }
+
+    #[test]
+    fn template_fields_have_one_line_per_fact() {
+        let template = Template {
+            id: 3,
+            creator: UserId::new(1),
+            question: "Rain on {date}?".into(),
+            description: String::new(),
+            recurrence: Recurrence::Weekly,
+            time_zone: "America/New_York".into(),
+            payer: TemplatePayer::Treasury,
+            next_open: DateTime::from_timestamp(1_900_000_000, 0).unwrap(),
+            next_close: DateTime::from_timestamp(1_900_604_800, 0).unwrap(),
+            channel: ChannelId::new(2),
+            guild: GuildId::new(4),
+            instances: Vec::new(),
+        };
+        let (name, value, inline) = template_to_field(&template);
+        assert_eq!(name, "__3__   Rain on {date}?");
+        assert_eq!(
+            value,
+            "weekly by <@1>, paid by the server treasury, in <#2>\n\
+             Next market <t:1900000000:R>, closing <t:1900604800:F>\n\
+             0 markets so far"
+        );
+        assert!(!inline);
+    }
+
+    #[test]
+    fn market_lines_show_the_chosen_decimal_places() {
+        let (alice, bob) = (UserId::new(2), UserId::new(3));
+        let (economy, market_id) = Economy::new()
+            .create_market(
+                UserId::new(1),
+                "Question?".into(),
+                String::new(),
+                None,
+                0.5,
+                Money(0.0),
+                None,
+                None,
+            )
+            .unwrap();
+        let (economy, _) = economy
+            .buy(alice, market_id, Money(10.0), ShareKind::Yes)
+            .unwrap();
+        let (economy, _) = economy
+            .buy(bob, market_id, Money(1.0), ShareKind::No)
+            .unwrap();
+        let market = economy.market(market_id).unwrap();
+
+        let alice_shares = market.num_user_shares[&alice].quantity.0;
+        assert!(market_position_lines(market, 1)
+            .contains(&format!("{} - {alice_shares:.1} YES", Mention::User(alice))));
+        let transactions = market_transaction_lines(market, 0);
+        assert!(transactions[0].ends_with(&format!(
+            "{} BUY {alice_shares:.0} YES for $10 | {}%",
+            Mention::User(alice),
+            market.transaction_history[0].new_probability
+        )));
+
+        let (_, resolution) = economy
+            .resolve_market(UserId::new(1), market_id, ResolveOutcome::Yes)
+            .unwrap();
+        let alice_payout = resolution.payouts[&alice].0;
+        assert!(payout_lines(&resolution, ResolveOutcome::Yes, 3)
+            .contains(&format!("{} ${alice_payout:.3}", Mention::User(alice))));
+    }
+}
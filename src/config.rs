@@ -0,0 +1,578 @@
+use anyhow::{bail, ensure, Context, Result};
+use poise::serenity_prelude::{GatewayIntents, UserId};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::storage::BackupPolicy;
+
+/// Where the config file is read from, unless `CONFIG_PATH` says otherwise
+const DEFAULT_PATH: &str = "config.toml";
+
+/// Everything about how the bot runs that isn't saved in the economy, read from a TOML file and
+/// the environment at startup
+pub struct Config {
+    /// The file the config was read from, or `None` if there was none and only the environment
+    /// and defaults were used
+    pub path: Option<PathBuf>,
+    pub discord_token: String,
+    /// Tracing filter directives, like `info` or `discord_prediction_market_bot=debug`
+    pub log_level: String,
+    pub state: StateConfig,
+    /// Names of the commands to register, or `None` for all of them
+    pub commands: Option<Vec<String>>,
+    /// Users allowed to run owner-only commands, on top of the bot application's owner
+    pub owners: HashSet<UserId>,
+    /// Time zone of servers that haven't set one with `/config`, and outside of servers
+    pub default_time_zone: String,
+    pub gateway_intents: GatewayIntents,
+    /// Time between reminders to resolve a closed market, or `None` to never remind
+    pub reminder_interval: Option<chrono::Duration>,
+    /// How to serve the HTTP API, or `None` if it's disabled
+    pub api: Option<ApiConfig>,
+    /// Where to serve metrics on their own, or `None` to only serve them with the API
+    pub metrics_address: Option<SocketAddr>,
+}
+
+pub struct ApiConfig {
+    pub address: SocketAddr,
+    /// Requests each API token can make per minute
+    pub requests_per_minute: u32,
+}
+
+pub struct StateConfig {
+    pub backend: Backend,
+    pub path: PathBuf,
+    pub backup_policy: BackupPolicy,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Json,
+    Sqlite,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Self::Json),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => bail!("expected \"json\" or \"sqlite\""),
+        }
+    }
+}
+
+/// The config file as written, before environment overrides and validation
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    discord_token: Option<String>,
+    log_level: String,
+    state: StateFile,
+    commands: Option<Vec<String>>,
+    owners: Vec<u64>,
+    default_time_zone: String,
+    gateway_intents: Option<Vec<String>>,
+    close_reminder_days: i64,
+    api: ApiFile,
+    metrics: MetricsFile,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ApiFile {
+    enabled: bool,
+    address: SocketAddr,
+    requests_per_minute: u32,
+}
+
+impl Default for ApiFile {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            requests_per_minute: 30,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MetricsFile {
+    enabled: bool,
+    address: SocketAddr,
+}
+
+impl Default for MetricsFile {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: SocketAddr::from(([127, 0, 0, 1], 9090)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StateFile {
+    backend: Backend,
+    path: Option<PathBuf>,
+    backup_count: usize,
+    backup_interval_minutes: u64,
+}
+
+impl Default for File {
+    fn default() -> Self {
+        Self {
+            discord_token: None,
+            log_level: "info".into(),
+            state: StateFile::default(),
+            commands: None,
+            owners: Vec::new(),
+            default_time_zone: crate::prediction_market::Settings::default().default_time_zone,
+            gateway_intents: None,
+            close_reminder_days: 3,
+            api: ApiFile::default(),
+            metrics: MetricsFile::default(),
+        }
+    }
+}
+
+impl Default for StateFile {
+    fn default() -> Self {
+        let backup_policy = BackupPolicy::default();
+        Self {
+            backend: Backend::Json,
+            path: None,
+            backup_count: backup_policy.count,
+            backup_interval_minutes: backup_policy.interval.as_secs() / 60,
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file at `CONFIG_PATH` (`config.toml` by default), override it with any
+    /// environment variables that are set, and check that the result makes sense. A missing
+    /// `config.toml` is fine, but a missing file named by `CONFIG_PATH` is not.
+    pub fn load() -> Result<Config> {
+        let (path, mut file) = match std::env::var("CONFIG_PATH") {
+            Ok(path) => {
+                let path = PathBuf::from(path);
+                let file = read_file(&path)?;
+                (Some(path), file)
+            }
+            Err(_) if Path::new(DEFAULT_PATH).exists() => (
+                Some(DEFAULT_PATH.into()),
+                read_file(Path::new(DEFAULT_PATH))?,
+            ),
+            Err(_) => (None, File::default()),
+        };
+        file.apply_env(&|name| std::env::var(name).ok())?;
+        file.validate(path)
+    }
+}
+
+fn read_file(path: &Path) -> Result<File> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed reading config file {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))
+}
+
+/// Looks up an environment variable, so tests don't have to change the real environment
+type Env<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Replace `field` with the value of the environment variable `name`, if it's set
+fn env_override<T: FromStr>(env: Env, name: &str, field: &mut T) -> Result<()>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Some(value) = env(name) {
+        *field = value
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid {name} {value:?}: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Replace `field` with the comma-separated list in the environment variable `name`, if it's set
+fn env_list_override<T: FromStr>(env: Env, name: &str, field: &mut Vec<T>) -> Result<()>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Some(value) = env(name) {
+        *field = value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                item.parse()
+                    .map_err(|e| anyhow::anyhow!("invalid {name} entry {item:?}: {e}"))
+            })
+            .collect::<Result<_>>()?;
+    }
+    Ok(())
+}
+
+impl File {
+    fn apply_env(&mut self, env: Env) -> Result<()> {
+        if let Some(token) = env("DISCORD_TOKEN") {
+            self.discord_token = Some(token);
+        }
+        // `RUST_LOG` keeps working the way it does for any program using tracing
+        env_override(env, "RUST_LOG", &mut self.log_level)?;
+        env_override(env, "STATE_BACKEND", &mut self.state.backend)?;
+        if let Some(path) = env("STATE_PATH") {
+            self.state.path = Some(path.into());
+        }
+        env_override(env, "BACKUP_COUNT", &mut self.state.backup_count)?;
+        env_override(
+            env,
+            "BACKUP_INTERVAL_MINUTES",
+            &mut self.state.backup_interval_minutes,
+        )?;
+        if env("ENABLED_COMMANDS").is_some() {
+            env_list_override(
+                env,
+                "ENABLED_COMMANDS",
+                self.commands.get_or_insert_with(Vec::new),
+            )?;
+        }
+        env_list_override(env, "OWNERS", &mut self.owners)?;
+        env_override(env, "DEFAULT_TIME_ZONE", &mut self.default_time_zone)?;
+        if env("GATEWAY_INTENTS").is_some() {
+            env_list_override(
+                env,
+                "GATEWAY_INTENTS",
+                self.gateway_intents.get_or_insert_with(Vec::new),
+            )?;
+        }
+        env_override(env, "CLOSE_REMINDER_DAYS", &mut self.close_reminder_days)?;
+        env_override(env, "API_ENABLED", &mut self.api.enabled)?;
+        env_override(env, "API_ADDRESS", &mut self.api.address)?;
+        env_override(
+            env,
+            "API_REQUESTS_PER_MINUTE",
+            &mut self.api.requests_per_minute,
+        )?;
+        env_override(env, "METRICS_ENABLED", &mut self.metrics.enabled)?;
+        env_override(env, "METRICS_ADDRESS", &mut self.metrics.address)?;
+        Ok(())
+    }
+
+    fn validate(self, path: Option<PathBuf>) -> Result<Config> {
+        let discord_token = self
+            .discord_token
+            .filter(|token| !token.is_empty())
+            .context(
+                "no Discord bot token, set discord_token in the config file or DISCORD_TOKEN",
+            )?;
+        tracing_subscriber::EnvFilter::try_new(&self.log_level)
+            .with_context(|| format!("invalid log_level {:?}", self.log_level))?;
+        ensure!(
+            self.default_time_zone.parse::<chrono_tz::Tz>().is_ok(),
+            "invalid default_time_zone {:?}, expected an IANA name like \"US/Eastern\"",
+            self.default_time_zone
+        );
+        ensure!(
+            self.close_reminder_days >= 0,
+            "close_reminder_days can't be negative, use 0 to turn reminders off"
+        );
+        let reminder_interval = match self.close_reminder_days {
+            0 => None,
+            days => Some(
+                chrono::Duration::try_days(days)
+                    .with_context(|| format!("close_reminder_days {days} is too large"))?,
+            ),
+        };
+        if let Some(commands) = &self.commands {
+            ensure!(
+                !commands.is_empty(),
+                "commands is empty, leave it out to enable every command"
+            );
+        }
+        ensure!(
+            !self.owners.contains(&0),
+            "0 isn't a valid user ID in owners"
+        );
+        ensure!(
+            self.api.requests_per_minute > 0,
+            "api.requests_per_minute must be at least 1"
+        );
+        ensure!(
+            !(self.api.enabled && self.metrics.enabled && self.api.address == self.metrics.address),
+            "api.address and metrics.address are both {}, the API already serves /metrics",
+            self.api.address
+        );
+        let gateway_intents = match self.gateway_intents {
+            None => GatewayIntents::non_privileged(),
+            Some(names) => names
+                .iter()
+                .try_fold(GatewayIntents::empty(), |intents, name| {
+                    let intent =
+                        GatewayIntents::from_name(&name.to_uppercase()).with_context(|| {
+                            format!(
+                                "unknown gateway intent {name:?}, expected a name like \
+                         \"GUILD_MESSAGES\" or \"MESSAGE_CONTENT\""
+                            )
+                        })?;
+                    Ok::<_, anyhow::Error>(intents | intent)
+                })?,
+        };
+        let state_path = self.state.path.unwrap_or_else(|| match self.state.backend {
+            Backend::Json => "state.json".into(),
+            Backend::Sqlite => "state.sqlite".into(),
+        });
+        Ok(Config {
+            path,
+            discord_token,
+            log_level: self.log_level,
+            state: StateConfig {
+                backend: self.state.backend,
+                path: state_path,
+                backup_policy: BackupPolicy {
+                    count: self.state.backup_count,
+                    interval: Duration::from_secs(
+                        self.state.backup_interval_minutes.saturating_mul(60),
+                    ),
+                },
+            },
+            commands: self.commands,
+            owners: self.owners.into_iter().map(UserId::new).collect(),
+            default_time_zone: self.default_time_zone,
+            gateway_intents,
+            reminder_interval,
+            api: self.api.enabled.then_some(ApiConfig {
+                address: self.api.address,
+                requests_per_minute: self.api.requests_per_minute,
+            }),
+            metrics_address: self.metrics.enabled.then_some(self.metrics.address),
+        })
+    }
+}
+
+/// Keep only the enabled commands, in their original order
+pub fn enabled_commands<U, E>(
+    all: Vec<poise::Command<U, E>>,
+    enabled: Option<&[String]>,
+) -> Result<Vec<poise::Command<U, E>>> {
+    let Some(enabled) = enabled else {
+        return Ok(all);
+    };
+    for name in enabled {
+        ensure!(
+            all.iter().any(|command| &command.name == name),
+            "unknown command {name:?} in commands, expected one of {}",
+            all.iter()
+                .map(|command| command.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(all
+        .into_iter()
+        .filter(|command| enabled.contains(&command.name))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn parse(text: &str) -> File {
+        toml::from_str(text).unwrap()
+    }
+
+    /// Apply `vars` as the environment and validate
+    fn load(mut file: File, vars: &[(&str, &str)]) -> Result<Config> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        file.apply_env(&|name| vars.get(name).map(|value| value.to_string()))?;
+        file.validate(None)
+    }
+
+    #[test]
+    fn defaults_need_only_a_token() {
+        assert!(load(File::default(), &[]).is_err());
+        let config = load(File::default(), &[("DISCORD_TOKEN", "token")]).unwrap();
+        assert_eq!(config.discord_token, "token");
+        assert_eq!(config.log_level, "info");
+        assert!(config.state.backend == Backend::Json);
+        assert_eq!(config.state.path, PathBuf::from("state.json"));
+        assert_eq!(config.commands, None);
+        assert!(config.owners.is_empty());
+        assert_eq!(config.gateway_intents, GatewayIntents::non_privileged());
+        assert_eq!(config.reminder_interval, Some(chrono::Duration::days(3)));
+        assert!(config.api.is_none());
+        assert_eq!(config.metrics_address, None);
+    }
+
+    #[test]
+    fn parses_the_file() {
+        let file = parse(
+            r#"
+            discord_token = "token"
+            log_level = "debug"
+            commands = ["buy", "sell"]
+            owners = [1, 2]
+            default_time_zone = "Europe/Paris"
+            gateway_intents = ["guild_messages"]
+            close_reminder_days = 0
+
+            [state]
+            backend = "sqlite"
+            backup_count = 2
+            backup_interval_minutes = 5
+
+            [api]
+            enabled = true
+            address = "0.0.0.0:9000"
+            requests_per_minute = 10
+
+            [metrics]
+            enabled = true
+            "#,
+        );
+        let config = load(file, &[]).unwrap();
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(
+            config.commands,
+            Some(vec!["buy".to_string(), "sell".to_string()])
+        );
+        assert_eq!(
+            config.owners,
+            HashSet::from([UserId::new(1), UserId::new(2)])
+        );
+        assert_eq!(config.default_time_zone, "Europe/Paris");
+        assert_eq!(config.gateway_intents, GatewayIntents::GUILD_MESSAGES);
+        assert_eq!(config.reminder_interval, None);
+        assert!(config.state.backend == Backend::Sqlite);
+        assert_eq!(config.state.path, PathBuf::from("state.sqlite"));
+        assert_eq!(config.state.backup_policy.count, 2);
+        assert_eq!(
+            config.state.backup_policy.interval,
+            Duration::from_secs(300)
+        );
+        let api = config.api.unwrap();
+        assert_eq!(api.address, SocketAddr::from(([0, 0, 0, 0], 9000)));
+        assert_eq!(api.requests_per_minute, 10);
+        assert_eq!(
+            config.metrics_address,
+            Some(SocketAddr::from(([127, 0, 0, 1], 9090)))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(toml::from_str::<File>("discord_tokn = \"token\"").is_err());
+        assert!(toml::from_str::<File>("[state]\nbackup = 2").is_err());
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let file = parse(
+            r#"
+            discord_token = "file"
+            owners = [1]
+            commands = ["buy"]
+            [state]
+            path = "file.json"
+            "#,
+        );
+        let config = load(
+            file,
+            &[
+                ("DISCORD_TOKEN", "env"),
+                ("STATE_BACKEND", "sqlite"),
+                ("STATE_PATH", "env.sqlite"),
+                ("OWNERS", "2, 3,"),
+                ("ENABLED_COMMANDS", "sell,balance"),
+                ("CLOSE_REMINDER_DAYS", "7"),
+                ("API_ENABLED", "true"),
+                ("API_REQUESTS_PER_MINUTE", "5"),
+                ("METRICS_ENABLED", "true"),
+                ("METRICS_ADDRESS", "0.0.0.0:9100"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.discord_token, "env");
+        assert!(config.state.backend == Backend::Sqlite);
+        assert_eq!(config.state.path, PathBuf::from("env.sqlite"));
+        assert_eq!(
+            config.owners,
+            HashSet::from([UserId::new(2), UserId::new(3)])
+        );
+        assert_eq!(
+            config.commands,
+            Some(vec!["sell".to_string(), "balance".to_string()])
+        );
+        assert_eq!(config.reminder_interval, Some(chrono::Duration::days(7)));
+        assert_eq!(config.api.unwrap().requests_per_minute, 5);
+        assert_eq!(
+            config.metrics_address,
+            Some(SocketAddr::from(([0, 0, 0, 0], 9100)))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_environment_values() {
+        for (name, value) in [
+            ("STATE_BACKEND", "postgres"),
+            ("BACKUP_COUNT", "-1"),
+            ("OWNERS", "1,me"),
+            ("API_ENABLED", "yes"),
+            ("API_ADDRESS", "localhost"),
+        ] {
+            let error = load(
+                File::default(),
+                &[("DISCORD_TOKEN", "token"), (name, value)],
+            )
+            .err()
+            .unwrap();
+            assert!(error.to_string().contains(name), "{error}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        for vars in [
+            [("DISCORD_TOKEN", ""), ("RUST_LOG", "info")],
+            [("DISCORD_TOKEN", "token"), ("RUST_LOG", "info=what")],
+            [("DISCORD_TOKEN", "token"), ("DEFAULT_TIME_ZONE", "Nowhere")],
+            [("DISCORD_TOKEN", "token"), ("CLOSE_REMINDER_DAYS", "-1")],
+            [
+                ("DISCORD_TOKEN", "token"),
+                ("CLOSE_REMINDER_DAYS", "9223372036854775807"),
+            ],
+            [("DISCORD_TOKEN", "token"), ("ENABLED_COMMANDS", ",")],
+            [("DISCORD_TOKEN", "token"), ("OWNERS", "0")],
+            [("DISCORD_TOKEN", "token"), ("API_REQUESTS_PER_MINUTE", "0")],
+            [
+                ("DISCORD_TOKEN", "token"),
+                ("GATEWAY_INTENTS", "everything"),
+            ],
+        ] {
+            assert!(load(File::default(), &vars).is_err(), "{vars:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_serving_metrics_where_the_api_is() {
+        let vars = [
+            ("DISCORD_TOKEN", "token"),
+            ("API_ENABLED", "true"),
+            ("METRICS_ENABLED", "true"),
+        ];
+        assert!(load(File::default(), &vars).is_ok());
+        let mut file = File::default();
+        file.metrics.address = file.api.address;
+        assert!(load(file, &vars).is_err());
+    }
+}
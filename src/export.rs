@@ -0,0 +1,443 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use poise::serenity_prelude::UserId;
+use serde::Serialize;
+
+use crate::{
+    prediction_market::{Market, MarketId, ShareKind, TransactionInfo, TransactionKind},
+    Economy,
+};
+
+/// File format of an export
+#[derive(Copy, Clone, poise::ChoiceParameter)]
+pub enum ExportFormat {
+    #[name = "CSV"]
+    Csv,
+    #[name = "JSON"]
+    Json,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// One trade. Every export of trades has these columns, in this order.
+#[derive(Serialize)]
+pub struct TradeRow {
+    market_id: u64,
+    question: String,
+    /// User IDs are strings so spreadsheets and JavaScript don't round them
+    user_id: String,
+    kind: &'static str,
+    share_kind: &'static str,
+    shares: f64,
+    money: f64,
+    /// Percent chance of YES after the trade
+    probability_after: u8,
+    time: String,
+}
+
+#[derive(Serialize)]
+pub struct MarketRow {
+    market_id: u64,
+    question: String,
+    description: String,
+    creator_id: String,
+    /// Percent chance of YES, or the chance when the market resolved
+    probability: u8,
+    /// Empty once the market resolved and its pool was paid out
+    yes_pool: Option<f64>,
+    no_pool: Option<f64>,
+    created: String,
+    /// Empty if the market never closes
+    closes: Option<String>,
+    /// `YES`, `NO` or `UNDO`, or empty while the market is unresolved
+    outcome: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PositionRow {
+    market_id: u64,
+    user_id: String,
+    share_kind: &'static str,
+    shares: f64,
+}
+
+#[derive(Serialize)]
+pub struct BalanceRow {
+    user_id: String,
+    balance: f64,
+}
+
+/// Every row of the whole economy, which is exported as one JSON document or one CSV file per
+/// table
+#[derive(Serialize)]
+pub struct EconomyExport {
+    markets: Vec<MarketRow>,
+    positions: Vec<PositionRow>,
+    trades: Vec<TradeRow>,
+    balances: Vec<BalanceRow>,
+}
+
+/// Times are ISO 8601 in UTC, like `2025-04-20T15:05:00Z`
+fn iso_8601(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn share_kind(kind: ShareKind) -> &'static str {
+    match kind {
+        ShareKind::Yes => "YES",
+        ShareKind::No => "NO",
+    }
+}
+
+/// Every trade in `market`, oldest first
+pub fn market_trades(market: &Market<UserId>) -> Vec<TradeRow> {
+    market
+        .transaction_history
+        .iter()
+        .map(|transaction| trade_row(market.id, &market.question, transaction))
+        .collect()
+}
+
+fn trade_row(
+    market_id: MarketId,
+    question: &str,
+    transaction: &TransactionInfo<UserId>,
+) -> TradeRow {
+    TradeRow {
+        market_id,
+        question: question.to_owned(),
+        user_id: transaction.user.to_string(),
+        kind: match transaction.kind {
+            TransactionKind::Buy => "BUY",
+            TransactionKind::Sell => "SELL",
+        },
+        share_kind: share_kind(transaction.shares.kind),
+        shares: transaction.shares.quantity.0,
+        money: transaction.money.0,
+        probability_after: transaction.new_probability,
+        time: iso_8601(transaction.time),
+    }
+}
+
+/// Every trade in every market that `keep` accepts, including resolved markets, oldest first
+fn all_trades(economy: &Economy, keep: impl Fn(&TransactionInfo<UserId>) -> bool) -> Vec<TradeRow> {
+    let unresolved = economy.list_markets().flat_map(|market| {
+        market
+            .transaction_history
+            .iter()
+            .map(|transaction| (market.id, &market.question, transaction))
+    });
+    let resolved = economy.list_resolved_markets().flat_map(|market| {
+        market
+            .transaction_history
+            .iter()
+            .map(|transaction| (market.id, &market.question, transaction))
+    });
+    let mut trades = unresolved
+        .chain(resolved)
+        .filter(|(_, _, transaction)| keep(transaction))
+        .collect::<Vec<_>>();
+    // Sorted before the times are rounded to seconds for the rows
+    trades.sort_by_key(|(_, _, transaction)| transaction.time);
+    trades
+        .into_iter()
+        .map(|(market_id, question, transaction)| trade_row(market_id, question, transaction))
+        .collect()
+}
+
+/// Every trade `user` made, oldest first
+pub fn user_trades(economy: &Economy, user: UserId) -> Vec<TradeRow> {
+    all_trades(economy, |transaction| transaction.user == user)
+}
+
+fn closes(close_timestamp: Option<i64>) -> Option<String> {
+    close_timestamp
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .map(iso_8601)
+}
+
+/// Every market, including resolved ones so every exported trade has its market, in the order
+/// they were created
+fn all_markets(economy: &Economy) -> Vec<MarketRow> {
+    let unresolved = economy.list_markets().map(|market| {
+        let (yes_pool, no_pool) = market.pool();
+        MarketRow {
+            market_id: market.id,
+            question: market.question.clone(),
+            description: market.description.clone(),
+            creator_id: market.creator.to_string(),
+            probability: market.probability(),
+            yes_pool: Some(yes_pool.0),
+            no_pool: Some(no_pool.0),
+            created: iso_8601(market.creation_time),
+            closes: closes(market.close_timestamp),
+            outcome: None,
+        }
+    });
+    let resolved = economy.list_resolved_markets().map(|market| MarketRow {
+        market_id: market.id,
+        question: market.question.clone(),
+        description: market.description.clone(),
+        creator_id: market.creator.to_string(),
+        probability: market.probability,
+        yes_pool: None,
+        no_pool: None,
+        created: iso_8601(market.creation_time),
+        closes: closes(market.close_timestamp),
+        outcome: Some(market.outcome.to_string()),
+    });
+    let mut markets = unresolved.chain(resolved).collect::<Vec<_>>();
+    markets.sort_by_key(|market| market.market_id);
+    markets
+}
+
+pub fn economy(economy: &Economy) -> EconomyExport {
+    let trades = all_trades(economy, |_| true);
+    EconomyExport {
+        markets: all_markets(economy),
+        positions: economy
+            .list_markets()
+            .flat_map(|market| {
+                market
+                    .num_user_shares
+                    .iter()
+                    .map(|(user, shares)| PositionRow {
+                        market_id: market.id,
+                        user_id: user.to_string(),
+                        share_kind: share_kind(shares.kind),
+                        shares: shares.quantity.0,
+                    })
+            })
+            .collect(),
+        trades,
+        balances: economy
+            .balances()
+            .into_iter()
+            .map(|(user, balance)| BalanceRow {
+                user_id: user.to_string(),
+                balance: balance.0,
+            })
+            .collect(),
+    }
+}
+
+/// A row of an exported table, whose columns are the same in every export
+pub trait Row: Serialize {
+    /// Column names, in the order the fields are serialized
+    const COLUMNS: &'static [&'static str];
+}
+
+impl Row for TradeRow {
+    const COLUMNS: &'static [&'static str] = &[
+        "market_id",
+        "question",
+        "user_id",
+        "kind",
+        "share_kind",
+        "shares",
+        "money",
+        "probability_after",
+        "time",
+    ];
+}
+
+impl Row for MarketRow {
+    const COLUMNS: &'static [&'static str] = &[
+        "market_id",
+        "question",
+        "description",
+        "creator_id",
+        "probability",
+        "yes_pool",
+        "no_pool",
+        "created",
+        "closes",
+        "outcome",
+    ];
+}
+
+impl Row for PositionRow {
+    const COLUMNS: &'static [&'static str] = &["market_id", "user_id", "share_kind", "shares"];
+}
+
+impl Row for BalanceRow {
+    const COLUMNS: &'static [&'static str] = &["user_id", "balance"];
+}
+
+/// Rows as a CSV file. The header is written even if there are no rows, which the CSV writer
+/// wouldn't do on its own.
+pub fn to_csv<T: Row>(rows: &[T]) -> Result<Vec<u8>> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    writer.write_record(T::COLUMNS)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.into_inner().context("failed writing CSV")
+}
+
+/// Anything as pretty-printed JSON
+pub fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec_pretty(value).context("failed writing JSON")
+}
+
+impl EconomyExport {
+    /// One CSV file per table, named after it
+    pub fn to_csv_files(&self) -> Result<Vec<(&'static str, Vec<u8>)>> {
+        Ok(vec![
+            ("markets", to_csv(&self.markets)?),
+            ("positions", to_csv(&self.positions)?),
+            ("trades", to_csv(&self.trades)?),
+            ("balances", to_csv(&self.balances)?),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{money::Money, prediction_market::ResolveOutcome};
+
+    const CREATOR: UserId = UserId::new(1);
+    const ALICE: UserId = UserId::new(2);
+    const BOB: UserId = UserId::new(3);
+
+    fn create_market(economy: &Economy, question: &str) -> (Economy, MarketId) {
+        economy
+            .create_market(
+                CREATOR,
+                question.into(),
+                String::new(),
+                None,
+                0.5,
+                Money(0.0),
+                None,
+                None,
+            )
+            .unwrap()
+    }
+
+    fn buy(economy: &Economy, user: UserId, market_id: MarketId, kind: ShareKind) -> Economy {
+        economy.buy(user, market_id, Money(10.0), kind).unwrap().0
+    }
+
+    /// Alice trades in a market that resolves, then Bob and Alice in one that doesn't
+    fn economy_with_trades() -> (Economy, MarketId, MarketId) {
+        let (economy, resolved) = create_market(&Economy::new(), "Resolved?");
+        let economy = buy(&economy, ALICE, resolved, ShareKind::Yes);
+        let (economy, _) = economy
+            .resolve_market(CREATOR, resolved, ResolveOutcome::Yes)
+            .unwrap();
+        let (economy, open) = create_market(&economy, "Open?");
+        let economy = buy(&economy, BOB, open, ShareKind::No);
+        let economy = buy(&economy, ALICE, open, ShareKind::No);
+        (economy, resolved, open)
+    }
+
+    #[test]
+    fn user_trades_include_resolved_markets() {
+        let (economy, resolved, open) = economy_with_trades();
+        let trades = user_trades(&economy, ALICE);
+        assert_eq!(
+            trades
+                .iter()
+                .map(|trade| (trade.market_id, trade.question.as_str()))
+                .collect::<Vec<_>>(),
+            [(resolved, "Resolved?"), (open, "Open?")]
+        );
+        assert!(trades.iter().all(|trade| trade.user_id == "2"));
+        assert_eq!(super::economy(&economy).trades.len(), 3);
+    }
+
+    #[test]
+    fn csv_has_a_header_even_without_rows() {
+        let csv = to_csv::<TradeRow>(&[]).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "market_id,question,user_id,kind,share_kind,shares,money,probability_after,time\n"
+        );
+        let csv = to_csv::<BalanceRow>(&[]).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "user_id,balance\n");
+    }
+
+    #[test]
+    fn csv_columns_match_the_header() {
+        let (economy, _, open) = economy_with_trades();
+        let market = economy.market(open).unwrap();
+        let trade = &market.transaction_history[0];
+        let csv = String::from_utf8(to_csv(&market_trades(market)).unwrap()).unwrap();
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let rows = reader
+            .deserialize::<std::collections::HashMap<String, String>>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        let row = &rows[0];
+        assert_eq!(row["market_id"], open.to_string());
+        assert_eq!(row["question"], "Open?");
+        assert_eq!(row["user_id"], "3");
+        assert_eq!(row["kind"], "BUY");
+        assert_eq!(row["share_kind"], "NO");
+        assert_eq!(row["money"], "10.0");
+        assert_eq!(row["probability_after"], trade.new_probability.to_string());
+        assert_eq!(row["time"], iso_8601(trade.time));
+    }
+
+    #[test]
+    fn times_are_iso_8601_in_utc() {
+        let time = DateTime::parse_from_rfc3339("2025-04-20T11:05:00.123-04:00")
+            .unwrap()
+            .to_utc();
+        assert_eq!(iso_8601(time), "2025-04-20T15:05:00Z");
+    }
+
+    #[test]
+    fn economy_exports_as_one_json_document() {
+        let (economy, resolved, open) = economy_with_trades();
+        let export: serde_json::Value =
+            serde_json::from_slice(&to_json(&super::economy(&economy)).unwrap()).unwrap();
+        let markets = export["markets"].as_array().unwrap();
+        assert_eq!(markets.len(), 2);
+        assert_eq!(markets[0]["market_id"], resolved);
+        assert_eq!(markets[0]["outcome"], "YES");
+        assert_eq!(markets[0]["yes_pool"], serde_json::Value::Null);
+        assert_eq!(markets[1]["market_id"], open);
+        assert_eq!(markets[1]["outcome"], serde_json::Value::Null);
+        assert_eq!(markets[1]["closes"], serde_json::Value::Null);
+        // A market seeded at 50% leaves its creator no shares
+        assert_eq!(export["positions"].as_array().unwrap().len(), 2);
+        assert_eq!(export["trades"].as_array().unwrap().len(), 3);
+        assert_eq!(export["balances"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn every_trade_has_its_market() {
+        let (economy, _, _) = economy_with_trades();
+        let export = super::economy(&economy);
+        assert!(export.trades.iter().all(|trade| export
+            .markets
+            .iter()
+            .any(|market| market.market_id == trade.market_id)));
+
+        let csv = String::from_utf8(to_csv(&export.markets).unwrap()).unwrap();
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        assert_eq!(reader.headers().unwrap(), MarketRow::COLUMNS);
+        let rows = reader
+            .deserialize::<std::collections::HashMap<String, String>>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        // The resolved market's pool is gone, and the open market has no outcome yet
+        assert_eq!(rows[0]["yes_pool"], "");
+        assert_eq!(rows[0]["outcome"], "YES");
+        assert_ne!(rows[1]["yes_pool"], "");
+        assert_eq!(rows[1]["outcome"], "");
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::Utc;
+use poise::serenity_prelude::{ChannelId, GuildId, UserId};
+use serde::Deserialize;
+
+use crate::{money::Money, prediction_market::MarketId, Economy};
+
+/// Least likely a market can start at, and how close to certain it can start
+const MIN_PROBABILITY: f64 = 0.01;
+
+/// A market as exported by Manifold's API. Fields this bot has no use for are ignored.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifoldMarket {
+    question: String,
+    /// Manifold's own exports have the description as rich text, with a plain text copy in
+    /// `textDescription`, so a plain string is only expected in hand-written files
+    #[serde(default)]
+    description: Option<serde_json::Value>,
+    #[serde(default)]
+    text_description: Option<String>,
+    /// Milliseconds since the Unix epoch
+    #[serde(default)]
+    close_time: Option<i64>,
+    /// Chance of YES, from 0 to 1
+    probability: f64,
+    #[serde(default)]
+    creator_username: Option<String>,
+    #[serde(default)]
+    outcome_type: Option<String>,
+    #[serde(default)]
+    is_resolved: bool,
+}
+
+/// A market from an import file that passed validation
+pub struct ImportedMarket {
+    /// Position in the file's list of markets, counting from 1
+    pub row: usize,
+    pub question: String,
+    pub description: String,
+    pub close_timestamp: Option<i64>,
+    pub probability: f64,
+    /// Who the creator was mapped to, or `None` if they weren't
+    pub creator: Option<UserId>,
+}
+
+/// A row of an import file that couldn't be imported
+pub struct RowError {
+    /// Position in the file's list of markets, counting from 1
+    pub row: usize,
+    /// The row's question, if it had one
+    pub question: Option<String>,
+    pub error: String,
+}
+
+/// What came of importing a file
+pub struct ImportReport {
+    pub created: Vec<MarketId>,
+    pub errors: Vec<RowError>,
+}
+
+/// Read an import file, which is either a list of markets or an object with the list in
+/// `markets` and a map from Manifold usernames to Discord user IDs in `creators`. Returns the
+/// rows that are valid and errors for the rest.
+pub fn parse(json: &[u8]) -> Result<(Vec<ImportedMarket>, Vec<RowError>)> {
+    let file: serde_json::Value = serde_json::from_slice(json).context("invalid JSON")?;
+    let (rows, creators) = match file {
+        serde_json::Value::Array(rows) => (rows, HashMap::new()),
+        serde_json::Value::Object(mut file) => {
+            let rows = match file.remove("markets") {
+                Some(serde_json::Value::Array(rows)) => rows,
+                _ => bail!("expected a list of markets in \"markets\""),
+            };
+            let creators = match file.remove("creators") {
+                None => HashMap::new(),
+                Some(creators) => serde_json::from_value::<HashMap<String, UserId>>(creators)
+                    .context("expected \"creators\" to map usernames to Discord user IDs")?,
+            };
+            (rows, creators)
+        }
+        _ => bail!("expected a list of markets, or an object with one in \"markets\""),
+    };
+
+    let now = Utc::now().timestamp();
+    let mut markets = Vec::new();
+    let mut errors = Vec::new();
+    for (index, row) in rows.into_iter().enumerate() {
+        let row_number = index + 1;
+        let question = row
+            .get("question")
+            .and_then(serde_json::Value::as_str)
+            .map(String::from);
+        match serde_json::from_value::<ManifoldMarket>(row)
+            .map_err(anyhow::Error::from)
+            .and_then(|market| validate(row_number, market, &creators, now))
+        {
+            Ok(market) => markets.push(market),
+            Err(e) => errors.push(RowError {
+                row: row_number,
+                question,
+                error: format!("{e:#}"),
+            }),
+        }
+    }
+    Ok((markets, errors))
+}
+
+fn validate(
+    row: usize,
+    market: ManifoldMarket,
+    creators: &HashMap<String, UserId>,
+    now: i64,
+) -> Result<ImportedMarket> {
+    let question = market.question.trim().to_string();
+    ensure!(!question.is_empty(), "the question is empty");
+    if let Some(outcome_type) = &market.outcome_type {
+        ensure!(
+            outcome_type == "BINARY",
+            "only yes/no markets can be imported, not {outcome_type}"
+        );
+    }
+    ensure!(!market.is_resolved, "the market is already resolved");
+    ensure!(
+        (MIN_PROBABILITY..=1.0 - MIN_PROBABILITY).contains(&market.probability),
+        "the probability {} isn't between {MIN_PROBABILITY} and {}",
+        market.probability,
+        1.0 - MIN_PROBABILITY
+    );
+    let close_timestamp = market.close_time.map(|close_time| close_time / 1000);
+    if let Some(close_timestamp) = close_timestamp {
+        ensure!(close_timestamp > now, "the market already closed");
+    }
+    let description = match (market.text_description, market.description) {
+        (Some(text), _) => text,
+        (None, Some(serde_json::Value::String(text))) => text,
+        (None, None) => String::new(),
+        (None, Some(_)) => bail!("the description isn't plain text and there's no textDescription"),
+    };
+    Ok(ImportedMarket {
+        row,
+        question,
+        description,
+        close_timestamp,
+        probability: market.probability,
+        creator: market
+            .creator_username
+            .and_then(|username| creators.get(&username).copied()),
+    })
+}
+
+/// Create every market in `markets`, charging each one's creator the usual creation cost.
+/// Markets whose creator wasn't mapped are created by `importer`.
+pub fn import(
+    economy: &Economy,
+    importer: UserId,
+    markets: &[ImportedMarket],
+    channel: Option<ChannelId>,
+    guild: Option<GuildId>,
+) -> (Economy, ImportReport) {
+    let mut economy = economy.clone();
+    let mut report = ImportReport {
+        created: Vec::new(),
+        errors: Vec::new(),
+    };
+    for market in markets {
+        match economy.create_market(
+            market.creator.unwrap_or(importer),
+            market.question.clone(),
+            market.description.clone(),
+            market.close_timestamp,
+            market.probability,
+            Money(0.0),
+            channel,
+            guild,
+        ) {
+            Ok((new_economy, market_id)) => {
+                economy = new_economy;
+                report.created.push(market_id);
+            }
+            Err(e) => report.errors.push(RowError {
+                row: market.row,
+                question: Some(market.question.clone()),
+                error: format!("{e:#}"),
+            }),
+        }
+    }
+    (economy, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prediction_market::Settings;
+    use serde_json::json;
+
+    const IMPORTER: UserId = UserId::new(1);
+    const CREATOR: UserId = UserId::new(2);
+
+    /// In 2096
+    const CLOSE_MILLIS: i64 = 4_000_000_000_000;
+
+    fn row(question: &str) -> serde_json::Value {
+        json!({
+            "question": question,
+            "textDescription": "Plain text",
+            "description": { "type": "doc", "content": [] },
+            "closeTime": CLOSE_MILLIS,
+            "probability": 0.25,
+            "creatorUsername": "alice",
+            "outcomeType": "BINARY",
+            "isResolved": false,
+        })
+    }
+
+    fn parse_value(file: serde_json::Value) -> Result<(Vec<ImportedMarket>, Vec<RowError>)> {
+        parse(file.to_string().as_bytes())
+    }
+
+    /// The error of the only row in a file
+    fn row_error(row: serde_json::Value) -> String {
+        let (markets, errors) = parse_value(json!([row])).unwrap();
+        assert!(markets.is_empty());
+        assert_eq!(errors.len(), 1);
+        errors[0].error.clone()
+    }
+
+    #[test]
+    fn parses_a_list_of_markets() {
+        let (markets, errors) = parse_value(json!([row("  First?  "), row("Second?")])).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(markets.len(), 2);
+        let market = &markets[0];
+        assert_eq!(market.row, 1);
+        assert_eq!(market.question, "First?");
+        assert_eq!(market.description, "Plain text");
+        assert_eq!(market.close_timestamp, Some(4_000_000_000));
+        assert_eq!(market.probability, 0.25);
+        assert_eq!(market.creator, None);
+        assert_eq!(markets[1].row, 2);
+    }
+
+    #[test]
+    fn maps_creators() {
+        let mut other = row("Other?");
+        other["creatorUsername"] = json!("bob");
+        let (markets, _) = parse_value(json!({
+            "markets": [row("Question?"), other],
+            "creators": { "alice": "2" },
+        }))
+        .unwrap();
+        assert_eq!(markets[0].creator, Some(CREATOR));
+        assert_eq!(markets[1].creator, None);
+    }
+
+    #[test]
+    fn accepts_minimal_rows() {
+        let (markets, errors) = parse_value(json!([
+            { "question": "Bare?", "probability": 0.5 },
+            { "question": "Plain?", "probability": 0.5, "description": "Just text" },
+        ]))
+        .unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(markets[0].description, "");
+        assert_eq!(markets[0].close_timestamp, None);
+        assert_eq!(markets[1].description, "Just text");
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(parse(b"not json").is_err());
+        assert!(parse_value(json!("markets")).is_err());
+        assert!(parse_value(json!({ "creators": {} })).is_err());
+        assert!(parse_value(json!({ "markets": [], "creators": { "alice": "me" } })).is_err());
+    }
+
+    #[test]
+    fn reports_invalid_rows() {
+        let mut multiple_choice = row("Which?");
+        multiple_choice["outcomeType"] = json!("MULTIPLE_CHOICE");
+        assert!(row_error(multiple_choice).contains("MULTIPLE_CHOICE"));
+
+        let mut resolved = row("Resolved?");
+        resolved["isResolved"] = json!(true);
+        assert!(row_error(resolved).contains("resolved"));
+
+        for probability in [0.0, 0.005, 0.995, 1.0] {
+            let mut certain = row("Certain?");
+            certain["probability"] = json!(probability);
+            assert!(row_error(certain).contains("probability"));
+        }
+
+        let mut closed = row("Closed?");
+        closed["closeTime"] = json!(1_000_000);
+        assert!(row_error(closed).contains("closed"));
+
+        assert!(row_error(row("   ")).contains("empty"));
+
+        let mut rich_text = row("Rich?");
+        rich_text.as_object_mut().unwrap().remove("textDescription");
+        assert!(row_error(rich_text).contains("plain text"));
+    }
+
+    #[test]
+    fn keeps_going_after_invalid_rows() {
+        let mut resolved = row("Resolved?");
+        resolved["isResolved"] = json!(true);
+        let (markets, errors) =
+            parse_value(json!([row("First?"), resolved, { "probability": 0.5 }, row("Last?")]))
+                .unwrap();
+        assert_eq!(
+            markets.iter().map(|market| market.row).collect::<Vec<_>>(),
+            [1, 4]
+        );
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| (error.row, error.question.as_deref()))
+                .collect::<Vec<_>>(),
+            [(2, Some("Resolved?")), (3, None)]
+        );
+    }
+
+    #[test]
+    fn imports_markets_and_reports_ones_it_cant_create() {
+        // The importer already has money, but the mapped creator is new to a server where users
+        // start with nothing
+        let guild = GuildId::new(3);
+        let economy = Economy::new()
+            .tip(IMPORTER, UserId::new(9), Money(0.0), None)
+            .unwrap();
+        let economy = economy
+            .set_settings(
+                guild,
+                Settings {
+                    start_balance: Money(0.0),
+                    ..Settings::default()
+                },
+            )
+            .unwrap();
+        let mut unmapped = row("Unmapped?");
+        unmapped["creatorUsername"] = json!("carol");
+        let (markets, _) = parse_value(json!({
+            "markets": [unmapped, { "question": "Mapped?", "probability": 0.5, "creatorUsername": "alice" }],
+            "creators": { "alice": "2" },
+        }))
+        .unwrap();
+        let (economy, report) = import(&economy, IMPORTER, &markets, None, Some(guild));
+        assert_eq!(report.created.len(), 1);
+        let market = economy.market(report.created[0]).unwrap();
+        assert_eq!(market.creator, IMPORTER);
+        assert_eq!(market.probability(), 25);
+        assert_eq!(market.close_timestamp, markets[0].close_timestamp);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].row, 2);
+        assert_eq!(report.errors[0].question.as_deref(), Some("Mapped?"));
+    }
+}
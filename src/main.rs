@@ -1,65 +1,271 @@
+mod api;
+mod bench;
+mod chart;
 mod commands;
+mod config;
+mod export;
+mod import;
+mod metrics;
 mod money;
+mod notifications;
+mod pagination;
 mod prediction_market;
+mod scheduler;
 mod share_quantity;
+mod shared_economy;
+mod shutdown;
+mod simulator;
+mod storage;
 
 use anyhow::Error;
-use poise::futures_util::lock::Mutex;
+use config::Config;
+use metrics::{Metrics, MetricsServer};
 use poise::serenity_prelude as serenity;
-use std::fs::File;
+use scheduler::Scheduler;
+use shared_economy::SharedEconomy;
+use shutdown::Shutdown;
+use std::sync::Arc;
+use storage::Saver;
 
-type Context<'a> = poise::Context<'a, Mutex<Economy>, Error>;
+type Context<'a> = poise::Context<'a, Data, Error>;
 type Economy = crate::prediction_market::Economy<serenity::UserId>;
 
-fn load_state() -> Economy {
-    match File::open("state.json") {
-        Ok(file) => serde_json::from_reader(file).unwrap(),
-        Err(_) => Economy::new(),
+pub struct Data {
+    economy: Arc<SharedEconomy>,
+    shutdown: Shutdown,
+    metrics: Metrics,
+}
+
+/// Refuse commands once the bot has started shutting down
+async fn check_not_shutting_down(ctx: Context<'_>) -> Result<bool, Error> {
+    if ctx.data().shutdown.is_started() {
+        ctx.send(
+            poise::CreateReply::default()
+                .content("The bot is shutting down, try again in a moment")
+                .ephemeral(true),
+        )
+        .await?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+async fn track_command(ctx: Context<'_>) {
+    ctx.data()
+        .metrics
+        .record_command(&ctx.command().qualified_name);
+    let guard = ctx.data().shutdown.track_command().await;
+    ctx.set_invocation_data(guard).await;
+}
+
+async fn on_error(error: poise::FrameworkError<'_, Data, Error>) {
+    if let Some(ctx) = error.ctx() {
+        ctx.data()
+            .metrics
+            .record_command_error(&ctx.command().qualified_name);
+    }
+    if let Err(e) = poise::builtins::on_error(error).await {
+        tracing::error!("failed handling command error: {e}");
     }
 }
 
-async fn save_state(ctx: Context<'_>) {
-    let economy = ctx.data().lock().await;
-    let file = File::create("state.json").expect("failed creating state.json");
-    serde_json::to_writer(file, &*economy).expect("failed writing economy to state.json");
+fn init_logging(log_level: &str) {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::builder()
+                .with_default_directive(tracing::Level::INFO.into())
+                .parse_lossy(log_level),
+        )
+        .init();
 }
 
 #[tokio::main]
 async fn main() {
-    let token = std::env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN");
-    let intents = serenity::GatewayIntents::non_privileged();
+    let args = std::env::args().collect::<Vec<String>>();
+    if args.len() > 1 {
+        init_logging(&std::env::var("RUST_LOG").unwrap_or_default());
+    }
+    if args.get(1).map(String::as_str) == Some("simulate") {
+        if let Err(e) = simulator::run(args.get(2).map(String::as_str)) {
+            eprintln!("simulation failed: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("bench") {
+        if let Err(e) = bench::run().await {
+            eprintln!("benchmark failed: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("import-json") {
+        let (Some(json_path), Some(sqlite_path)) = (args.get(2), args.get(3)) else {
+            eprintln!("usage: {} import-json STATE_JSON STATE_SQLITE", args[0]);
+            std::process::exit(2);
+        };
+        if let Err(e) = storage::import_json_into_sqlite(json_path, sqlite_path) {
+            eprintln!("import failed: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {e:#}");
+            std::process::exit(1);
+        }
+    };
+    init_logging(&config.log_level);
+    match &config.path {
+        Some(path) => tracing::info!("loaded config from {}", path.display()),
+        None => tracing::info!("no config file, using the environment and defaults"),
+    }
+
+    let commands = {
+        use commands::*;
+        vec![
+            help(),
+            balance(),
+            balances(),
+            portfolio(),
+            create_market(),
+            list_markets(),
+            show_market(),
+            market_history(),
+            chart(),
+            watch(),
+            unwatch(),
+            watchlist(),
+            alert(),
+            alerts(),
+            resolve_market(),
+            buy(),
+            sell(),
+            tip(),
+            subsidize(),
+            config(),
+            settings(),
+            template(),
+            treasury(),
+            api_token(),
+            export(),
+            import_markets(),
+            register(),
+            input_time(),
+        ]
+    };
+    let commands = match config::enabled_commands(commands, config.commands.as_deref()) {
+        Ok(commands) => commands,
+        Err(e) => {
+            tracing::error!("invalid configuration: {e:#}");
+            std::process::exit(1);
+        }
+    };
+
+    let (storage, economy) = match storage::open(&config.state).and_then(|mut storage| {
+        let economy = storage.load()?;
+        Ok((storage, economy))
+    }) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            tracing::error!("failed loading state: {e:#}");
+            std::process::exit(1);
+        }
+    };
+    let economy = economy.with_default_settings(prediction_market::Settings {
+        default_time_zone: config.default_time_zone.clone(),
+        ..Default::default()
+    });
+
+    let metrics = Metrics::default();
+    let saver = Saver::spawn(storage, economy.clone(), metrics.clone());
+    let shutdown = Shutdown::default();
+    let economy = Arc::new(SharedEconomy::new(economy, Some(saver.clone())));
+    let data = Data {
+        economy: economy.clone(),
+        shutdown: shutdown.clone(),
+        metrics: metrics.clone(),
+    };
 
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: {
-                use commands::*;
-                vec![
-                    help(),
-                    balance(),
-                    balances(),
-                    portfolio(),
-                    create_market(),
-                    list_markets(),
-                    show_market(),
-                    resolve_market(),
-                    buy(),
-                    sell(),
-                    tip(),
-                    register(),
-                    input_time(),
-                ]
-            },
-            post_command: |ctx| Box::pin(save_state(ctx)),
+            commands,
+            owners: config.owners,
+            command_check: Some(|ctx| Box::pin(check_not_shutting_down(ctx))),
+            pre_command: |ctx| Box::pin(track_command(ctx)),
+            on_error: |error| Box::pin(on_error(error)),
             ..Default::default()
         })
-        .setup(|_ctx, _ready, _framework| Box::pin(async move { Ok(Mutex::new(load_state())) }))
+        .setup(|_ctx, _ready, _framework| Box::pin(async move { Ok(data) }))
         .build();
 
-    serenity::ClientBuilder::new(token, intents)
+    let mut client = serenity::ClientBuilder::new(config.discord_token, config.gateway_intents)
         .framework(framework)
         .await
-        .unwrap()
-        .start()
-        .await
         .unwrap();
+
+    if let Some(api_config) = &config.api {
+        let api = api::Api {
+            address: api_config.address,
+            economy: economy.clone(),
+            http: client.http.clone(),
+            shutdown: shutdown.clone(),
+            metrics: metrics.clone(),
+            requests_per_minute: api_config.requests_per_minute,
+        };
+        if let Err(e) = api.spawn().await {
+            tracing::error!("failed starting the API: {e:#}");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(address) = config.metrics_address {
+        let server = MetricsServer {
+            address,
+            metrics,
+            economy: economy.clone(),
+            shutdown: shutdown.clone(),
+        };
+        if let Err(e) = server.spawn().await {
+            tracing::error!("failed starting the metrics server: {e:#}");
+            std::process::exit(1);
+        }
+    }
+
+    Scheduler {
+        http: client.http.clone(),
+        economy: economy.clone(),
+        shutdown: shutdown.clone(),
+        reminder_interval: config.reminder_interval,
+    }
+    .spawn();
+
+    let shard_manager = client.shard_manager.clone();
+    let stop = async move {
+        shutdown::signal().await;
+        tracing::info!("shutting down, waiting for running commands to finish");
+        let commands_blocked = shutdown.begin().await;
+        tracing::info!("disconnecting from Discord");
+        shard_manager.shutdown_all().await;
+        commands_blocked
+    };
+
+    // Late commands stay blocked until the final save is done and the process exits
+    let _commands_blocked = tokio::select! {
+        result = client.start() => {
+            if let Err(e) = result {
+                tracing::error!("client error: {e}");
+            }
+            None
+        }
+        commands_blocked = stop => Some(commands_blocked),
+    };
+
+    match saver.flush().await {
+        Ok(summary) => tracing::info!("saved {summary}"),
+        Err(e) => tracing::error!("failed saving economy on shutdown: {e:#}"),
+    }
 }
@@ -0,0 +1,331 @@
+use anyhow::{Context, Result};
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    money::Money, prediction_market::TransactionKind, shared_economy::SharedEconomy,
+    shutdown::Shutdown, Economy,
+};
+
+/// Upper bounds of the save latency histogram's buckets, in seconds
+const SAVE_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Counts of what the bot did since it started, exported in Prometheus' text format. Gauges about
+/// the economy itself are read from it when the metrics are rendered.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Mutex<Counters>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    commands: BTreeMap<String, u64>,
+    command_errors: BTreeMap<String, u64>,
+    buys: u64,
+    sells: u64,
+    /// Money spent on buying and received from selling shares
+    volume: f64,
+    /// Number of saves that took at most each of `SAVE_BUCKETS`
+    save_buckets: [u64; SAVE_BUCKETS.len()],
+    save_seconds: f64,
+    saves: u64,
+    save_errors: u64,
+}
+
+impl Metrics {
+    fn counters(&self) -> std::sync::MutexGuard<'_, Counters> {
+        self.counters.lock().expect("metrics lock poisoned")
+    }
+
+    pub fn record_command(&self, command: &str) {
+        *self.counters().commands.entry(command.into()).or_default() += 1;
+    }
+
+    pub fn record_command_error(&self, command: &str) {
+        *self
+            .counters()
+            .command_errors
+            .entry(command.into())
+            .or_default() += 1;
+    }
+
+    pub fn record_trade(&self, kind: TransactionKind, money: Money) {
+        let mut counters = self.counters();
+        match kind {
+            TransactionKind::Buy => counters.buys += 1,
+            TransactionKind::Sell => counters.sells += 1,
+        }
+        counters.volume += money.0;
+    }
+
+    pub fn record_save(&self, elapsed: Duration) {
+        let mut counters = self.counters();
+        let seconds = elapsed.as_secs_f64();
+        for (bound, count) in SAVE_BUCKETS.iter().zip(&mut counters.save_buckets) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        counters.save_seconds += seconds;
+        counters.saves += 1;
+    }
+
+    pub fn record_save_error(&self) {
+        self.counters().save_errors += 1;
+    }
+
+    /// Every metric in Prometheus' text exposition format
+    pub fn render(&self, economy: &Economy) -> String {
+        let counters = self.counters();
+        let mut out = String::new();
+
+        metric_header(
+            &mut out,
+            "bot_commands_total",
+            "counter",
+            "Commands invoked",
+        );
+        for (command, count) in &counters.commands {
+            writeln!(out, "bot_commands_total{{command=\"{command}\"}} {count}").unwrap();
+        }
+        metric_header(
+            &mut out,
+            "bot_command_errors_total",
+            "counter",
+            "Commands that failed, including ones refused by checks",
+        );
+        for (command, count) in &counters.command_errors {
+            writeln!(
+                out,
+                "bot_command_errors_total{{command=\"{command}\"}} {count}"
+            )
+            .unwrap();
+        }
+
+        metric_header(&mut out, "bot_trades_total", "counter", "Trades made");
+        writeln!(out, "bot_trades_total{{kind=\"buy\"}} {}", counters.buys).unwrap();
+        writeln!(out, "bot_trades_total{{kind=\"sell\"}} {}", counters.sells).unwrap();
+        metric_header(
+            &mut out,
+            "bot_trade_volume_dollars_total",
+            "counter",
+            "Money spent buying and received selling shares",
+        );
+        writeln!(out, "bot_trade_volume_dollars_total {}", counters.volume).unwrap();
+
+        let (open, closed) = economy
+            .list_markets()
+            .fold((0, 0), |(open, closed), market| {
+                if market.is_open() {
+                    (open + 1, closed)
+                } else {
+                    (open, closed + 1)
+                }
+            });
+        metric_header(&mut out, "bot_markets", "gauge", "Unresolved markets");
+        writeln!(out, "bot_markets{{state=\"open\"}} {open}").unwrap();
+        writeln!(out, "bot_markets{{state=\"closed\"}} {closed}").unwrap();
+        let balances = economy.balances();
+        metric_header(&mut out, "bot_users", "gauge", "Users with a balance");
+        writeln!(out, "bot_users {}", balances.len()).unwrap();
+        metric_header(
+            &mut out,
+            "bot_money_supply_dollars",
+            "gauge",
+            "Money held by all users, not counting their shares",
+        );
+        let supply: f64 = balances.iter().map(|(_, balance)| balance.0).sum();
+        writeln!(out, "bot_money_supply_dollars {supply}").unwrap();
+
+        metric_header(
+            &mut out,
+            "bot_save_duration_seconds",
+            "histogram",
+            "Time taken to save the economy",
+        );
+        for (bound, count) in SAVE_BUCKETS.iter().zip(&counters.save_buckets) {
+            writeln!(
+                out,
+                "bot_save_duration_seconds_bucket{{le=\"{bound}\"}} {count}"
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "bot_save_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            counters.saves
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "bot_save_duration_seconds_sum {}",
+            counters.save_seconds
+        )
+        .unwrap();
+        writeln!(out, "bot_save_duration_seconds_count {}", counters.saves).unwrap();
+        metric_header(
+            &mut out,
+            "bot_save_errors_total",
+            "counter",
+            "Saves that failed",
+        );
+        writeln!(out, "bot_save_errors_total {}", counters.save_errors).unwrap();
+
+        out
+    }
+}
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// HTTP server that only serves `GET /metrics`, for scraping metrics without turning on the API
+pub struct MetricsServer {
+    pub address: SocketAddr,
+    pub metrics: Metrics,
+    pub economy: Arc<SharedEconomy>,
+    pub shutdown: Shutdown,
+}
+
+impl MetricsServer {
+    /// Start listening on the address, then serve in the background until the bot shuts down
+    pub async fn spawn(self) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind(self.address)
+            .await
+            .with_context(|| format!("failed listening on {}", self.address))?;
+        tracing::info!("serving metrics on http://{}/metrics", self.address);
+        let router = Router::new()
+            .route("/metrics", get(serve))
+            .with_state((self.metrics, self.economy));
+        let shutdown = self.shutdown;
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, router)
+                .with_graceful_shutdown(async move { shutdown.started().await })
+                .await
+            {
+                tracing::error!("metrics server failed: {e}");
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Every metric, as a response Prometheus can scrape
+pub fn response(metrics: &Metrics, economy: &Economy) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(economy),
+    )
+}
+
+async fn serve(
+    State((metrics, economy)): State<(Metrics, Arc<SharedEconomy>)>,
+) -> impl IntoResponse {
+    response(&metrics, &economy.snapshot())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poise::serenity_prelude::UserId;
+
+    fn lines(rendered: &str) -> Vec<&str> {
+        rendered
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect()
+    }
+
+    #[test]
+    fn counts_commands_and_trades() {
+        let metrics = Metrics::default();
+        metrics.record_command("buy");
+        metrics.record_command("buy");
+        metrics.record_command("sell");
+        metrics.record_command_error("sell");
+        metrics.record_trade(TransactionKind::Buy, Money(10.0));
+        metrics.record_trade(TransactionKind::Sell, Money(2.5));
+        metrics.record_save_error();
+
+        let rendered = metrics.render(&Economy::new());
+        let lines = lines(&rendered);
+        for expected in [
+            "bot_commands_total{command=\"buy\"} 2",
+            "bot_commands_total{command=\"sell\"} 1",
+            "bot_command_errors_total{command=\"sell\"} 1",
+            "bot_trades_total{kind=\"buy\"} 1",
+            "bot_trades_total{kind=\"sell\"} 1",
+            "bot_trade_volume_dollars_total 12.5",
+            "bot_save_errors_total 1",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "missing {expected} in {rendered}"
+            );
+        }
+        assert!(!rendered.contains("bot_command_errors_total{command=\"buy\"}"));
+    }
+
+    #[test]
+    fn save_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.record_save(Duration::from_micros(500));
+        metrics.record_save(Duration::from_millis(20));
+        metrics.record_save(Duration::from_secs(2));
+
+        let rendered = metrics.render(&Economy::new());
+        let lines = lines(&rendered);
+        for expected in [
+            "bot_save_duration_seconds_bucket{le=\"0.001\"} 1",
+            "bot_save_duration_seconds_bucket{le=\"0.01\"} 1",
+            "bot_save_duration_seconds_bucket{le=\"0.025\"} 2",
+            "bot_save_duration_seconds_bucket{le=\"1\"} 2",
+            "bot_save_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "bot_save_duration_seconds_count 3",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "missing {expected} in {rendered}"
+            );
+        }
+    }
+
+    #[test]
+    fn reads_gauges_from_the_economy() {
+        let economy = Economy::new()
+            .tip(UserId::new(1), UserId::new(2), Money(0.0), None)
+            .unwrap();
+        let economy = economy
+            .create_market(
+                UserId::new(1),
+                "Question?".into(),
+                String::new(),
+                None,
+                0.5,
+                Money(0.0),
+                None,
+                None,
+            )
+            .unwrap()
+            .0;
+
+        let rendered = Metrics::default().render(&economy);
+        let lines = lines(&rendered);
+        assert!(lines.contains(&"bot_markets{state=\"open\"} 1"));
+        assert!(lines.contains(&"bot_markets{state=\"closed\"} 0"));
+        assert!(lines.contains(&"bot_users 2"));
+        let supply: f64 = economy
+            .balances()
+            .iter()
+            .map(|(_, balance)| balance.0)
+            .sum();
+        assert!(lines.contains(&format!("bot_money_supply_dollars {supply}").as_str()));
+    }
+}
@@ -6,3 +6,10 @@ use serde::{Deserialize, Serialize};
 )]
 #[display("${_0:.2}")]
 pub struct Money(pub f64);
+
+impl Money {
+    /// Format like `Display`, with `decimal_places` digits after the point
+    pub fn with_precision(self, decimal_places: u8) -> String {
+        format!("${:.*}", usize::from(decimal_places), self.0)
+    }
+}
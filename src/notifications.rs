@@ -0,0 +1,91 @@
+use anyhow::Result;
+use poise::serenity_prelude::{CacheHttp, CreateMessage, Mention, UserId};
+
+use crate::{
+    prediction_market::MarketId,
+    shared_economy::{SharedEconomy, Update},
+    Economy,
+};
+
+/// Send `content` as a direct message to everyone watching a market, except `skip` and users who
+/// turned these messages off. Users who can't be messaged are skipped.
+pub async fn notify_watchers(
+    http: impl CacheHttp,
+    economy: &Economy,
+    market_id: MarketId,
+    skip: &[UserId],
+    content: &str,
+) {
+    for user in economy.watchers(market_id) {
+        if skip.contains(&user) || !economy.preferences(&user).watchlist_dms {
+            continue;
+        }
+        if let Err(e) = user
+            .direct_message(&http, CreateMessage::new().content(content))
+            .await
+        {
+            tracing::debug!("failed messaging watcher {user} of market {market_id}: {e}");
+        }
+    }
+}
+
+/// Tell watchers of a market, other than the trader, when a trade moved its probability a lot
+pub async fn notify_big_move(
+    http: impl CacheHttp,
+    economy: &SharedEconomy,
+    old_economy: &Economy,
+    new_economy: &Economy,
+    market_id: MarketId,
+    trader: UserId,
+) -> Result<()> {
+    let market = new_economy.market(market_id)?;
+    let old_prob = old_economy.market(market_id)?.probability();
+    let new_prob = market.probability();
+    if old_prob.abs_diff(new_prob) < new_economy.settings(market.guild).big_move_points {
+        return Ok(());
+    }
+    let content = format!(
+        "Market __{}__ **{}** that you're watching moved from {old_prob}% to {new_prob}%.",
+        market.id, market.question
+    );
+    notify_watchers(http, &economy.snapshot(), market_id, &[trader], &content).await;
+    Ok(())
+}
+
+/// Send the alerts on a market that the last trade set off, removing them so they only fire once
+pub async fn fire_alerts(http: impl CacheHttp, economy: &SharedEconomy, market_id: MarketId) {
+    let Update {
+        after,
+        value: alerts,
+        ..
+    } = match economy.update(|economy| economy.take_crossed_alerts(market_id)) {
+        Ok(update) => update,
+        // The market was resolved since the trade
+        Err(e) => {
+            tracing::debug!("not checking alerts on market {market_id}: {e:#}");
+            return;
+        }
+    };
+    let Ok(market) = after.market(market_id) else {
+        return;
+    };
+    for alert in alerts {
+        let content = format!(
+            "{} Market __{}__ **{}** is now {}%, {} your alert at {}%.",
+            Mention::User(alert.user),
+            market.id,
+            market.question,
+            market.probability(),
+            alert.direction,
+            alert.threshold
+        );
+        let message = CreateMessage::new().content(content);
+        let result = match alert.channel {
+            Some(channel) => channel.send_message(&http, message).await,
+            None => alert.user.direct_message(&http, message).await,
+        };
+        if let Err(e) = result {
+            tracing::debug!("failed sending alert {}: {e}", alert.id);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::Result;
+use poise::serenity_prelude::{
+    ComponentInteractionCollector, CreateActionRow, CreateButton, CreateEmbed, CreateEmbedFooter,
+    CreateInteractionResponse, CreateInteractionResponseMessage,
+};
+use std::time::Duration;
+
+use crate::Context;
+
+/// Stop listening for button presses after nobody has pressed one for this long
+const TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Default number of items on each page
+pub const DEFAULT_PAGE_SIZE: usize = 10;
+
+/// Most items Discord allows on a page, since each item is an embed field
+pub const MAX_PAGE_SIZE: usize = 25;
+
+/// Send `pages` as an embed with previous and next buttons to flip between them. This only
+/// returns once the buttons time out or the bot shuts down.
+pub async fn paginate(ctx: Context<'_>, pages: Vec<CreateEmbed>) -> Result<()> {
+    let num_pages = pages.len();
+    let pages = pages
+        .into_iter()
+        .enumerate()
+        .map(|(i, page)| {
+            page.footer(CreateEmbedFooter::new(format!(
+                "Page {} of {num_pages}",
+                i + 1
+            )))
+        })
+        .collect::<Vec<CreateEmbed>>();
+    let Some(first_page) = pages.first() else {
+        return Ok(());
+    };
+    if num_pages == 1 {
+        ctx.send(poise::CreateReply::default().embed(first_page.clone()))
+            .await?;
+        return Ok(());
+    }
+
+    // The invocation ID makes the buttons unique to this message
+    let ctx_id = ctx.id();
+    let prev_button_id = format!("{ctx_id}prev");
+    let next_button_id = format!("{ctx_id}next");
+    let buttons = CreateActionRow::Buttons(vec![
+        CreateButton::new(&prev_button_id).emoji('◀'),
+        CreateButton::new(&next_button_id).emoji('▶'),
+    ]);
+    let reply = ctx
+        .send(
+            poise::CreateReply::default()
+                .embed(first_page.clone())
+                .components(vec![buttons]),
+        )
+        .await?;
+
+    let mut current_page = 0;
+    loop {
+        let collector = ComponentInteractionCollector::new(ctx)
+            .filter(move |press| press.data.custom_id.starts_with(&ctx_id.to_string()))
+            .timeout(TIMEOUT);
+        let press = tokio::select! {
+            press = collector.next() => press,
+            () = ctx.data().shutdown.started() => None,
+        };
+        let Some(press) = press else {
+            break;
+        };
+        if press.data.custom_id == next_button_id {
+            current_page = (current_page + 1) % num_pages;
+        } else if press.data.custom_id == prev_button_id {
+            current_page = current_page.checked_sub(1).unwrap_or(num_pages - 1);
+        } else {
+            continue;
+        }
+        press
+            .create_response(
+                ctx.serenity_context(),
+                CreateInteractionResponse::UpdateMessage(
+                    CreateInteractionResponseMessage::new().embed(pages[current_page].clone()),
+                ),
+            )
+            .await?;
+    }
+
+    // Remove the buttons once they stop working
+    reply
+        .edit(
+            ctx,
+            poise::CreateReply::default()
+                .embed(pages[current_page].clone())
+                .components(vec![]),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Split `fields` into embeds of at most `page_size` fields each, all made with `make_embed`.
+/// There is always at least one page, so an empty list still gets a reply.
+pub fn field_pages(
+    fields: Vec<(String, String, bool)>,
+    page_size: usize,
+    make_embed: impl Fn() -> CreateEmbed,
+) -> Vec<CreateEmbed> {
+    let page_size = page_size.clamp(1, MAX_PAGE_SIZE);
+    if fields.is_empty() {
+        return vec![make_embed()];
+    }
+    fields
+        .chunks(page_size)
+        .map(|page| make_embed().fields(page.iter().cloned()))
+        .collect()
+}
+
+/// Split `lines` into embeds with at most `page_size` lines each in their description, all made
+/// with `make_embed`. There is always at least one page, so an empty list still gets a reply.
+pub fn line_pages(
+    lines: Vec<String>,
+    page_size: usize,
+    make_embed: impl Fn() -> CreateEmbed,
+) -> Vec<CreateEmbed> {
+    let page_size = page_size.clamp(1, MAX_PAGE_SIZE);
+    if lines.is_empty() {
+        return vec![make_embed().description("None")];
+    }
+    lines
+        .chunks(page_size)
+        .map(|page| make_embed().description(page.join("\n")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn embed() -> CreateEmbed {
+        CreateEmbed::new().title("Title")
+    }
+
+    fn json(page: &CreateEmbed) -> Value {
+        serde_json::to_value(page).unwrap()
+    }
+
+    fn field(i: usize) -> (String, String, bool) {
+        (format!("Name {i}"), format!("Value {i}"), false)
+    }
+
+    #[test]
+    fn splits_fields_into_pages() {
+        let pages = field_pages((0..23).map(field).collect(), 10, embed);
+        let sizes = pages
+            .iter()
+            .map(|page| json(page)["fields"].as_array().unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [10, 10, 3]);
+        assert_eq!(json(&pages[2])["fields"][0]["name"], "Name 20");
+        assert_eq!(json(&pages[2])["title"], "Title");
+    }
+
+    #[test]
+    fn clamps_the_page_size_to_what_discord_allows() {
+        let pages = field_pages((0..30).map(field).collect(), 100, embed);
+        assert_eq!(
+            json(&pages[0])["fields"].as_array().unwrap().len(),
+            MAX_PAGE_SIZE
+        );
+        assert_eq!(field_pages((0..3).map(field).collect(), 0, embed).len(), 3);
+    }
+
+    #[test]
+    fn always_has_a_page() {
+        assert_eq!(field_pages(Vec::new(), 10, embed).len(), 1);
+        let pages = line_pages(Vec::new(), 10, embed);
+        assert_eq!(pages.len(), 1);
+        assert_eq!(json(&pages[0])["description"], "None");
+    }
+
+    #[test]
+    fn splits_lines_into_descriptions() {
+        let lines = (0..5).map(|i| format!("Line {i}")).collect();
+        let pages = line_pages(lines, 2, embed);
+        assert_eq!(pages.len(), 3);
+        assert_eq!(json(&pages[1])["description"], "Line 2\nLine 3");
+        assert_eq!(json(&pages[2])["description"], "Line 4");
+    }
+}
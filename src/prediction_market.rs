@@ -1,35 +1,285 @@
 use anyhow::{bail, ensure, Context, Result};
-use chrono::{DateTime, Utc};
-use im::ordmap::OrdMap;
-use poise::ChoiceParameter;
+use chrono::{DateTime, Days, Months, Utc};
+use im::{
+    ordmap::{DiffItem, OrdMap},
+    ordset::OrdSet,
+};
+use poise::{
+    serenity_prelude::{ChannelId, GuildId},
+    ChoiceParameter,
+};
 use serde::{Deserialize, Serialize};
 
 use crate::{money::Money, share_quantity::ShareQuantity};
 
 pub type MarketId = u64;
+pub type AlertId = u64;
+pub type TemplateId = u64;
 
-const USER_START_BALANCE: Money = Money(1000.0);
-const MARKET_CREATION_COST: Money = Money(50.0);
+/// Tunables each server can change with `/config`
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    /// Money users have before they first trade
+    pub start_balance: Money,
+    /// Cost of creating a market, which also becomes the market maker's starting pools
+    pub market_creation_cost: Money,
+    /// Time zone market close times are entered in, as an IANA name like `US/Eastern`
+    pub default_time_zone: String,
+    /// Smallest change in probability from a single trade, in percentage points, that watchers
+    /// of a market are told about
+    pub big_move_points: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            start_balance: Money(1000.0),
+            market_creation_cost: Money(50.0),
+            default_time_zone: "US/Eastern".into(),
+            big_move_points: 10,
+        }
+    }
+}
+
+/// How a user wants the bot to treat them, changed with `/settings`
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preferences {
+    /// Time zone close times are entered in, or `None` to use the server's
+    pub time_zone: Option<String>,
+    /// Whether a date like 3/4 means March 4th or April 3rd
+    pub date_dialect: DateDialect,
+    /// Get a direct message when a market they hold shares in resolves
+    pub resolution_dms: bool,
+    /// Get direct messages about big moves in, closing of and resolution of watched markets
+    pub watchlist_dms: bool,
+    /// Decimal places money and share amounts are shown with
+    pub decimal_places: u8,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Self {
+            time_zone: None,
+            date_dialect: DateDialect::Us,
+            resolution_dms: true,
+            watchlist_dms: true,
+            decimal_places: 2,
+        }
+    }
+}
+
+/// A user's personal token for trading through the HTTP API. Only a hash of the token is kept, so
+/// a leaked state file doesn't leak the token.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Hex-encoded SHA-256 hash of the token
+    pub hash: String,
+    pub created: DateTime<Utc>,
+}
+
+/// Most decimal places amounts can be shown with
+pub const MAX_DECIMAL_PLACES: u8 = 6;
+
+#[derive(
+    Copy, Clone, PartialEq, Eq, Serialize, Deserialize, ChoiceParameter, derive_more::Display,
+)]
+#[display("{}", self.name())]
+pub enum DateDialect {
+    /// Month first, so 3/4 is March 4th
+    #[name = "US (month/day)"]
+    Us,
+    /// Day first, so 3/4 is April 3rd
+    #[name = "UK (day/month)"]
+    Uk,
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Economy<UserId: Ord + Clone> {
-    next_market_id: MarketId,
-    user_money: OrdMap<UserId, Money>,
-    markets: OrdMap<MarketId, Market<UserId>>,
+    pub(crate) next_market_id: MarketId,
+    pub(crate) user_money: OrdMap<UserId, Money>,
+    pub(crate) markets: OrdMap<MarketId, Market<UserId>>,
+    /// What's left of markets after they resolve
+    pub(crate) resolved_markets: OrdMap<MarketId, ResolvedMarket<UserId>>,
+    /// Preferences of users who changed them from the defaults
+    pub(crate) preferences: OrdMap<UserId, Preferences>,
+    /// The one API token each user may have
+    pub(crate) api_tokens: OrdMap<UserId, ApiToken>,
+    /// Markets each user follows without necessarily holding shares in them
+    pub(crate) watchlists: OrdMap<UserId, OrdSet<MarketId>>,
+    pub(crate) next_alert_id: AlertId,
+    /// Alerts that haven't fired yet
+    pub(crate) alerts: OrdMap<AlertId, Alert<UserId>>,
+    /// Settings of servers that changed them from the defaults
+    pub(crate) guild_settings: OrdMap<GuildId, Settings>,
+    pub(crate) next_template_id: TemplateId,
+    pub(crate) templates: OrdMap<TemplateId, Template<UserId>>,
+    /// Money each server has put aside, for example to pay for its templates' markets
+    pub(crate) treasuries: OrdMap<GuildId, Money>,
+    /// Settings of servers that didn't change them, and outside of servers. These come from the
+    /// bot's config rather than the saved state.
+    #[serde(skip)]
+    pub(crate) default_settings: Settings,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// Tells a user once when a market's probability crosses a threshold
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alert<UserId> {
+    pub id: AlertId,
+    pub user: UserId,
+    pub market_id: MarketId,
+    pub direction: AlertDirection,
+    /// Probability in percent
+    pub threshold: u8,
+    /// Channel to ping the user in, or `None` to send them a direct message
+    pub channel: Option<ChannelId>,
+}
+
+/// Creates a market with the same question again and again, like every Friday
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Template<UserId> {
+    pub id: TemplateId,
+    /// Who creates and resolves the template's markets
+    pub creator: UserId,
+    /// Question and description of every market, where `{date}` is replaced with its close date
+    /// and `{number}` with how many markets the template has made, counting it
+    pub question: String,
+    pub description: String,
+    pub recurrence: Recurrence,
+    /// Time zone close times recur in, so they stay at the same local time across daylight
+    /// saving changes
+    pub time_zone: String,
+    pub payer: TemplatePayer,
+    /// When the next market is created, which is when the one before it closes
+    pub next_open: DateTime<Utc>,
+    pub next_close: DateTime<Utc>,
+    /// Channel new markets are announced in
+    pub channel: ChannelId,
+    pub guild: GuildId,
+    /// Every market made from the template, oldest first
+    pub instances: Vec<TemplateInstance>,
+}
+
+/// A market made from a template, which is remembered after it resolves
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemplateInstance {
+    pub market_id: MarketId,
+    pub question: String,
+    pub close_time: DateTime<Utc>,
+    /// How the market resolved, or `None` while it's unresolved
+    pub outcome: Option<ResolveOutcome>,
+}
+
+#[derive(
+    Copy, Clone, PartialEq, Eq, Serialize, Deserialize, ChoiceParameter, derive_more::Display,
+)]
+#[display("{}", self.name())]
+pub enum Recurrence {
+    #[name = "daily"]
+    Daily,
+    #[name = "weekly"]
+    Weekly,
+    #[name = "monthly"]
+    Monthly,
+}
+
+impl Recurrence {
+    /// The same local time in `time_zone` a period after `time`, or before it if `!forward`
+    fn step(self, time: DateTime<Utc>, time_zone: chrono_tz::Tz, forward: bool) -> DateTime<Utc> {
+        let local = time.with_timezone(&time_zone);
+        let stepped = match (self, forward) {
+            (Self::Daily, true) => local.checked_add_days(Days::new(1)),
+            (Self::Daily, false) => local.checked_sub_days(Days::new(1)),
+            (Self::Weekly, true) => local.checked_add_days(Days::new(7)),
+            (Self::Weekly, false) => local.checked_sub_days(Days::new(7)),
+            (Self::Monthly, true) => local.checked_add_months(Months::new(1)),
+            (Self::Monthly, false) => local.checked_sub_months(Months::new(1)),
+        };
+        // A local time skipped by a daylight saving change has no equivalent, so keep the same
+        // length of time instead
+        stepped.map_or_else(
+            || {
+                let period = match self {
+                    Self::Daily => chrono::Duration::days(1),
+                    Self::Weekly => chrono::Duration::weeks(1),
+                    Self::Monthly => chrono::Duration::days(30),
+                };
+                if forward {
+                    time + period
+                } else {
+                    time - period
+                }
+            },
+            |stepped| stepped.with_timezone(&Utc),
+        )
+    }
+}
+
+/// Who pays the creation cost of a template's markets
+#[derive(
+    Copy, Clone, PartialEq, Eq, Serialize, Deserialize, ChoiceParameter, derive_more::Display,
+)]
+#[display("{}", self.name())]
+pub enum TemplatePayer {
+    /// The template's creator, like for any other market they create
+    #[name = "creator"]
+    Creator,
+    /// The server's treasury, which also gets back the pool or refund the creator otherwise would
+    #[name = "server treasury"]
+    Treasury,
+}
+
+/// `text` with a template's placeholders filled in
+fn fill_placeholders(text: &str, date: &str, number: usize) -> String {
+    text.replace("{date}", date)
+        .replace("{number}", &number.to_string())
+}
+
+#[derive(
+    Copy, Clone, PartialEq, Eq, Serialize, Deserialize, ChoiceParameter, derive_more::Display,
+)]
+#[display("{}", self.name())]
+pub enum AlertDirection {
+    #[name = "above"]
+    Above,
+    #[name = "below"]
+    Below,
+}
+
+impl AlertDirection {
+    fn is_crossed(&self, probability: u8, threshold: u8) -> bool {
+        match self {
+            Self::Above => probability >= threshold,
+            Self::Below => probability <= threshold,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Market<UserId: Ord + Clone> {
     pub id: MarketId,
     pub creator: UserId,
     pub question: String,
     pub description: String,
-    y: ShareQuantity,
-    n: ShareQuantity,
+    pub(crate) y: ShareQuantity,
+    pub(crate) n: ShareQuantity,
     pub num_user_shares: OrdMap<UserId, ShareKindAndQuantity>,
     pub transaction_history: Vec<TransactionInfo<UserId>>,
+    /// Money added to the pool on top of the creation cost, oldest first
+    pub subsidies: Vec<Subsidy<UserId>>,
     pub close_timestamp: Option<i64>,
     pub creation_time: DateTime<Utc>,
+    /// Channel the market was created in, where its closing is announced
+    pub channel: Option<ChannelId>,
+    /// When the creator was last told the market closed and needs resolving
+    pub last_close_notice: Option<DateTime<Utc>>,
+    /// Server the market was created in, whose settings apply to users new to the economy who
+    /// trade in it
+    pub guild: Option<GuildId>,
+    /// What the creator paid, which is refunded if the market is undone
+    pub creation_cost: Money,
+    /// Server whose treasury paid the creation cost, which gets what the creator otherwise would
+    /// when the market resolves
+    pub treasury: Option<GuildId>,
 }
 
 pub struct Portfolio {
@@ -48,7 +298,36 @@ pub enum ShareKind {
     No,
 }
 
-#[derive(Copy, Clone, ChoiceParameter, derive_more::Display)]
+/// What is left of a market that has resolved, kept so users can still export it and its trades
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResolvedMarket<UserId> {
+    pub id: MarketId,
+    pub creator: UserId,
+    pub question: String,
+    pub description: String,
+    pub close_timestamp: Option<i64>,
+    pub creation_time: DateTime<Utc>,
+    /// Percent chance of YES when the market resolved
+    pub probability: u8,
+    pub outcome: ResolveOutcome,
+    pub transaction_history: Vec<TransactionInfo<UserId>>,
+}
+
+/// A resolved market and who it paid what
+pub struct Resolution<UserId: Ord + Clone> {
+    pub market: Market<UserId>,
+    /// Money paid to everyone who held shares and to the creator. When a market is undone, this
+    /// is each user's net refund, which is negative if they sold shares for more than they paid.
+    pub payouts: OrdMap<UserId, Money>,
+    /// Part of the creator's payout from the market maker's leftover pool, or their refunded
+    /// creation cost if the market was undone. It goes to the treasury instead if that paid for
+    /// the market.
+    pub creator_pool: Money,
+}
+
+#[derive(
+    Copy, Clone, PartialEq, Eq, Serialize, Deserialize, ChoiceParameter, derive_more::Display,
+)]
 #[display("{}", self.name())]
 pub enum ResolveOutcome {
     #[name = "YES"]
@@ -60,7 +339,7 @@ pub enum ResolveOutcome {
     Undo,
 }
 
-#[derive(Copy, Clone, Serialize, Deserialize, derive_more::Display)]
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, derive_more::Display)]
 pub enum TransactionKind {
     #[display("BUY")]
     Buy,
@@ -68,7 +347,7 @@ pub enum TransactionKind {
     Sell,
 }
 
-#[derive(Copy, Clone, Serialize, Deserialize)]
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TransactionInfo<UserId> {
     pub user: UserId,
     pub kind: TransactionKind,
@@ -78,39 +357,67 @@ pub struct TransactionInfo<UserId> {
     pub time: DateTime<Utc>,
 }
 
-#[derive(Copy, Clone, Serialize, Deserialize, derive_more::Display)]
+impl<UserId> TransactionInfo<UserId> {
+    fn map_user<NewUserId>(self, f: &impl Fn(UserId) -> NewUserId) -> TransactionInfo<NewUserId> {
+        TransactionInfo {
+            user: f(self.user),
+            kind: self.kind,
+            shares: self.shares,
+            money: self.money,
+            new_probability: self.new_probability,
+            time: self.time,
+        }
+    }
+}
+
+/// Money someone put into a market's pool to make it take bigger trades to move, without getting
+/// anything back unless the market is undone
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Subsidy<UserId> {
+    pub user: UserId,
+    pub money: Money,
+    /// Shares the money bought that couldn't go into the pool without moving its probability.
+    /// The market maker keeps them, so they go to the creator along with the pool.
+    pub leftover: ShareKindAndQuantity,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize, derive_more::Display)]
 #[display("{quantity} {kind}")]
 pub struct ShareKindAndQuantity {
     pub kind: ShareKind,
     pub quantity: ShareQuantity,
 }
 
-impl<UserId: Ord + Clone> Market<UserId> {
-    fn new(
-        id: MarketId,
-        creator: UserId,
-        question: String,
-        description: String,
-        close_timestamp: Option<i64>,
-        creation_time: DateTime<Utc>,
-    ) -> Self {
-        Market {
-            id,
-            creator,
-            question,
-            description,
-            y: ShareQuantity(MARKET_CREATION_COST.0),
-            n: ShareQuantity(MARKET_CREATION_COST.0),
-            num_user_shares: OrdMap::new(),
-            transaction_history: Vec::new(),
-            close_timestamp,
-            creation_time,
-        }
+impl ShareKindAndQuantity {
+    /// Format like `Display`, with `decimal_places` digits after the point
+    pub fn with_precision(&self, decimal_places: u8) -> String {
+        format!(
+            "{} {}",
+            self.quantity.with_precision(decimal_places),
+            self.kind
+        )
     }
+}
 
+/// A chance of YES as a whole percentage, rounded down. Pools seeded at a whole percentage can
+/// come out a hair under it, like 7.999999%, which would otherwise show a point low.
+fn percent(p: f64) -> u8 {
+    (p * 100.0 + 1e-9) as u8
+}
+
+impl<UserId: Ord + Clone> Market<UserId> {
     pub fn probability(&self) -> u8 {
-        let p = self.n / (self.y + self.n);
-        (p.0 * 100.0) as u8
+        percent((self.n / (self.y + self.n)).0)
+    }
+
+    /// What a share of `kind` would pay out if the market resolved at its current probability
+    pub fn share_price(&self, kind: ShareKind) -> f64 {
+        let p = (self.n / (self.y + self.n)).0;
+        match kind {
+            ShareKind::Yes => p,
+            ShareKind::No => 1.0 - p,
+        }
     }
 
     pub fn is_open(&self) -> bool {
@@ -119,6 +426,121 @@ impl<UserId: Ord + Clone> Market<UserId> {
             Some(close_timestamp) => Utc::now().timestamp() < close_timestamp,
         }
     }
+
+    /// Probability after every trade, starting from when the market was created
+    pub fn probability_history(&self) -> impl Iterator<Item = (DateTime<Utc>, u8)> + '_ {
+        let (y, n) = self.initial_pool();
+        let initial_probability = percent((n / (y + n)).0);
+        std::iter::once((self.creation_time, initial_probability)).chain(
+            self.transaction_history
+                .iter()
+                .map(|transaction| (transaction.time, transaction.new_probability)),
+        )
+    }
+
+    /// Number of YES and NO shares held by the market maker
+    pub fn pool(&self) -> (ShareQuantity, ShareQuantity) {
+        (self.y, self.n)
+    }
+
+    /// Money added to the pool by everyone's subsidies
+    pub fn total_subsidy(&self) -> Money {
+        Money(self.subsidies.iter().map(|subsidy| subsidy.money.0).sum())
+    }
+
+    /// Shares of `kind` left over from subsidies, which the creator gets along with the pool
+    fn subsidy_leftover(&self, kind: ShareKind) -> ShareQuantity {
+        ShareQuantity(
+            self.subsidies
+                .iter()
+                .filter(|subsidy| subsidy.leftover.kind == kind)
+                .map(|subsidy| subsidy.leftover.quantity.0)
+                .sum(),
+        )
+    }
+
+    /// The market maker's pools when the market was created, found by undoing every trade and
+    /// subsidy, latest first
+    fn initial_pool(&self) -> (ShareQuantity, ShareQuantity) {
+        let mut transactions = self.transaction_history.iter().rev().peekable();
+        let mut subsidies = self.subsidies.iter().rev().peekable();
+        let (mut y, mut n) = (self.y, self.n);
+        loop {
+            let undo_subsidy = match (transactions.peek(), subsidies.peek()) {
+                (None, None) => return (y, n),
+                (Some(transaction), Some(subsidy)) => subsidy.time > transaction.time,
+                (None, Some(_)) => true,
+                (Some(_), None) => false,
+            };
+            if undo_subsidy {
+                // A subsidy adds its money to both pools, less the leftover shares
+                let subsidy = subsidies.next().unwrap();
+                let money = ShareQuantity(subsidy.money.0);
+                (y, n) = (y - money, n - money);
+                match subsidy.leftover.kind {
+                    ShareKind::Yes => y += subsidy.leftover.quantity,
+                    ShareKind::No => n += subsidy.leftover.quantity,
+                }
+                continue;
+            }
+            let transaction = transactions.next().unwrap();
+            let money = ShareQuantity(transaction.money.0);
+            let shares = transaction.shares.quantity;
+            // Buying adds the money to both pools and takes the shares out of one, and selling
+            // does the opposite
+            (y, n) = match transaction.kind {
+                TransactionKind::Buy => (y - money, n - money),
+                TransactionKind::Sell => (y + money, n + money),
+            };
+            (y, n) = match (transaction.kind, transaction.shares.kind) {
+                (TransactionKind::Buy, ShareKind::Yes) => (y + shares, n),
+                (TransactionKind::Buy, ShareKind::No) => (y, n + shares),
+                (TransactionKind::Sell, ShareKind::Yes) => (y - shares, n),
+                (TransactionKind::Sell, ShareKind::No) => (y, n - shares),
+            };
+        }
+    }
+
+    fn map_users<NewUserId: Ord + Clone>(
+        self,
+        f: &impl Fn(UserId) -> NewUserId,
+    ) -> Market<NewUserId> {
+        Market {
+            id: self.id,
+            creator: f(self.creator),
+            question: self.question,
+            description: self.description,
+            y: self.y,
+            n: self.n,
+            num_user_shares: self
+                .num_user_shares
+                .into_iter()
+                .map(|(user, shares)| (f(user), shares))
+                .collect(),
+            transaction_history: self
+                .transaction_history
+                .into_iter()
+                .map(|transaction| transaction.map_user(f))
+                .collect(),
+            subsidies: self
+                .subsidies
+                .into_iter()
+                .map(|subsidy| Subsidy {
+                    user: f(subsidy.user),
+                    money: subsidy.money,
+                    leftover: subsidy.leftover,
+                    time: subsidy.time,
+                })
+                .collect(),
+            close_timestamp: self.close_timestamp,
+            creation_time: self.creation_time,
+            channel: self.channel,
+            last_close_notice: self.last_close_notice,
+            guild: self.guild,
+            creation_cost: self.creation_cost,
+            treasury: self.treasury,
+        }
+    }
 }
 
 impl<UserId: Ord + Clone> Economy<UserId> {
@@ -127,9 +549,399 @@ impl<UserId: Ord + Clone> Economy<UserId> {
             next_market_id: 0,
             user_money: OrdMap::new(),
             markets: OrdMap::new(),
+            resolved_markets: OrdMap::new(),
+            preferences: OrdMap::new(),
+            api_tokens: OrdMap::new(),
+            watchlists: OrdMap::new(),
+            next_alert_id: 0,
+            alerts: OrdMap::new(),
+            guild_settings: OrdMap::new(),
+            next_template_id: 0,
+            templates: OrdMap::new(),
+            treasuries: OrdMap::new(),
+            default_settings: Settings::default(),
+        }
+    }
+
+    /// Use `settings` for servers that haven't changed them, and outside of servers
+    pub fn with_default_settings(mut self, settings: Settings) -> Self {
+        self.default_settings = settings;
+        self
+    }
+
+    /// Whether `other` is a clone of this economy with no changes made since, which is much
+    /// cheaper than comparing the contents
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.next_market_id == other.next_market_id
+            && self.user_money.ptr_eq(&other.user_money)
+            && self.markets.ptr_eq(&other.markets)
+            && self.resolved_markets.ptr_eq(&other.resolved_markets)
+            && self.preferences.ptr_eq(&other.preferences)
+            && self.api_tokens.ptr_eq(&other.api_tokens)
+            && self.watchlists.ptr_eq(&other.watchlists)
+            && self.next_alert_id == other.next_alert_id
+            && self.alerts.ptr_eq(&other.alerts)
+            && self.guild_settings.ptr_eq(&other.guild_settings)
+            && self.next_template_id == other.next_template_id
+            && self.templates.ptr_eq(&other.templates)
+            && self.treasuries.ptr_eq(&other.treasuries)
+    }
+
+    /// Apply the changes made from `base` to `new` on top of this economy, where `base` is an
+    /// earlier version of it. Returns `None` if a change made since `base` conflicts, because it
+    /// touched the same market or left a user unable to afford their part of the changes.
+    pub fn rebase(&self, base: &Self, new: &Self) -> Option<Self> {
+        let mut rebased = self.clone();
+
+        if new.next_market_id != base.next_market_id {
+            if self.next_market_id != base.next_market_id {
+                return None;
+            }
+            rebased.next_market_id = new.next_market_id;
+        }
+        if new.next_alert_id != base.next_alert_id {
+            if self.next_alert_id != base.next_alert_id {
+                return None;
+            }
+            rebased.next_alert_id = new.next_alert_id;
+        }
+        if new.next_template_id != base.next_template_id {
+            if self.next_template_id != base.next_template_id {
+                return None;
+            }
+            rebased.next_template_id = new.next_template_id;
+        }
+
+        for item in base.user_money.diff(&new.user_money) {
+            let user = match item {
+                DiffItem::Add(user, _) | DiffItem::Update { new: (user, _), .. } => user,
+                DiffItem::Remove(..) => return None,
+            };
+            let new_balance = new.user_money[user];
+            match (self.user_money.get(user), base.user_money.get(user)) {
+                (current, base_balance) if current == base_balance => {
+                    rebased.user_money.insert(user.clone(), new_balance);
+                }
+                // Someone else changed this user's balance too, so apply just the difference
+                (Some(current), Some(base_balance)) => {
+                    let balance = Money(current.0 + new_balance.0 - base_balance.0);
+                    if balance.0.is_sign_negative() {
+                        return None;
+                    }
+                    rebased.user_money.insert(user.clone(), balance);
+                }
+                // Someone else gave this user a starting balance, possibly a different one
+                _ => return None,
+            }
+        }
+
+        for item in base.markets.diff(&new.markets) {
+            match item {
+                DiffItem::Add(market_id, market) => {
+                    if rebased.markets.insert(*market_id, market.clone()).is_some() {
+                        return None;
+                    }
+                }
+                DiffItem::Update {
+                    old: (market_id, old),
+                    new: (_, market),
+                } => {
+                    if self.markets.get(market_id) != Some(old) {
+                        return None;
+                    }
+                    rebased.markets.insert(*market_id, market.clone());
+                }
+                DiffItem::Remove(market_id, old) => {
+                    if self.markets.get(market_id) != Some(old) {
+                        return None;
+                    }
+                    rebased.markets.remove(market_id);
+                }
+            }
+        }
+
+        // A market resolves once, which already conflicts with anything else done to it
+        for item in base.resolved_markets.diff(&new.resolved_markets) {
+            let DiffItem::Add(market_id, market) = item else {
+                return None;
+            };
+            if rebased
+                .resolved_markets
+                .insert(*market_id, market.clone())
+                .is_some()
+            {
+                return None;
+            }
+        }
+
+        // Preferences only ever change for the user who set them, so they never conflict
+        for item in base.preferences.diff(&new.preferences) {
+            let user = match item {
+                DiffItem::Add(user, _)
+                | DiffItem::Update { new: (user, _), .. }
+                | DiffItem::Remove(user, _) => user,
+            };
+            match new.preferences.get(user) {
+                Some(preferences) => rebased
+                    .preferences
+                    .insert(user.clone(), preferences.clone()),
+                None => rebased.preferences.remove(user),
+            };
+        }
+
+        // Same for API tokens
+        for item in base.api_tokens.diff(&new.api_tokens) {
+            let user = match item {
+                DiffItem::Add(user, _)
+                | DiffItem::Update { new: (user, _), .. }
+                | DiffItem::Remove(user, _) => user,
+            };
+            match new.api_tokens.get(user) {
+                Some(token) => rebased.api_tokens.insert(user.clone(), token.clone()),
+                None => rebased.api_tokens.remove(user),
+            };
+        }
+
+        for item in base.watchlists.diff(&new.watchlists) {
+            let user = match item {
+                DiffItem::Add(user, _)
+                | DiffItem::Update { new: (user, _), .. }
+                | DiffItem::Remove(user, _) => user,
+            };
+            if self.watchlists.get(user) != base.watchlists.get(user) {
+                return None;
+            }
+            match new.watchlists.get(user) {
+                Some(watchlist) => rebased.watchlists.insert(user.clone(), watchlist.clone()),
+                None => rebased.watchlists.remove(user),
+            };
+        }
+
+        for item in base.guild_settings.diff(&new.guild_settings) {
+            let guild = match item {
+                DiffItem::Add(guild, _)
+                | DiffItem::Update {
+                    new: (guild, _), ..
+                }
+                | DiffItem::Remove(guild, _) => guild,
+            };
+            if self.guild_settings.get(guild) != base.guild_settings.get(guild) {
+                return None;
+            }
+            match new.guild_settings.get(guild) {
+                Some(settings) => rebased.guild_settings.insert(*guild, settings.clone()),
+                None => rebased.guild_settings.remove(guild),
+            };
+        }
+
+        for item in base.alerts.diff(&new.alerts) {
+            match item {
+                DiffItem::Add(alert_id, alert) => {
+                    if rebased.alerts.insert(*alert_id, alert.clone()).is_some() {
+                        return None;
+                    }
+                }
+                DiffItem::Update {
+                    old: (alert_id, old),
+                    new: (_, alert),
+                } => {
+                    if self.alerts.get(alert_id) != Some(old) {
+                        return None;
+                    }
+                    rebased.alerts.insert(*alert_id, alert.clone());
+                }
+                // An alert can only fire or be canceled once
+                DiffItem::Remove(alert_id, _) => {
+                    rebased.alerts.remove(alert_id)?;
+                }
+            }
+        }
+
+        for item in base.templates.diff(&new.templates) {
+            match item {
+                DiffItem::Add(template_id, template) => {
+                    if rebased
+                        .templates
+                        .insert(*template_id, template.clone())
+                        .is_some()
+                    {
+                        return None;
+                    }
+                }
+                DiffItem::Update {
+                    old: (template_id, old),
+                    new: (_, template),
+                } => {
+                    if self.templates.get(template_id) != Some(old) {
+                        return None;
+                    }
+                    rebased.templates.insert(*template_id, template.clone());
+                }
+                DiffItem::Remove(template_id, old) => {
+                    if self.templates.get(template_id) != Some(old) {
+                        return None;
+                    }
+                    rebased.templates.remove(template_id);
+                }
+            }
+        }
+
+        // Like balances, treasuries can change concurrently as long as they stay positive
+        for item in base.treasuries.diff(&new.treasuries) {
+            let guild = match item {
+                DiffItem::Add(guild, _)
+                | DiffItem::Update {
+                    new: (guild, _), ..
+                } => guild,
+                DiffItem::Remove(..) => return None,
+            };
+            let base_balance = base.treasury(*guild);
+            let balance = Money(self.treasury(*guild).0 + new.treasury(*guild).0 - base_balance.0);
+            if balance.0.is_sign_negative() {
+                return None;
+            }
+            rebased.treasuries.insert(*guild, balance);
+        }
+
+        Some(rebased)
+    }
+
+    /// Convert every user ID in the economy, for example to run a saved Discord economy in the
+    /// simulator
+    pub fn map_users<NewUserId: Ord + Clone>(
+        self,
+        f: impl Fn(UserId) -> NewUserId,
+    ) -> Economy<NewUserId> {
+        Economy {
+            next_market_id: self.next_market_id,
+            user_money: self
+                .user_money
+                .into_iter()
+                .map(|(user, money)| (f(user), money))
+                .collect(),
+            markets: self
+                .markets
+                .into_iter()
+                .map(|(id, market)| (id, market.map_users(&f)))
+                .collect(),
+            resolved_markets: self
+                .resolved_markets
+                .into_iter()
+                .map(|(id, market)| {
+                    (
+                        id,
+                        ResolvedMarket {
+                            id: market.id,
+                            creator: f(market.creator),
+                            question: market.question,
+                            description: market.description,
+                            close_timestamp: market.close_timestamp,
+                            creation_time: market.creation_time,
+                            probability: market.probability,
+                            outcome: market.outcome,
+                            transaction_history: market
+                                .transaction_history
+                                .into_iter()
+                                .map(|transaction| transaction.map_user(&f))
+                                .collect(),
+                        },
+                    )
+                })
+                .collect(),
+            preferences: self
+                .preferences
+                .into_iter()
+                .map(|(user, preferences)| (f(user), preferences))
+                .collect(),
+            api_tokens: self
+                .api_tokens
+                .into_iter()
+                .map(|(user, token)| (f(user), token))
+                .collect(),
+            watchlists: self
+                .watchlists
+                .into_iter()
+                .map(|(user, watchlist)| (f(user), watchlist))
+                .collect(),
+            next_alert_id: self.next_alert_id,
+            alerts: self
+                .alerts
+                .into_iter()
+                .map(|(id, alert)| {
+                    (
+                        id,
+                        Alert {
+                            id: alert.id,
+                            user: f(alert.user),
+                            market_id: alert.market_id,
+                            direction: alert.direction,
+                            threshold: alert.threshold,
+                            channel: alert.channel,
+                        },
+                    )
+                })
+                .collect(),
+            guild_settings: self.guild_settings,
+            next_template_id: self.next_template_id,
+            templates: self
+                .templates
+                .into_iter()
+                .map(|(id, template)| {
+                    (
+                        id,
+                        Template {
+                            id: template.id,
+                            creator: f(template.creator),
+                            question: template.question,
+                            description: template.description,
+                            recurrence: template.recurrence,
+                            time_zone: template.time_zone,
+                            payer: template.payer,
+                            next_open: template.next_open,
+                            next_close: template.next_close,
+                            channel: template.channel,
+                            guild: template.guild,
+                            instances: template.instances,
+                        },
+                    )
+                })
+                .collect(),
+            treasuries: self.treasuries,
+            default_settings: self.default_settings,
         }
     }
 
+    /// Settings of a server, or the defaults outside of one
+    pub fn settings(&self, guild: Option<GuildId>) -> Settings {
+        guild
+            .and_then(|guild| self.guild_settings.get(&guild))
+            .unwrap_or(&self.default_settings)
+            .clone()
+    }
+
+    pub fn set_settings(&self, guild: GuildId, settings: Settings) -> Result<Economy<UserId>> {
+        ensure!(
+            !settings.start_balance.0.is_sign_negative(),
+            "start balance can't be negative"
+        );
+        ensure!(
+            settings.market_creation_cost.0 > 0.0,
+            "market creation cost must be positive"
+        );
+        ensure!(
+            settings.default_time_zone.parse::<chrono_tz::Tz>().is_ok(),
+            "invalid time zone {}",
+            settings.default_time_zone
+        );
+        ensure!(
+            (1..=100).contains(&settings.big_move_points),
+            "big move must be between 1 and 100 points"
+        );
+        let mut new_economy = self.clone();
+        new_economy.guild_settings.insert(guild, settings);
+        Ok(new_economy)
+    }
+
     pub fn market(&self, market_id: MarketId) -> Result<&Market<UserId>> {
         self.markets
             .get(&market_id)
@@ -146,17 +958,37 @@ impl<UserId: Ord + Clone> Economy<UserId> {
         ret
     }
 
-    pub fn balance(&self, user: UserId) -> Money {
-        *self.user_money.get(&user).unwrap_or(&USER_START_BALANCE)
+    /// Every user's money plus their shares valued at each market's current probability, richest
+    /// first
+    pub fn net_worths(&self) -> Vec<(UserId, Money)> {
+        let mut net_worths = self.user_money.clone();
+        for market in self.markets.values() {
+            for (user, shares) in &market.num_user_shares {
+                *net_worths.entry(user.clone()).or_insert(Money(0.0)) +=
+                    Money(shares.quantity.0 * market.share_price(shares.kind));
+            }
+        }
+        let mut ret = net_worths.into_iter().collect::<Vec<(UserId, Money)>>();
+        ret.sort_by(|(_, a), (_, b)| b.partial_cmp(a).expect("failed comparing net worths"));
+        ret
+    }
+
+    /// Balance of `user`, who starts with the start balance of `guild` if they're new
+    pub fn balance(&self, user: UserId, guild: Option<GuildId>) -> Money {
+        match self.user_money.get(&user) {
+            Some(balance) => *balance,
+            None => self.settings(guild).start_balance,
+        }
     }
 
-    fn balance_mut(&mut self, user: UserId) -> &mut Money {
-        self.user_money.entry(user).or_insert(USER_START_BALANCE)
+    fn balance_mut(&mut self, user: UserId, guild: Option<GuildId>) -> &mut Money {
+        let start_balance = self.settings(guild).start_balance;
+        self.user_money.entry(user).or_insert(start_balance)
     }
 
-    pub fn portfolio(&self, user: UserId) -> Portfolio {
+    pub fn portfolio(&self, user: UserId, guild: Option<GuildId>) -> Portfolio {
         Portfolio {
-            cash: self.balance(user.clone()),
+            cash: self.balance(user.clone(), guild),
             market_positions: self
                 .markets
                 .values()
@@ -170,66 +1002,245 @@ impl<UserId: Ord + Clone> Economy<UserId> {
         }
     }
 
+    /// Create a market starting at `probability`, a chance of YES strictly between 0 and 1, with
+    /// `subsidy` from the creator added to the pool on top of the creation cost
+    #[allow(clippy::too_many_arguments)]
     pub fn create_market(
         &self,
         calling_user: UserId,
         question: String,
         description: String,
         close_timestamp: Option<i64>,
+        probability: f64,
+        subsidy: Money,
+        channel: Option<ChannelId>,
+        guild: Option<GuildId>,
     ) -> Result<(Economy<UserId>, MarketId)> {
+        ensure!(
+            probability > 0.0 && probability < 1.0,
+            "the starting probability must be between 0% and 100%"
+        );
+        ensure!(
+            !subsidy.0.is_sign_negative(),
+            "the subsidy can't be negative"
+        );
+        let creation_cost = self.settings(guild).market_creation_cost;
         let mut new_economy = self.clone();
-
-        // Create new market ID
-        let market_id = new_economy.next_market_id;
-        new_economy.next_market_id = market_id
-            .checked_add(1)
-            .context("overflow getting next market id")?;
-
-        // Deduct market creation cost
-        let user_money = new_economy.balance_mut(calling_user.clone());
-        *user_money -= MARKET_CREATION_COST;
+        let user_money = new_economy.balance_mut(calling_user.clone(), guild);
+        *user_money -= creation_cost;
         ensure!(
             !user_money.0.is_sign_negative(),
             "can't afford market creation cost"
         );
-
-        // Create market
-        let market = Market::new(
-            market_id,
-            calling_user,
+        let (mut new_economy, market_id) = new_economy.open_market(
+            calling_user.clone(),
             question,
             description,
             close_timestamp,
-            Utc::now(),
-        );
-        ensure!(
-            new_economy.markets.insert(market_id, market).is_none(),
-            "somehow, market with this id exists already"
-        );
+            probability,
+            creation_cost,
+            channel,
+            guild,
+        )?;
+        if subsidy.0 > 0.0 {
+            new_economy = new_economy.subsidize(calling_user, market_id, subsidy)?;
+        }
 
         Ok((new_economy, market_id))
     }
 
-    pub fn resolve_market(
+    /// Add a market whose `creation_cost` was already paid, seeding its pools with it
+    #[allow(clippy::too_many_arguments)]
+    fn open_market(
         &self,
         calling_user: UserId,
-        market_id: MarketId,
-        outcome: ResolveOutcome,
-    ) -> Result<(Economy<UserId>, Market<UserId>)> {
-        let market = self
-            .markets
-            .get(&market_id)
-            .context("market does not exist")?;
+        question: String,
+        description: String,
+        close_timestamp: Option<i64>,
+        probability: f64,
+        creation_cost: Money,
+        channel: Option<ChannelId>,
+        guild: Option<GuildId>,
+    ) -> Result<(Economy<UserId>, MarketId)> {
+        let mut new_economy = self.clone();
+
+        // Create new market ID
+        let market_id = new_economy.next_market_id;
+        new_economy.next_market_id = market_id
+            .checked_add(1)
+            .context("overflow getting next market id")?;
+
+        // The creation cost buys as many YES as NO shares. The market maker's pools start with
+        // all of the less likely kind and enough of the other to set the probability, and the
+        // creator holds the rest, so whichever way it resolves they get their cost back.
+        let (y, n, creator_shares) = if probability < 0.5 {
+            let n = creation_cost.0 * probability / (1.0 - probability);
+            (creation_cost.0, n, (ShareKind::No, creation_cost.0 - n))
+        } else {
+            let y = creation_cost.0 * (1.0 - probability) / probability;
+            (y, creation_cost.0, (ShareKind::Yes, creation_cost.0 - y))
+        };
+        let mut num_user_shares = OrdMap::new();
+        if creator_shares.1 > 0.0 {
+            num_user_shares.insert(
+                calling_user.clone(),
+                ShareKindAndQuantity {
+                    kind: creator_shares.0,
+                    quantity: ShareQuantity(creator_shares.1),
+                },
+            );
+        }
+        let market = Market {
+            id: market_id,
+            creator: calling_user,
+            question,
+            description,
+            y: ShareQuantity(y),
+            n: ShareQuantity(n),
+            num_user_shares,
+            transaction_history: Vec::new(),
+            subsidies: Vec::new(),
+            close_timestamp,
+            creation_time: Utc::now(),
+            channel,
+            last_close_notice: None,
+            guild,
+            creation_cost,
+            treasury: None,
+        };
+        ensure!(
+            new_economy.markets.insert(market_id, market).is_none(),
+            "somehow, market with this id exists already"
+        );
+
+        Ok((new_economy, market_id))
+    }
+
+    /// Add `money` from `calling_user` to a market's pool, keeping its probability
+    pub fn subsidize(
+        &self,
+        calling_user: UserId,
+        market_id: MarketId,
+        money: Money,
+    ) -> Result<Economy<UserId>> {
+        ensure!(
+            money.0 > 0.0,
+            "must subsidize with a positive amount of money"
+        );
+        let guild = self.market(market_id)?.guild;
+        let mut new_economy = self.clone();
+        let user_money = new_economy.balance_mut(calling_user.clone(), guild);
+        *user_money -= money;
+        ensure!(
+            !user_money.0.is_sign_negative(),
+            "you can't afford that in this economy"
+        );
+        let market = new_economy
+            .markets
+            .get_mut(&market_id)
+            .context("market does not exist")?;
+        ensure!(market.is_open(), "this market closed");
+        // The money buys as many YES as NO shares. All of the less likely kind go into the pool,
+        // along with as many of the other kind as keep the pools' ratio the same.
+        let (y, n) = (market.y.0, market.n.0);
+        let leftover = if y >= n {
+            let added = money.0 * n / y;
+            market.y += ShareQuantity(money.0);
+            market.n += ShareQuantity(added);
+            ShareKindAndQuantity {
+                kind: ShareKind::No,
+                quantity: ShareQuantity(money.0 - added),
+            }
+        } else {
+            let added = money.0 * y / n;
+            market.y += ShareQuantity(added);
+            market.n += ShareQuantity(money.0);
+            ShareKindAndQuantity {
+                kind: ShareKind::Yes,
+                quantity: ShareQuantity(money.0 - added),
+            }
+        };
+        market.subsidies.push(Subsidy {
+            user: calling_user,
+            money,
+            leftover,
+            time: Utc::now(),
+        });
+        Ok(new_economy)
+    }
+
+    pub fn resolve_market(
+        &self,
+        calling_user: UserId,
+        market_id: MarketId,
+        outcome: ResolveOutcome,
+    ) -> Result<(Economy<UserId>, Resolution<UserId>)> {
+        let market = self
+            .markets
+            .get(&market_id)
+            .context("market does not exist")?;
         ensure!(
             calling_user == market.creator,
             "this is someone else's market"
         );
 
-        match outcome {
+        let (mut new_economy, resolution) = match outcome {
             ResolveOutcome::Yes => self.resolve_market_payout(calling_user, market, ShareKind::Yes),
             ResolveOutcome::No => self.resolve_market_payout(calling_user, market, ShareKind::No),
             ResolveOutcome::Undo => self.resolve_market_undo(calling_user, market),
+        }?;
+
+        new_economy.resolved_markets.insert(
+            market_id,
+            ResolvedMarket {
+                id: market_id,
+                creator: market.creator.clone(),
+                question: market.question.clone(),
+                description: market.description.clone(),
+                close_timestamp: market.close_timestamp,
+                creation_time: market.creation_time,
+                probability: market.probability(),
+                outcome,
+                transaction_history: resolution.market.transaction_history.clone(),
+            },
+        );
+        // Nobody can watch a market that no longer exists, or be alerted about it
+        for user in new_economy.watchers(market_id) {
+            new_economy = new_economy.unwatch(user, market_id)?;
+        }
+        let alert_ids = new_economy
+            .alerts
+            .values()
+            .filter(|alert| alert.market_id == market_id)
+            .map(|alert| alert.id)
+            .collect::<Vec<_>>();
+        for alert_id in alert_ids {
+            new_economy.alerts.remove(&alert_id);
+        }
+        // Templates remember how their markets resolved
+        let template_id = new_economy
+            .templates
+            .values()
+            .find(|template| {
+                template
+                    .instances
+                    .iter()
+                    .any(|instance| instance.market_id == market_id)
+            })
+            .map(|template| template.id);
+        if let Some(template_id) = template_id {
+            let template = new_economy
+                .templates
+                .get_mut(&template_id)
+                .context("template disappeared")?;
+            for instance in &mut template.instances {
+                if instance.market_id == market_id {
+                    instance.outcome = Some(outcome);
+                }
+            }
         }
+
+        Ok((new_economy, resolution))
     }
 
     fn resolve_market_payout(
@@ -237,46 +1248,91 @@ impl<UserId: Ord + Clone> Economy<UserId> {
         calling_user: UserId,
         market: &Market<UserId>,
         outcome: ShareKind,
-    ) -> Result<(Economy<UserId>, Market<UserId>)> {
+    ) -> Result<(Economy<UserId>, Resolution<UserId>)> {
         let mut new_economy = self.clone();
+        let mut payouts = OrdMap::new();
 
         for (user, share_balance) in market.num_user_shares.iter() {
-            if share_balance.kind == outcome {
-                let user_money = new_economy.balance_mut(user.clone());
-                *user_money += Money(share_balance.quantity.0)
-            }
+            let payout = if share_balance.kind == outcome {
+                Money(share_balance.quantity.0)
+            } else {
+                Money(0.0)
+            };
+            *new_economy.balance_mut(user.clone(), market.guild) += payout;
+            payouts.insert(user.clone(), payout);
         }
 
-        let caller_money = new_economy.balance_mut(calling_user);
-        match outcome {
-            ShareKind::No => *caller_money += Money(market.n.0),
-            ShareKind::Yes => *caller_money += Money(market.y.0),
-        }
+        let creator_pool = match outcome {
+            ShareKind::No => Money(market.n.0 + market.subsidy_leftover(ShareKind::No).0),
+            ShareKind::Yes => Money(market.y.0 + market.subsidy_leftover(ShareKind::Yes).0),
+        };
+        new_economy.pay_creator(market, calling_user, creator_pool, &mut payouts);
 
         let market = new_economy.markets.remove(&market.id).context("market does not exist, after we already accessed it?? this definitely shouldn't happen")?;
 
-        Ok((new_economy, market))
+        Ok((
+            new_economy,
+            Resolution {
+                market,
+                payouts,
+                creator_pool,
+            },
+        ))
+    }
+
+    /// Pay what the creator gets from resolving `market` to them, or to the treasury if that paid
+    /// for the market
+    fn pay_creator(
+        &mut self,
+        market: &Market<UserId>,
+        creator: UserId,
+        money: Money,
+        payouts: &mut OrdMap<UserId, Money>,
+    ) {
+        match market.treasury {
+            Some(guild) => *self.treasuries.entry(guild).or_insert(Money(0.0)) += money,
+            None => {
+                *self.balance_mut(creator.clone(), market.guild) += money;
+                *payouts.entry(creator).or_insert(Money(0.0)) += money;
+            }
+        }
     }
 
     fn resolve_market_undo(
         &self,
         calling_user: UserId,
         market: &Market<UserId>,
-    ) -> Result<(Economy<UserId>, Market<UserId>)> {
+    ) -> Result<(Economy<UserId>, Resolution<UserId>)> {
         let mut new_economy = self.clone();
+        let mut payouts = OrdMap::new();
 
-        *new_economy.balance_mut(calling_user) += MARKET_CREATION_COST;
+        new_economy.pay_creator(market, calling_user, market.creation_cost, &mut payouts);
         for transaction in &market.transaction_history {
             let sign = match transaction.kind {
                 TransactionKind::Buy => 1.0,
                 TransactionKind::Sell => -1.0,
             };
-            *new_economy.balance_mut(transaction.user.clone()) += Money(transaction.money.0 * sign);
+            let refund = Money(transaction.money.0 * sign);
+            *new_economy.balance_mut(transaction.user.clone(), market.guild) += refund;
+            *payouts
+                .entry(transaction.user.clone())
+                .or_insert(Money(0.0)) += refund;
+        }
+        for subsidy in &market.subsidies {
+            *new_economy.balance_mut(subsidy.user.clone(), market.guild) += subsidy.money;
+            *payouts.entry(subsidy.user.clone()).or_insert(Money(0.0)) += subsidy.money;
         }
 
         let market = new_economy.markets.remove(&market.id).context("market does not exist, after we already accessed it?? this definitely shouldn't happen")?;
 
-        Ok((new_economy, market))
+        Ok((
+            new_economy,
+            Resolution {
+                creator_pool: market.creation_cost,
+                market,
+                payouts,
+            },
+        ))
     }
 
     pub fn sell(
@@ -351,7 +1407,8 @@ impl<UserId: Ord + Clone> Economy<UserId> {
             new_probability,
             time: Utc::now(),
         });
-        let user_money = new_economy.balance_mut(calling_user);
+        let guild = market.guild;
+        let user_money = new_economy.balance_mut(calling_user, guild);
         *user_money += sale_price;
         Ok((new_economy, shares_sold, sale_price))
     }
@@ -367,8 +1424,9 @@ impl<UserId: Ord + Clone> Economy<UserId> {
             purchase_price.0.is_sign_positive(),
             "must buy with a positive amount of money"
         );
+        let guild = self.market(market_id)?.guild;
         let mut new_economy = self.clone();
-        let user_money = new_economy.balance_mut(calling_user.clone());
+        let user_money = new_economy.balance_mut(calling_user.clone(), guild);
         *user_money -= purchase_price;
         ensure!(
             !user_money.0.is_sign_negative(),
@@ -439,25 +1497,1254 @@ impl<UserId: Ord + Clone> Economy<UserId> {
         self.markets.values()
     }
 
+    /// Markets that have resolved, in the order they were created
+    pub fn list_resolved_markets(&self) -> impl Iterator<Item = &ResolvedMarket<UserId>> + '_ {
+        self.resolved_markets.values()
+    }
+
+    /// Preferences of `user`, or the defaults if they never changed them
+    pub fn preferences(&self, user: &UserId) -> Preferences {
+        self.preferences.get(user).cloned().unwrap_or_default()
+    }
+
+    pub fn set_preferences(
+        &self,
+        user: UserId,
+        preferences: Preferences,
+    ) -> Result<Economy<UserId>> {
+        if let Some(time_zone) = &preferences.time_zone {
+            ensure!(
+                time_zone.parse::<chrono_tz::Tz>().is_ok(),
+                "invalid time zone {time_zone}"
+            );
+        }
+        ensure!(
+            preferences.decimal_places <= MAX_DECIMAL_PLACES,
+            "can't show more than {MAX_DECIMAL_PLACES} decimal places"
+        );
+        let mut new_economy = self.clone();
+        if preferences == Preferences::default() {
+            new_economy.preferences.remove(&user);
+        } else {
+            new_economy.preferences.insert(user, preferences);
+        }
+        Ok(new_economy)
+    }
+
+    pub fn api_token(&self, user: &UserId) -> Option<&ApiToken> {
+        self.api_tokens.get(user)
+    }
+
+    /// Give `user` a new API token, replacing any they had
+    pub fn set_api_token(&self, user: UserId, token: ApiToken) -> Economy<UserId> {
+        let mut new_economy = self.clone();
+        new_economy.api_tokens.insert(user, token);
+        new_economy
+    }
+
+    pub fn revoke_api_token(&self, user: &UserId) -> Result<Economy<UserId>> {
+        let mut new_economy = self.clone();
+        new_economy
+            .api_tokens
+            .remove(user)
+            .context("you don't have an API token")?;
+        Ok(new_economy)
+    }
+
+    pub fn watch(&self, user: UserId, market_id: MarketId) -> Result<Economy<UserId>> {
+        self.market(market_id)?;
+        let mut new_economy = self.clone();
+        let watchlist = new_economy.watchlists.entry(user).or_default();
+        ensure!(
+            watchlist.insert(market_id).is_none(),
+            "you're already watching this market"
+        );
+        Ok(new_economy)
+    }
+
+    pub fn unwatch(&self, user: UserId, market_id: MarketId) -> Result<Economy<UserId>> {
+        let mut new_economy = self.clone();
+        let watchlist = new_economy
+            .watchlists
+            .get_mut(&user)
+            .and_then(|watchlist| watchlist.remove(&market_id).map(|_| watchlist))
+            .context("you aren't watching this market")?;
+        if watchlist.is_empty() {
+            new_economy.watchlists.remove(&user);
+        }
+        Ok(new_economy)
+    }
+
+    /// Markets `user` is watching
+    pub fn watchlist(&self, user: &UserId) -> impl Iterator<Item = &Market<UserId>> + '_ {
+        self.watchlists
+            .get(user)
+            .into_iter()
+            .flatten()
+            .filter_map(|market_id| self.markets.get(market_id))
+    }
+
+    /// Users watching a market
+    pub fn watchers(&self, market_id: MarketId) -> Vec<UserId> {
+        self.watchlists
+            .iter()
+            .filter(|(_, watchlist)| watchlist.contains(&market_id))
+            .map(|(user, _)| user.clone())
+            .collect()
+    }
+
+    /// Alert `user` when a market's probability goes above or below `threshold` percent
+    pub fn create_alert(
+        &self,
+        user: UserId,
+        market_id: MarketId,
+        direction: AlertDirection,
+        threshold: u8,
+        channel: Option<ChannelId>,
+    ) -> Result<(Economy<UserId>, AlertId)> {
+        ensure!(
+            (1..=99).contains(&threshold),
+            "threshold must be between 1% and 99%"
+        );
+        let probability = self.market(market_id)?.probability();
+        ensure!(
+            !direction.is_crossed(probability, threshold),
+            "the market is already at {probability}%"
+        );
+        let mut new_economy = self.clone();
+        let alert_id = new_economy.next_alert_id;
+        new_economy.next_alert_id = alert_id
+            .checked_add(1)
+            .context("overflow getting next alert id")?;
+        new_economy.alerts.insert(
+            alert_id,
+            Alert {
+                id: alert_id,
+                user,
+                market_id,
+                direction,
+                threshold,
+                channel,
+            },
+        );
+        Ok((new_economy, alert_id))
+    }
+
+    pub fn cancel_alert(&self, user: UserId, alert_id: AlertId) -> Result<Economy<UserId>> {
+        let mut new_economy = self.clone();
+        match new_economy.alerts.remove(&alert_id) {
+            Some(alert) if alert.user == user => Ok(new_economy),
+            _ => bail!("you don't have an alert with ID {alert_id}"),
+        }
+    }
+
+    /// Alerts `user` has set that haven't fired yet
+    pub fn user_alerts<'a>(&'a self, user: &'a UserId) -> impl Iterator<Item = &'a Alert<UserId>> {
+        self.alerts
+            .values()
+            .filter(move |alert| alert.user == *user)
+    }
+
+    /// Remove and return the alerts on a market that its current probability has crossed, so
+    /// each alert only fires once
+    pub fn take_crossed_alerts(
+        &self,
+        market_id: MarketId,
+    ) -> Result<(Economy<UserId>, Vec<Alert<UserId>>)> {
+        let probability = self.market(market_id)?.probability();
+        let mut new_economy = self.clone();
+        let crossed = self
+            .alerts
+            .values()
+            .filter(|alert| {
+                alert.market_id == market_id
+                    && alert.direction.is_crossed(probability, alert.threshold)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for alert in &crossed {
+            new_economy.alerts.remove(&alert.id);
+        }
+        Ok((new_economy, crossed))
+    }
+
+    /// Record that the creator of a closed market was told to resolve it at `time`
+    pub fn record_close_notice(
+        &self,
+        market_id: MarketId,
+        time: DateTime<Utc>,
+    ) -> Result<Economy<UserId>> {
+        let mut new_economy = self.clone();
+        let market = new_economy
+            .markets
+            .get_mut(&market_id)
+            .context("market does not exist")?;
+        market.last_close_notice = Some(time);
+        Ok(new_economy)
+    }
+
     pub fn tip(
         &self,
         calling_user: UserId,
         user_to_tip: UserId,
         amount: Money,
+        guild: Option<GuildId>,
     ) -> Result<Economy<UserId>> {
         ensure!(
             amount.0.is_sign_positive(),
             "can only send positive amounts of money"
         );
         let mut new_economy = self.clone();
-        let caller_money = new_economy.balance_mut(calling_user);
+        let caller_money = new_economy.balance_mut(calling_user, guild);
         *caller_money -= amount;
         ensure!(
             !caller_money.0.is_sign_negative(),
             "you can't afford that in this economy"
         );
-        let tipped_user_money = new_economy.balance_mut(user_to_tip);
+        let tipped_user_money = new_economy.balance_mut(user_to_tip, guild);
         *tipped_user_money += amount;
         Ok(new_economy)
     }
+
+    /// Money in the treasury of `guild`
+    pub fn treasury(&self, guild: GuildId) -> Money {
+        self.treasuries.get(&guild).copied().unwrap_or(Money(0.0))
+    }
+
+    /// Move `amount` from `calling_user` to the treasury of `guild`
+    pub fn deposit_to_treasury(
+        &self,
+        calling_user: UserId,
+        guild: GuildId,
+        amount: Money,
+    ) -> Result<Economy<UserId>> {
+        ensure!(
+            amount.0.is_sign_positive(),
+            "can only deposit positive amounts of money"
+        );
+        let mut new_economy = self.clone();
+        let caller_money = new_economy.balance_mut(calling_user, Some(guild));
+        *caller_money -= amount;
+        ensure!(
+            !caller_money.0.is_sign_negative(),
+            "you can't afford that in this economy"
+        );
+        *new_economy.treasuries.entry(guild).or_insert(Money(0.0)) += amount;
+        Ok(new_economy)
+    }
+
+    /// Move `amount` from the treasury of `guild` to `calling_user`
+    pub fn withdraw_from_treasury(
+        &self,
+        calling_user: UserId,
+        guild: GuildId,
+        amount: Money,
+    ) -> Result<Economy<UserId>> {
+        ensure!(
+            amount.0.is_sign_positive(),
+            "can only withdraw positive amounts of money"
+        );
+        let mut new_economy = self.clone();
+        let treasury = new_economy.treasuries.entry(guild).or_insert(Money(0.0));
+        *treasury -= amount;
+        ensure!(
+            !treasury.0.is_sign_negative(),
+            "the treasury only has {}",
+            self.treasury(guild)
+        );
+        *new_economy.balance_mut(calling_user, Some(guild)) += amount;
+        Ok(new_economy)
+    }
+
+    /// Create a template whose first market closes at `first_close`. That market is created a
+    /// period earlier, or as soon as possible if that's already past.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_template(
+        &self,
+        calling_user: UserId,
+        question: String,
+        description: String,
+        recurrence: Recurrence,
+        time_zone: String,
+        payer: TemplatePayer,
+        first_close: DateTime<Utc>,
+        channel: ChannelId,
+        guild: GuildId,
+    ) -> Result<(Economy<UserId>, TemplateId)> {
+        ensure!(!question.trim().is_empty(), "the question can't be empty");
+        let tz = time_zone
+            .parse::<chrono_tz::Tz>()
+            .ok()
+            .with_context(|| format!("invalid time zone {time_zone}"))?;
+        ensure!(
+            first_close > Utc::now(),
+            "the first market must close in the future"
+        );
+        let mut new_economy = self.clone();
+        let template_id = new_economy.next_template_id;
+        new_economy.next_template_id = template_id
+            .checked_add(1)
+            .context("overflow getting next template id")?;
+        new_economy.templates.insert(
+            template_id,
+            Template {
+                id: template_id,
+                creator: calling_user,
+                question,
+                description,
+                recurrence,
+                time_zone,
+                payer,
+                next_open: recurrence.step(first_close, tz, false),
+                next_close: first_close,
+                channel,
+                guild,
+                instances: Vec::new(),
+            },
+        );
+        Ok((new_economy, template_id))
+    }
+
+    /// Delete a template, which leaves the markets it already made alone. Only its creator can,
+    /// unless `is_manager`.
+    pub fn delete_template(
+        &self,
+        calling_user: UserId,
+        template_id: TemplateId,
+        is_manager: bool,
+    ) -> Result<Economy<UserId>> {
+        let mut new_economy = self.clone();
+        match new_economy.templates.remove(&template_id) {
+            Some(template) if template.creator == calling_user || is_manager => Ok(new_economy),
+            Some(_) => bail!("this is someone else's template"),
+            None => bail!("template {template_id} does not exist"),
+        }
+    }
+
+    pub fn template(&self, template_id: TemplateId) -> Result<&Template<UserId>> {
+        self.templates
+            .get(&template_id)
+            .with_context(|| format!("template {template_id} does not exist"))
+    }
+
+    pub fn list_templates(&self) -> impl Iterator<Item = &Template<UserId>> + '_ {
+        self.templates.values()
+    }
+
+    /// Templates whose next market should have been created by `now`
+    pub fn due_templates(&self, now: DateTime<Utc>) -> Vec<TemplateId> {
+        self.templates
+            .values()
+            .filter(|template| template.next_open <= now)
+            .map(|template| template.id)
+            .collect()
+    }
+
+    /// Create the next market of a template and move its schedule on by a period. Closes that
+    /// already passed, like while the bot was down, are skipped rather than made up for. The
+    /// schedule moves on even if the market can't be created, so the returned economy is always
+    /// updated and the inner result says whether a market was made.
+    pub fn create_template_market(
+        &self,
+        template_id: TemplateId,
+        now: DateTime<Utc>,
+    ) -> Result<(Economy<UserId>, Result<MarketId>)> {
+        let mut template = self.template(template_id)?.clone();
+        let tz = template
+            .time_zone
+            .parse::<chrono_tz::Tz>()
+            .ok()
+            .context("invalid time zone")?;
+        while template.next_close <= now {
+            template.next_close = template.recurrence.step(template.next_close, tz, true);
+        }
+        let close_time = template.next_close;
+        template.next_open = close_time;
+        template.next_close = template.recurrence.step(close_time, tz, true);
+
+        let date = close_time.with_timezone(&tz).format("%Y-%m-%d").to_string();
+        let number = template.instances.len() + 1;
+        let question = fill_placeholders(&template.question, &date, number);
+        let description = fill_placeholders(&template.description, &date, number);
+        let created = match template.payer {
+            TemplatePayer::Creator => self.create_market(
+                template.creator.clone(),
+                question.clone(),
+                description,
+                Some(close_time.timestamp()),
+                0.5,
+                Money(0.0),
+                Some(template.channel),
+                Some(template.guild),
+            ),
+            TemplatePayer::Treasury => self.create_treasury_market(
+                template.creator.clone(),
+                question.clone(),
+                description,
+                close_time,
+                template.channel,
+                template.guild,
+            ),
+        };
+
+        let (mut new_economy, market_id) = match created {
+            Ok((new_economy, market_id)) => {
+                template.instances.push(TemplateInstance {
+                    market_id,
+                    question,
+                    close_time,
+                    outcome: None,
+                });
+                (new_economy, Ok(market_id))
+            }
+            Err(e) => (self.clone(), Err(e)),
+        };
+        new_economy.templates.insert(template_id, template);
+        Ok((new_economy, market_id))
+    }
+
+    /// Create a market for `creator` whose creation cost the treasury of `guild` pays
+    fn create_treasury_market(
+        &self,
+        creator: UserId,
+        question: String,
+        description: String,
+        close_time: DateTime<Utc>,
+        channel: ChannelId,
+        guild: GuildId,
+    ) -> Result<(Economy<UserId>, MarketId)> {
+        let creation_cost = self.settings(Some(guild)).market_creation_cost;
+        let mut new_economy = self.clone();
+        let treasury = new_economy.treasuries.entry(guild).or_insert(Money(0.0));
+        *treasury -= creation_cost;
+        ensure!(
+            !treasury.0.is_sign_negative(),
+            "the server treasury can't afford the market creation cost of {creation_cost}"
+        );
+        let (mut new_economy, market_id) = new_economy.open_market(
+            creator,
+            question,
+            description,
+            Some(close_time.timestamp()),
+            0.5,
+            creation_cost,
+            Some(channel),
+            Some(guild),
+        )?;
+        new_economy
+            .markets
+            .get_mut(&market_id)
+            .context("market disappeared")?
+            .treasury = Some(guild);
+        Ok((new_economy, market_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CREATOR: u64 = 1;
+    const ALICE: u64 = 2;
+    const BOB: u64 = 3;
+
+    /// An economy where everyone involved already has their starting balance
+    fn economy_with_users() -> Economy<u64> {
+        let economy = Economy::new();
+        let economy = economy.tip(ALICE, BOB, Money(0.0), None).unwrap();
+        economy.tip(CREATOR, ALICE, Money(0.0), None).unwrap()
+    }
+
+    fn create_market(economy: &Economy<u64>) -> (Economy<u64>, MarketId) {
+        economy
+            .create_market(
+                CREATOR,
+                "Question?".into(),
+                String::new(),
+                None,
+                0.5,
+                Money(0.0),
+                None,
+                None,
+            )
+            .unwrap()
+    }
+
+    fn buy(economy: &Economy<u64>, user: u64, market_id: MarketId, money: f64) -> Economy<u64> {
+        economy
+            .buy(user, market_id, Money(money), ShareKind::Yes)
+            .unwrap()
+            .0
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn rebases_trades_in_different_markets() {
+        let (base, first) = create_market(&economy_with_users());
+        let (base, second) = create_market(&base);
+        let concurrent = buy(&base, ALICE, first, 10.0);
+        let new = buy(&base, BOB, second, 20.0);
+        let rebased = concurrent.rebase(&base, &new).unwrap();
+        assert!(rebased.market(first).unwrap() == concurrent.market(first).unwrap());
+        assert!(rebased.market(second).unwrap() == new.market(second).unwrap());
+        assert_close(rebased.balance(ALICE, None).0, 990.0);
+        assert_close(rebased.balance(BOB, None).0, 980.0);
+    }
+
+    #[test]
+    fn applies_the_difference_to_a_balance_changed_concurrently() {
+        let (base, first) = create_market(&economy_with_users());
+        let (base, second) = create_market(&base);
+        let concurrent = buy(&base, ALICE, first, 10.0);
+        let new = buy(&base, ALICE, second, 20.0);
+        let rebased = concurrent.rebase(&base, &new).unwrap();
+        assert_close(rebased.balance(ALICE, None).0, 970.0);
+    }
+
+    #[test]
+    fn conflicts_on_the_same_market() {
+        let (base, market_id) = create_market(&economy_with_users());
+        let concurrent = buy(&base, ALICE, market_id, 10.0);
+        let new = buy(&base, BOB, market_id, 10.0);
+        assert!(concurrent.rebase(&base, &new).is_none());
+    }
+
+    #[test]
+    fn conflicts_when_a_balance_would_go_negative() {
+        let base = economy_with_users();
+        let concurrent = base.tip(ALICE, BOB, Money(600.0), None).unwrap();
+        let new = base.tip(ALICE, CREATOR, Money(600.0), None).unwrap();
+        assert!(concurrent.rebase(&base, &new).is_none());
+    }
+
+    #[test]
+    fn conflicts_on_new_ids() {
+        let base = economy_with_users();
+        let (concurrent, _) = create_market(&base);
+        let (new, _) = create_market(&base);
+        assert!(concurrent.rebase(&base, &new).is_none());
+    }
+
+    #[test]
+    fn conflicts_on_a_new_users_starting_balance() {
+        let base = economy_with_users();
+        let concurrent = base.tip(ALICE, 10, Money(5.0), None).unwrap();
+        let new = base.tip(BOB, 10, Money(5.0), None).unwrap();
+        assert!(concurrent.rebase(&base, &new).is_none());
+    }
+
+    #[test]
+    fn keeps_concurrent_preferences() {
+        let base = economy_with_users();
+        let preferences = Preferences {
+            decimal_places: 4,
+            ..Preferences::default()
+        };
+        let concurrent = base.set_preferences(ALICE, preferences.clone()).unwrap();
+        let new = base.set_preferences(BOB, preferences.clone()).unwrap();
+        let rebased = concurrent.rebase(&base, &new).unwrap();
+        assert!(rebased.preferences(&ALICE) == preferences);
+        assert!(rebased.preferences(&BOB) == preferences);
+    }
+
+    fn total_money(economy: &Economy<u64>) -> f64 {
+        economy.balances().iter().map(|(_, money)| money.0).sum()
+    }
+
+    #[test]
+    fn pays_winners_and_gives_the_creator_the_rest_of_the_pool() {
+        let base = economy_with_users();
+        let (economy, market_id) = create_market(&base);
+        let economy = buy(&economy, ALICE, market_id, 10.0);
+        let (economy, _) = economy
+            .buy(BOB, market_id, Money(20.0), ShareKind::No)
+            .unwrap();
+        let market = economy.market(market_id).unwrap().clone();
+        let alice_shares = market.num_user_shares[&ALICE].quantity.0;
+
+        let (resolved, resolution) = economy
+            .resolve_market(CREATOR, market_id, ResolveOutcome::Yes)
+            .unwrap();
+        assert!(resolved.market(market_id).is_err());
+        assert_close(resolution.payouts[&ALICE].0, alice_shares);
+        assert_close(resolution.payouts[&BOB].0, 0.0);
+        assert_close(resolution.creator_pool.0, market.y.0);
+        assert_close(resolution.payouts[&CREATOR].0, market.y.0);
+        assert_close(resolved.balance(ALICE, None).0, 990.0 + alice_shares);
+        assert_close(resolved.balance(BOB, None).0, 980.0);
+        // Money only moved between users
+        assert_close(total_money(&resolved), total_money(&base));
+    }
+
+    #[test]
+    fn undo_refunds_every_trade_and_the_creation_cost() {
+        let base = economy_with_users();
+        let (economy, market_id) = create_market(&base);
+        let economy = buy(&economy, ALICE, market_id, 10.0);
+        let economy = buy(&economy, BOB, market_id, 30.0);
+        let (economy, _, _) = economy.sell(ALICE, market_id, None).unwrap();
+
+        let (resolved, resolution) = economy
+            .resolve_market(CREATOR, market_id, ResolveOutcome::Undo)
+            .unwrap();
+        assert_close(resolution.creator_pool.0, 50.0);
+        for user in [CREATOR, ALICE, BOB] {
+            assert_close(resolved.balance(user, None).0, 1000.0);
+        }
+    }
+
+    #[test]
+    fn only_the_creator_can_resolve() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        assert!(economy
+            .resolve_market(ALICE, market_id, ResolveOutcome::Yes)
+            .is_err());
+    }
+
+    #[test]
+    fn resolving_keeps_the_trades() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        let economy = buy(&economy, ALICE, market_id, 10.0);
+        let economy = buy(&economy, BOB, market_id, 20.0);
+        let market = economy.market(market_id).unwrap();
+        let history = market.transaction_history.clone();
+        let probability = market.probability();
+        let (economy, _) = economy
+            .resolve_market(CREATOR, market_id, ResolveOutcome::No)
+            .unwrap();
+        let resolved = economy.list_resolved_markets().collect::<Vec<_>>();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].id, market_id);
+        assert_eq!(resolved[0].creator, CREATOR);
+        assert_eq!(resolved[0].probability, probability);
+        assert!(resolved[0].outcome == ResolveOutcome::No);
+        assert!(resolved[0].transaction_history == history);
+    }
+
+    #[test]
+    fn rebases_resolutions_of_different_markets() {
+        let (base, first) = create_market(&economy_with_users());
+        let (base, second) = create_market(&base);
+        let (current, _) = base
+            .resolve_market(CREATOR, first, ResolveOutcome::Yes)
+            .unwrap();
+        let (new, _) = base
+            .resolve_market(CREATOR, second, ResolveOutcome::No)
+            .unwrap();
+        let rebased = current.rebase(&base, &new).unwrap();
+        assert_eq!(
+            rebased
+                .list_resolved_markets()
+                .map(|market| market.id)
+                .collect::<Vec<_>>(),
+            [first, second]
+        );
+    }
+
+    #[test]
+    fn alerts_fire_once_when_crossed() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        let (economy, above) = economy
+            .create_alert(ALICE, market_id, AlertDirection::Above, 60, None)
+            .unwrap();
+        let (economy, below) = economy
+            .create_alert(BOB, market_id, AlertDirection::Below, 40, None)
+            .unwrap();
+
+        let economy = buy(&economy, CREATOR, market_id, 5.0);
+        let (economy, fired) = economy.take_crossed_alerts(market_id).unwrap();
+        assert!(fired.is_empty());
+
+        let economy = buy(&economy, CREATOR, market_id, 50.0);
+        assert!(economy.market(market_id).unwrap().probability() >= 60);
+        let (economy, fired) = economy.take_crossed_alerts(market_id).unwrap();
+        assert_eq!(
+            fired.iter().map(|alert| alert.id).collect::<Vec<_>>(),
+            [above]
+        );
+        let (economy, fired) = economy.take_crossed_alerts(market_id).unwrap();
+        assert!(fired.is_empty());
+        assert_eq!(
+            economy
+                .user_alerts(&BOB)
+                .map(|alert| alert.id)
+                .collect::<Vec<_>>(),
+            [below]
+        );
+    }
+
+    #[test]
+    fn refuses_alerts_that_would_fire_immediately() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        assert!(economy
+            .create_alert(ALICE, market_id, AlertDirection::Above, 50, None)
+            .is_err());
+        assert!(economy
+            .create_alert(ALICE, market_id, AlertDirection::Below, 0, None)
+            .is_err());
+        assert!(economy
+            .create_alert(ALICE, market_id, AlertDirection::Above, 100, None)
+            .is_err());
+    }
+
+    #[test]
+    fn only_the_owner_cancels_an_alert() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        let (economy, alert_id) = economy
+            .create_alert(ALICE, market_id, AlertDirection::Above, 70, None)
+            .unwrap();
+        assert!(economy.cancel_alert(BOB, alert_id).is_err());
+        let economy = economy.cancel_alert(ALICE, alert_id).unwrap();
+        assert_eq!(economy.user_alerts(&ALICE).count(), 0);
+    }
+
+    #[test]
+    fn resolving_removes_a_markets_alerts() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        let (economy, _) = economy
+            .create_alert(ALICE, market_id, AlertDirection::Above, 70, None)
+            .unwrap();
+        let (economy, _) = economy
+            .resolve_market(CREATOR, market_id, ResolveOutcome::No)
+            .unwrap();
+        assert_eq!(economy.user_alerts(&ALICE).count(), 0);
+    }
+
+    #[test]
+    fn validates_settings() {
+        let economy = Economy::<u64>::new();
+        let guild = GuildId::new(1);
+        let valid = Settings::default();
+        assert!(economy.set_settings(guild, valid.clone()).is_ok());
+        for invalid in [
+            Settings {
+                start_balance: Money(-1.0),
+                ..valid.clone()
+            },
+            Settings {
+                market_creation_cost: Money(0.0),
+                ..valid.clone()
+            },
+            Settings {
+                default_time_zone: "Mars/Olympus_Mons".into(),
+                ..valid.clone()
+            },
+            Settings {
+                big_move_points: 0,
+                ..valid.clone()
+            },
+            Settings {
+                big_move_points: 101,
+                ..valid.clone()
+            },
+        ] {
+            assert!(economy.set_settings(guild, invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn uses_each_servers_settings() {
+        let guild = GuildId::new(1);
+        let economy = Economy::<u64>::new()
+            .set_settings(
+                guild,
+                Settings {
+                    start_balance: Money(200.0),
+                    market_creation_cost: Money(20.0),
+                    ..Settings::default()
+                },
+            )
+            .unwrap();
+        assert!(economy.balance(ALICE, Some(guild)) == Money(200.0));
+        assert!(economy.balance(ALICE, None) == Money(1000.0));
+        let (economy, _) = economy
+            .create_market(
+                ALICE,
+                "Question?".into(),
+                String::new(),
+                None,
+                0.5,
+                Money(0.0),
+                None,
+                Some(guild),
+            )
+            .unwrap();
+        // The balance is shared between servers once the user has one
+        assert!(economy.balance(ALICE, None) == Money(180.0));
+    }
+
+    #[test]
+    fn probabilities_round_down() {
+        assert_eq!(percent(0.0), 0);
+        assert_eq!(percent(0.495), 49);
+        assert_eq!(percent(0.5), 50);
+        assert_eq!(percent(0.996), 99);
+        assert_eq!(percent(0.999_999), 99);
+        assert_eq!(percent(1.0), 100);
+    }
+
+    #[test]
+    fn markets_start_at_the_chosen_probability() {
+        for percentage in 1..100 {
+            let (economy, market_id) = economy_with_users()
+                .create_market(
+                    CREATOR,
+                    "Question?".into(),
+                    String::new(),
+                    None,
+                    f64::from(percentage) / 100.0,
+                    Money(0.0),
+                    None,
+                    None,
+                )
+                .unwrap();
+            let market = economy.market(market_id).unwrap();
+            assert_eq!(market.probability(), percentage);
+            assert_close(
+                market.share_price(ShareKind::Yes),
+                f64::from(percentage) / 100.0,
+            );
+        }
+    }
+
+    /// The creator's leftover shares plus the pool of the same kind pay back the creation cost
+    /// whichever way the market resolves
+    #[test]
+    fn seeding_leaves_the_creator_the_likelier_shares() {
+        for (probability, kind) in [(0.2, ShareKind::No), (0.8, ShareKind::Yes)] {
+            let (economy, market_id) = economy_with_users()
+                .create_market(
+                    CREATOR,
+                    "Question?".into(),
+                    String::new(),
+                    None,
+                    probability,
+                    Money(0.0),
+                    None,
+                    None,
+                )
+                .unwrap();
+            let market = economy.market(market_id).unwrap();
+            let shares = market.num_user_shares[&CREATOR];
+            assert!(shares.kind == kind);
+            let (y, n) = market.pool();
+            for outcome in [ShareKind::Yes, ShareKind::No] {
+                let pool = match outcome {
+                    ShareKind::Yes => y.0,
+                    ShareKind::No => n.0,
+                };
+                let held = if outcome == kind {
+                    shares.quantity.0
+                } else {
+                    0.0
+                };
+                assert_close(pool + held, 50.0);
+            }
+        }
+        let (economy, market_id) = create_market(&economy_with_users());
+        assert!(economy
+            .market(market_id)
+            .unwrap()
+            .num_user_shares
+            .is_empty());
+    }
+
+    #[test]
+    fn history_starts_at_the_chosen_probability() {
+        let (economy, market_id) = economy_with_users()
+            .create_market(
+                CREATOR,
+                "Question?".into(),
+                String::new(),
+                None,
+                0.08,
+                Money(0.0),
+                None,
+                None,
+            )
+            .unwrap();
+        let economy = buy(&economy, ALICE, market_id, 30.0);
+        let economy = economy.subsidize(BOB, market_id, Money(20.0)).unwrap();
+        let (economy, _, _) = economy.sell(ALICE, market_id, None).unwrap();
+        let market = economy.market(market_id).unwrap();
+        let (initial_y, initial_n) = market.initial_pool();
+        assert_close(initial_y.0, 50.0);
+        assert_close(initial_n.0, 50.0 * 0.08 / 0.92);
+        let history = market
+            .probability_history()
+            .map(|(_, probability)| probability)
+            .collect::<Vec<_>>();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0], 8);
+        assert!(history[1] > 8);
+    }
+
+    #[test]
+    fn subsidies_keep_the_probability() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        let unsubsidized = buy(&economy, ALICE, market_id, 30.0);
+        let before = unsubsidized.market(market_id).unwrap();
+        let economy = unsubsidized.subsidize(BOB, market_id, Money(40.0)).unwrap();
+        let market = economy.market(market_id).unwrap();
+        assert_close(
+            market.share_price(ShareKind::Yes),
+            before.share_price(ShareKind::Yes),
+        );
+        assert_close(economy.balance(BOB, None).0, 960.0);
+        // YES is likelier, so the pool takes every NO share and BOB's leftover is YES
+        let subsidy = market.subsidies[0];
+        assert!(subsidy.leftover.kind == ShareKind::Yes);
+        assert_close(market.pool().1 .0, before.pool().1 .0 + 40.0);
+        assert_close(
+            market.pool().0 .0 + subsidy.leftover.quantity.0,
+            before.pool().0 .0 + 40.0,
+        );
+        // A bigger pool moves less for the same trade
+        let moved = |economy: &Economy<u64>| {
+            let after = buy(economy, ALICE, market_id, 10.0);
+            after.market(market_id).unwrap().share_price(ShareKind::Yes)
+                - economy
+                    .market(market_id)
+                    .unwrap()
+                    .share_price(ShareKind::Yes)
+        };
+        assert!(moved(&economy) < moved(&unsubsidized));
+    }
+
+    #[test]
+    fn refuses_invalid_subsidies() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        assert!(economy.subsidize(ALICE, market_id, Money(0.0)).is_err());
+        assert!(economy.subsidize(ALICE, market_id, Money(-5.0)).is_err());
+        assert!(economy.subsidize(ALICE, market_id, Money(1000.1)).is_err());
+        assert!(economy.subsidize(ALICE, market_id + 1, Money(5.0)).is_err());
+        let (closed, closed_id) = economy_with_users()
+            .create_market(
+                CREATOR,
+                "Closed?".into(),
+                String::new(),
+                Some(1),
+                0.5,
+                Money(0.0),
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(closed.subsidize(ALICE, closed_id, Money(5.0)).is_err());
+    }
+
+    #[test]
+    fn resolving_pays_out_every_subsidy() {
+        for (outcome, kind) in [
+            (ResolveOutcome::Yes, ShareKind::Yes),
+            (ResolveOutcome::No, ShareKind::No),
+        ] {
+            let (economy, market_id) = create_market(&economy_with_users());
+            let economy = buy(&economy, ALICE, market_id, 30.0);
+            let economy = economy.subsidize(BOB, market_id, Money(40.0)).unwrap();
+            let (economy, _) = economy
+                .buy(BOB, market_id, Money(25.0), ShareKind::No)
+                .unwrap();
+            let economy = economy.subsidize(ALICE, market_id, Money(15.0)).unwrap();
+            let market = economy.market(market_id).unwrap().clone();
+            let (resolved, resolution) =
+                economy.resolve_market(CREATOR, market_id, outcome).unwrap();
+            let (y, n) = market.pool();
+            let pool = if kind == ShareKind::Yes { y } else { n };
+            assert_close(
+                resolution.creator_pool.0,
+                pool.0 + market.subsidy_leftover(kind).0,
+            );
+            // Subsidizers get nothing back, but no money appears or disappears
+            assert_close(total_money(&resolved), 3000.0);
+        }
+    }
+
+    #[test]
+    fn undoing_refunds_subsidies() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        let economy = buy(&economy, ALICE, market_id, 30.0);
+        let economy = economy.subsidize(BOB, market_id, Money(40.0)).unwrap();
+        let economy = economy.subsidize(BOB, market_id, Money(10.0)).unwrap();
+        let (resolved, resolution) = economy
+            .resolve_market(CREATOR, market_id, ResolveOutcome::Undo)
+            .unwrap();
+        assert_close(resolution.payouts[&BOB].0, 50.0);
+        for user in [CREATOR, ALICE, BOB] {
+            assert_close(resolved.balance(user, None).0, 1000.0);
+        }
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().to_utc()
+    }
+
+    #[test]
+    fn recurrences_keep_the_local_time_across_daylight_saving() {
+        let new_york = chrono_tz::America::New_York;
+        // 17:00 EDT, the day before clocks go back on 2026-11-01
+        let before = utc("2026-10-31T21:00:00Z");
+        let after = utc("2026-11-01T22:00:00Z");
+        assert_eq!(Recurrence::Daily.step(before, new_york, true), after);
+        assert_eq!(Recurrence::Daily.step(after, new_york, false), before);
+        assert_eq!(
+            Recurrence::Weekly.step(utc("2026-10-28T21:00:00Z"), new_york, true),
+            utc("2026-11-04T22:00:00Z")
+        );
+        // 02:30 doesn't exist on 2027-03-14, so a day is 24 hours instead
+        assert_eq!(
+            Recurrence::Daily.step(utc("2027-03-13T07:30:00Z"), new_york, true),
+            utc("2027-03-14T07:30:00Z")
+        );
+    }
+
+    #[test]
+    fn monthly_recurrences_stay_in_shorter_months() {
+        let new_york = chrono_tz::America::New_York;
+        assert_eq!(
+            Recurrence::Monthly.step(utc("2027-01-31T22:00:00Z"), new_york, true),
+            utc("2027-02-28T22:00:00Z")
+        );
+        assert_eq!(
+            Recurrence::Monthly.step(utc("2027-03-15T21:00:00Z"), new_york, false),
+            utc("2027-02-15T22:00:00Z")
+        );
+    }
+
+    const GUILD: GuildId = GuildId::new(1);
+    const CHANNEL: ChannelId = ChannelId::new(2);
+
+    fn create_template(economy: &Economy<u64>, payer: TemplatePayer) -> (Economy<u64>, TemplateId) {
+        economy
+            .create_template(
+                CREATOR,
+                "Rain on {date}? (#{number})".into(),
+                "Market {number}".into(),
+                Recurrence::Weekly,
+                "America/New_York".into(),
+                payer,
+                utc("2030-01-04T17:00:00Z"),
+                CHANNEL,
+                GUILD,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn templates_create_markets_a_period_apart() {
+        let (economy, template_id) = create_template(&economy_with_users(), TemplatePayer::Creator);
+        let template = economy.template(template_id).unwrap();
+        assert_eq!(template.next_open, utc("2029-12-28T17:00:00Z"));
+        assert!(economy
+            .due_templates(utc("2029-12-28T16:59:59Z"))
+            .is_empty());
+        assert_eq!(
+            economy.due_templates(utc("2029-12-28T17:00:00Z")),
+            [template_id]
+        );
+
+        let (economy, first) = economy
+            .create_template_market(template_id, utc("2029-12-28T17:00:00Z"))
+            .unwrap();
+        let first = first.unwrap();
+        let market = economy.market(first).unwrap();
+        assert_eq!(market.question, "Rain on 2030-01-04? (#1)");
+        assert_eq!(market.description, "Market 1");
+        assert_eq!(
+            market.close_timestamp,
+            Some(utc("2030-01-04T17:00:00Z").timestamp())
+        );
+        assert_eq!(market.channel, Some(CHANNEL));
+        assert_eq!(market.treasury, None);
+        assert_close(economy.balance(CREATOR, None).0, 950.0);
+
+        let template = economy.template(template_id).unwrap();
+        assert_eq!(template.next_open, utc("2030-01-04T17:00:00Z"));
+        assert_eq!(template.next_close, utc("2030-01-11T17:00:00Z"));
+        assert_eq!(template.instances.len(), 1);
+        assert_eq!(template.instances[0].market_id, first);
+
+        let (economy, second) = economy
+            .create_template_market(template_id, utc("2030-01-04T17:00:00Z"))
+            .unwrap();
+        let market = economy.market(second.unwrap()).unwrap();
+        assert_eq!(market.question, "Rain on 2030-01-11? (#2)");
+
+        // Resolving is remembered by the template
+        let (economy, _) = economy
+            .resolve_market(CREATOR, first, ResolveOutcome::No)
+            .unwrap();
+        let template = economy.template(template_id).unwrap();
+        assert!(template.instances[0].outcome == Some(ResolveOutcome::No));
+        assert!(template.instances[1].outcome.is_none());
+    }
+
+    #[test]
+    fn templates_skip_closes_that_already_passed() {
+        let (economy, template_id) = create_template(&economy_with_users(), TemplatePayer::Creator);
+        // The bot was down for three weeks
+        let (economy, market_id) = economy
+            .create_template_market(template_id, utc("2030-01-20T12:00:00Z"))
+            .unwrap();
+        let market = economy.market(market_id.unwrap()).unwrap();
+        assert_eq!(
+            market.close_timestamp,
+            Some(utc("2030-01-25T17:00:00Z").timestamp())
+        );
+        assert_eq!(market.question, "Rain on 2030-01-25? (#1)");
+        let template = economy.template(template_id).unwrap();
+        assert_eq!(template.next_close, utc("2030-02-01T17:00:00Z"));
+    }
+
+    #[test]
+    fn treasuries_pay_for_template_markets() {
+        let economy = economy_with_users()
+            .deposit_to_treasury(ALICE, GUILD, Money(70.0))
+            .unwrap();
+        let (economy, template_id) = create_template(&economy, TemplatePayer::Treasury);
+        let (economy, market_id) = economy
+            .create_template_market(template_id, utc("2029-12-28T17:00:00Z"))
+            .unwrap();
+        let market_id = market_id.unwrap();
+        assert_eq!(economy.market(market_id).unwrap().treasury, Some(GUILD));
+        assert_close(economy.treasury(GUILD).0, 20.0);
+        assert_close(economy.balance(CREATOR, None).0, 1000.0);
+
+        // The treasury can't afford another, but the schedule still moves on
+        let (economy, failed) = economy
+            .create_template_market(template_id, utc("2030-01-04T17:00:00Z"))
+            .unwrap();
+        assert!(failed.is_err());
+        let template = economy.template(template_id).unwrap();
+        assert_eq!(template.instances.len(), 1);
+        assert_eq!(template.next_open, utc("2030-01-11T17:00:00Z"));
+        assert_close(economy.treasury(GUILD).0, 20.0);
+
+        // Undoing refunds the treasury, not the creator
+        let (economy, resolution) = economy
+            .resolve_market(CREATOR, market_id, ResolveOutcome::Undo)
+            .unwrap();
+        assert!(!resolution.payouts.contains_key(&CREATOR));
+        assert_close(economy.treasury(GUILD).0, 70.0);
+        assert_close(economy.balance(CREATOR, None).0, 1000.0);
+    }
+
+    #[test]
+    fn resolving_a_treasury_market_pays_the_pool_to_the_treasury() {
+        let economy = economy_with_users()
+            .deposit_to_treasury(ALICE, GUILD, Money(50.0))
+            .unwrap();
+        let (economy, template_id) = create_template(&economy, TemplatePayer::Treasury);
+        let (economy, market_id) = economy
+            .create_template_market(template_id, utc("2029-12-28T17:00:00Z"))
+            .unwrap();
+        let market_id = market_id.unwrap();
+        let economy = buy(&economy, BOB, market_id, 20.0);
+        let (economy, resolution) = economy
+            .resolve_market(CREATOR, market_id, ResolveOutcome::No)
+            .unwrap();
+        assert_close(economy.treasury(GUILD).0, resolution.creator_pool.0);
+        assert_close(economy.treasury(GUILD).0, 70.0);
+        assert_close(total_money(&economy) + economy.treasury(GUILD).0, 3000.0);
+    }
+
+    #[test]
+    fn validates_treasury_transfers() {
+        let economy = economy_with_users();
+        assert!(economy
+            .deposit_to_treasury(ALICE, GUILD, Money(-1.0))
+            .is_err());
+        assert!(economy
+            .deposit_to_treasury(ALICE, GUILD, Money(1000.1))
+            .is_err());
+        let economy = economy
+            .deposit_to_treasury(ALICE, GUILD, Money(100.0))
+            .unwrap();
+        assert!(economy
+            .withdraw_from_treasury(BOB, GUILD, Money(100.1))
+            .is_err());
+        let economy = economy
+            .withdraw_from_treasury(BOB, GUILD, Money(100.0))
+            .unwrap();
+        assert_close(economy.balance(BOB, None).0, 1100.0);
+        assert_close(economy.treasury(GUILD).0, 0.0);
+    }
+
+    #[test]
+    fn watches_and_unwatches_markets() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        assert!(economy.watch(ALICE, market_id + 1).is_err());
+        assert!(economy.unwatch(ALICE, market_id).is_err());
+
+        let economy = economy.watch(ALICE, market_id).unwrap();
+        assert!(economy.watch(ALICE, market_id).is_err());
+        let economy = economy.watch(BOB, market_id).unwrap();
+        assert_eq!(economy.watchers(market_id), vec![ALICE, BOB]);
+        assert_eq!(
+            economy
+                .watchlist(&ALICE)
+                .map(|market| market.id)
+                .collect::<Vec<_>>(),
+            vec![market_id]
+        );
+
+        let economy = economy.unwatch(ALICE, market_id).unwrap();
+        assert_eq!(economy.watchers(market_id), vec![BOB]);
+        assert_eq!(economy.watchlist(&ALICE).count(), 0);
+        assert!(!economy.watchlists.contains_key(&ALICE));
+    }
+
+    #[test]
+    fn resolving_stops_everyone_watching() {
+        let (economy, market_id) = create_market(&economy_with_users());
+        let economy = economy.watch(ALICE, market_id).unwrap();
+        let economy = economy.watch(BOB, market_id).unwrap();
+        let economy = economy
+            .resolve_market(CREATOR, market_id, ResolveOutcome::Yes)
+            .unwrap()
+            .0;
+        assert!(economy.watchers(market_id).is_empty());
+        assert!(economy.watchlists.is_empty());
+    }
+
+    #[test]
+    fn validates_preferences() {
+        let economy = economy_with_users();
+        assert!(economy
+            .set_preferences(
+                ALICE,
+                Preferences {
+                    time_zone: Some("Mars/Olympus_Mons".into()),
+                    ..Default::default()
+                }
+            )
+            .is_err());
+        assert!(economy
+            .set_preferences(
+                ALICE,
+                Preferences {
+                    decimal_places: MAX_DECIMAL_PLACES + 1,
+                    ..Default::default()
+                }
+            )
+            .is_err());
+
+        let preferences = Preferences {
+            time_zone: Some("Europe/Amsterdam".into()),
+            decimal_places: MAX_DECIMAL_PLACES,
+            ..Default::default()
+        };
+        let economy = economy.set_preferences(ALICE, preferences.clone()).unwrap();
+        assert!(economy.preferences(&ALICE) == preferences);
+        assert!(economy.preferences(&BOB) == Preferences::default());
+
+        // Going back to the defaults forgets the user had preferences
+        let economy = economy
+            .set_preferences(ALICE, Preferences::default())
+            .unwrap();
+        assert!(economy.preferences.is_empty());
+    }
 }
@@ -0,0 +1,249 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use poise::serenity_prelude::{self as serenity, CreateMessage, Mention};
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    notifications::notify_watchers,
+    prediction_market::{Market, MarketId, TemplateId},
+    shared_economy::SharedEconomy,
+    shutdown::Shutdown,
+    Economy,
+};
+
+/// How often to check for markets that closed or need a reminder, and templates due a new market
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Announces markets closing in the channel they were created in and to their watchers, keeps
+/// reminding their creators to resolve them, and creates templates' markets
+pub struct Scheduler {
+    pub http: Arc<serenity::Http>,
+    pub economy: Arc<SharedEconomy>,
+    pub shutdown: Shutdown,
+    /// Time between reminders to resolve a closed market, or `None` to never remind
+    pub reminder_interval: Option<chrono::Duration>,
+}
+
+/// Why the creator of a market is being notified
+#[derive(Debug, PartialEq)]
+enum Notice {
+    Closed,
+    Reminder,
+}
+
+impl Scheduler {
+    /// Run in the background until the bot shuts down
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    () = self.shutdown.started() => return,
+                }
+                // Shutdown waits for notices being sent, like it does for commands
+                let _running = self.shutdown.track_command().await;
+                if self.shutdown.is_started() {
+                    return;
+                }
+                self.send_due_notices().await;
+                self.create_template_markets().await;
+            }
+        });
+    }
+
+    async fn send_due_notices(&self) {
+        let now = Utc::now();
+        let economy = self.economy.snapshot();
+        for market in economy.list_markets() {
+            let Some(notice) = due_notice(market, now, self.reminder_interval) else {
+                continue;
+            };
+            if let Notice::Closed = notice {
+                let content = format!(
+                    "Market __{}__ **{}** that you're watching closed.",
+                    market.id, market.question
+                );
+                notify_watchers(&self.http, &economy, market.id, &[market.creator], &content).await;
+            }
+            if let Err(e) = self.send_notice(market, notice).await {
+                tracing::warn!("failed notifying about market {}: {e:#}", market.id);
+            }
+            // Record the notice even if it failed, so a deleted channel isn't retried forever
+            if let Err(e) = self
+                .economy
+                .update(|economy| Ok((economy.record_close_notice(market.id, now)?, ())))
+            {
+                // The market was resolved in the meantime
+                tracing::debug!("not recording notice for market {}: {e:#}", market.id);
+            }
+        }
+    }
+
+    async fn create_template_markets(&self) {
+        let now = Utc::now();
+        for template_id in self.economy.snapshot().due_templates(now) {
+            let update = match self
+                .economy
+                .update(|economy| economy.create_template_market(template_id, now))
+            {
+                Ok(update) => update,
+                // The template was deleted in the meantime
+                Err(e) => {
+                    tracing::debug!("not creating market for template {template_id}: {e:#}");
+                    continue;
+                }
+            };
+            if let Err(e) = self
+                .announce_template_market(template_id, &update.after, update.value)
+                .await
+            {
+                tracing::warn!("failed announcing market of template {template_id}: {e:#}");
+            }
+        }
+    }
+
+    /// Post a template's new market in its channel, or why it couldn't be created
+    async fn announce_template_market(
+        &self,
+        template_id: TemplateId,
+        economy: &Economy,
+        created: Result<MarketId>,
+    ) -> Result<()> {
+        let template = economy.template(template_id)?;
+        let content = match created {
+            Ok(market_id) => {
+                let market = economy.market(market_id)?;
+                format!(
+                    "New market __{}__ **{}** closes <t:{}:F>. Trade it with `/buy`.",
+                    market.id,
+                    market.question,
+                    market.close_timestamp.unwrap_or_default()
+                )
+            }
+            Err(e) => format!(
+                "Couldn't create the next market of template __{}__ **{}**: {e:#}. {}, the one \
+                 after is due <t:{}:R>.",
+                template.id,
+                template.question,
+                Mention::User(template.creator),
+                template.next_open.timestamp()
+            ),
+        };
+        template
+            .channel
+            .send_message(&self.http, CreateMessage::new().content(content))
+            .await?;
+        Ok(())
+    }
+
+    /// Post the notice in the market's channel, or message the creator if that isn't possible
+    async fn send_notice(&self, market: &Market<serenity::UserId>, notice: Notice) -> Result<()> {
+        let creator = Mention::User(market.creator);
+        let close_timestamp = market.close_timestamp.unwrap_or_default();
+        let content = match notice {
+            Notice::Closed => format!(
+                "Market __{}__ **{}** closed <t:{close_timestamp}:R>. \
+                 {creator}, resolve it with `/resolve_market`.",
+                market.id, market.question
+            ),
+            Notice::Reminder => format!(
+                "Market __{}__ **{}** closed <t:{close_timestamp}:R> and still isn't resolved. \
+                 {creator}, resolve it with `/resolve_market`.",
+                market.id, market.question
+            ),
+        };
+        if let Some(channel) = market.channel {
+            match channel
+                .send_message(&self.http, CreateMessage::new().content(&content))
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(e) => tracing::warn!(
+                    "failed announcing market {} in channel {channel}, messaging its creator \
+                     instead: {e}",
+                    market.id
+                ),
+            }
+        }
+        market
+            .creator
+            .direct_message(&self.http, CreateMessage::new().content(content))
+            .await?;
+        Ok(())
+    }
+}
+
+/// What the creator of `market` should be told at `now`, if anything
+fn due_notice<UserId: Ord + Clone>(
+    market: &Market<UserId>,
+    now: DateTime<Utc>,
+    reminder_interval: Option<chrono::Duration>,
+) -> Option<Notice> {
+    let close_time = DateTime::from_timestamp(market.close_timestamp?, 0)?;
+    if now < close_time {
+        return None;
+    }
+    match market.last_close_notice {
+        None => Some(Notice::Closed),
+        Some(last_notice) => (now - last_notice >= reminder_interval?).then_some(Notice::Reminder),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{money::Money, prediction_market::Economy};
+
+    #[test]
+    fn announces_closing_then_reminds_every_interval() {
+        let close = DateTime::from_timestamp(2_000_000_000, 0).unwrap();
+        let (economy, market_id) = Economy::<u64>::new()
+            .create_market(
+                1,
+                "Question?".into(),
+                String::new(),
+                Some(close.timestamp()),
+                0.5,
+                Money(0.0),
+                None,
+                None,
+            )
+            .unwrap();
+        let days = chrono::Duration::days;
+        let interval = Some(days(3));
+        let market = economy.market(market_id).unwrap();
+        assert_eq!(due_notice(market, close - days(1), interval), None);
+        assert_eq!(due_notice(market, close, interval), Some(Notice::Closed));
+
+        let economy = economy.record_close_notice(market_id, close).unwrap();
+        let market = economy.market(market_id).unwrap();
+        assert_eq!(due_notice(market, close + days(2), interval), None);
+        assert_eq!(
+            due_notice(market, close + days(3), interval),
+            Some(Notice::Reminder)
+        );
+        assert_eq!(due_notice(market, close + days(30), None), None);
+    }
+
+    #[test]
+    fn never_notifies_about_markets_that_never_close() {
+        let (economy, market_id) = Economy::<u64>::new()
+            .create_market(
+                1,
+                "Question?".into(),
+                String::new(),
+                None,
+                0.5,
+                Money(0.0),
+                None,
+                None,
+            )
+            .unwrap();
+        let market = economy.market(market_id).unwrap();
+        assert_eq!(
+            due_notice(market, Utc::now(), Some(chrono::Duration::days(1))),
+            None
+        );
+    }
+}
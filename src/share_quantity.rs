@@ -2,9 +2,27 @@ use derive_more::*;
 use serde::{Deserialize, Serialize};
 
 #[derive(
-    Copy, Clone, Serialize, Deserialize, Display, Add, Sub, Mul, Div, AddAssign, SubAssign,
+    Copy,
+    Clone,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    AddAssign,
+    SubAssign,
 )]
 #[mul(forward)]
 #[div(forward)]
 #[display("{_0:.2}")]
 pub struct ShareQuantity(pub f64);
+
+impl ShareQuantity {
+    /// Format like `Display`, with `decimal_places` digits after the point
+    pub fn with_precision(self, decimal_places: u8) -> String {
+        format!("{:.*}", usize::from(decimal_places), self.0)
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::{bail, Result};
+use std::sync::Mutex;
+
+use crate::{storage::Saver, Economy};
+
+/// Give up on an update after this many conflicts, which only happens under extreme contention
+const MAX_ATTEMPTS: usize = 100;
+
+/// The economy shared between all commands, updated with optimistic concurrency. An update runs
+/// against a snapshot without holding any lock, and is then committed by rebasing its changes onto
+/// the current economy. Only updates touching the same market or the same user's money conflict,
+/// and those are simply retried against a fresh snapshot, so trades in different markets proceed
+/// concurrently and nothing ever waits on a slow Discord request.
+pub struct SharedEconomy {
+    /// Only locked for as long as it takes to clone or rebase, never across an `await`
+    current: Mutex<Economy>,
+    /// Gets every committed economy, so a change is saved whatever its command does afterwards
+    saver: Option<Saver>,
+}
+
+/// Result of a committed update
+pub struct Update<T> {
+    /// Economy the update was computed from
+    pub before: Economy,
+    /// Economy the update produced. It doesn't include changes committed concurrently by others.
+    pub after: Economy,
+    pub value: T,
+}
+
+impl SharedEconomy {
+    pub fn new(economy: Economy, saver: Option<Saver>) -> Self {
+        Self {
+            current: Mutex::new(economy),
+            saver,
+        }
+    }
+
+    /// A cheap copy of the current economy
+    pub fn snapshot(&self) -> Economy {
+        self.current.lock().expect("economy lock poisoned").clone()
+    }
+
+    /// Compute a new economy from a snapshot with `f` and commit it. `f` may run more than once if
+    /// a conflicting update commits first, so it must not have side effects.
+    pub fn update<T>(&self, f: impl Fn(&Economy) -> Result<(Economy, T)>) -> Result<Update<T>> {
+        for _ in 0..MAX_ATTEMPTS {
+            let before = self.snapshot();
+            let (after, value) = f(&before)?;
+            let mut current = self.current.lock().expect("economy lock poisoned");
+            let rebased = if current.ptr_eq(&before) {
+                Some(after.clone())
+            } else {
+                current.rebase(&before, &after)
+            };
+            if let Some(rebased) = rebased {
+                // Submitted while still locked, so the saver never gets an older economy last
+                if let Some(saver) = &self.saver {
+                    saver.submit(&rebased);
+                }
+                *current = rebased;
+                return Ok(Update {
+                    before,
+                    after,
+                    value,
+                });
+            }
+        }
+        bail!("the economy is too busy right now, try again")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{money::Money, prediction_market::ShareKind};
+    use poise::serenity_prelude::UserId;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const CREATOR: UserId = UserId::new(1);
+    const ALICE: UserId = UserId::new(2);
+    const BOB: UserId = UserId::new(3);
+
+    #[test]
+    fn retries_after_a_conflicting_commit() {
+        let (economy, market_id) = Economy::new()
+            .create_market(
+                CREATOR,
+                "Question?".into(),
+                String::new(),
+                None,
+                0.5,
+                Money(0.0),
+                None,
+                None,
+            )
+            .unwrap();
+        let shared = SharedEconomy::new(economy, None);
+        let attempts = AtomicUsize::new(0);
+        let update = shared
+            .update(|economy| {
+                // Someone else trades in the same market while the first attempt runs
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    shared
+                        .update(|economy| economy.buy(BOB, market_id, Money(10.0), ShareKind::No))
+                        .unwrap();
+                }
+                economy.buy(ALICE, market_id, Money(10.0), ShareKind::Yes)
+            })
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        let market = shared.snapshot();
+        let market = market.market(market_id).unwrap();
+        assert_eq!(market.transaction_history.len(), 2);
+        assert!(
+            update
+                .before
+                .market(market_id)
+                .unwrap()
+                .transaction_history
+                .len()
+                == 1
+        );
+    }
+
+    #[test]
+    fn failed_updates_change_nothing() {
+        let shared = SharedEconomy::new(Economy::new(), None);
+        let before = shared.snapshot();
+        assert!(shared
+            .update(|economy| Ok((economy.tip(ALICE, BOB, Money(5000.0), None)?, ())))
+            .is_err());
+        assert!(shared.snapshot().ptr_eq(&before));
+    }
+}
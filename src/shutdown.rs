@@ -0,0 +1,103 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::{Notify, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+/// Coordinates stopping the bot: once shutdown begins, new commands are refused, and shutdown
+/// waits until every command already running has finished
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    started: Arc<AtomicBool>,
+    started_notify: Arc<Notify>,
+    /// Every running command holds a read guard, so taking the write guard waits for all of them
+    commands: Arc<RwLock<()>>,
+}
+
+impl Shutdown {
+    pub fn is_started(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    /// Wait until shutdown begins, so long-running commands can stop early
+    pub async fn started(&self) {
+        let notified = self.started_notify.notified();
+        if !self.is_started() {
+            notified.await;
+        }
+    }
+
+    /// Mark a command as running until the returned guard is dropped
+    pub async fn track_command(&self) -> OwnedRwLockReadGuard<()> {
+        self.commands.clone().read_owned().await
+    }
+
+    /// Refuse new commands and wait for running ones to finish. Commands that were already past
+    /// the check stay blocked for as long as the returned guard is held.
+    pub async fn begin(&self) -> OwnedRwLockWriteGuard<()> {
+        self.started.store(true, Ordering::SeqCst);
+        self.started_notify.notify_waiters();
+        self.commands.clone().write_owned().await
+    }
+}
+
+/// Wait until the process is asked to stop with SIGINT (Ctrl-C) or SIGTERM
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed handling SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => tracing::info!("received SIGINT"),
+            _ = terminate.recv() => tracing::info!("received SIGTERM"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed handling Ctrl-C");
+        tracing::info!("received Ctrl-C");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn waits_for_running_commands() {
+        let shutdown = Shutdown::default();
+        let command = shutdown.track_command().await;
+        let begin = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.begin().await }
+        });
+        shutdown.started().await;
+        assert!(shutdown.is_started());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!begin.is_finished());
+
+        drop(command);
+        let _blocked = begin.await.unwrap();
+        // Commands that got past the check wait until the process exits
+        let late = tokio::time::timeout(Duration::from_millis(10), shutdown.track_command());
+        assert!(late.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn started_returns_once_shutdown_began() {
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.is_started());
+        let waiting = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.started().await }
+        });
+        tokio::task::yield_now().await;
+        let _blocked = shutdown.begin().await;
+        waiting.await.unwrap();
+        // Including for anyone who only starts waiting afterwards
+        shutdown.started().await;
+    }
+}
@@ -0,0 +1,610 @@
+use anyhow::{ensure, Context, Result};
+use poise::serenity_prelude::GuildId;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use std::{fs::File, path::PathBuf};
+
+use crate::{
+    money::Money,
+    prediction_market::{MarketId, ResolveOutcome, Settings, ShareKind},
+    share_quantity::ShareQuantity,
+};
+
+type Economy = crate::prediction_market::Economy<u64>;
+
+/// Server the simulation runs in, so it can have its own settings
+const GUILD: GuildId = GuildId::new(1);
+
+/// Parameters of a simulation run, read from a JSON file
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SimulationConfig {
+    /// Seed for the random number generator, so runs are reproducible
+    seed: u64,
+    /// Saved economy to start from (default is a fresh economy)
+    state: Option<PathBuf>,
+    /// Number of markets the agents create and trade in
+    markets: usize,
+    /// Number of trading rounds before the markets resolve
+    rounds: usize,
+    /// Money agents new to the economy start with
+    start_balance: f64,
+    /// Cost of creating each market, which is also the starting size of its pools
+    market_creation_cost: f64,
+    agents: Vec<AgentConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+enum AgentConfig {
+    /// Agents that believe each market's true probability plus some uniform noise, and buy
+    /// whichever side they think is underpriced
+    Random {
+        count: usize,
+        /// Maximum distance between an agent's belief and the true probability
+        #[serde(default = "default_noise")]
+        noise: f64,
+        /// Chance an agent trades in a given round
+        #[serde(default = "default_activity")]
+        activity: f64,
+        /// Minimum difference between belief and market probability before trading
+        #[serde(default = "default_edge")]
+        edge: f64,
+        /// Fraction of the agent's cash spent on each trade
+        #[serde(default = "default_bet_fraction")]
+        bet_fraction: f64,
+    },
+    /// A single agent that performs a fixed list of actions
+    Scripted { actions: Vec<ScriptedAction> },
+}
+
+#[derive(Clone, Deserialize)]
+struct ScriptedAction {
+    round: usize,
+    /// Index of the simulated market, from 0 to `markets - 1`
+    market: usize,
+    #[serde(flatten)]
+    trade: ScriptedTrade,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum ScriptedTrade {
+    Buy { amount: f64, share_kind: ShareKind },
+    Sell { amount: Option<f64> },
+}
+
+fn default_noise() -> f64 {
+    0.1
+}
+
+fn default_activity() -> f64 {
+    0.5
+}
+
+fn default_edge() -> f64 {
+    0.03
+}
+
+fn default_bet_fraction() -> f64 {
+    0.05
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            state: None,
+            markets: 10,
+            rounds: 100,
+            start_balance: Settings::default().start_balance.0,
+            market_creation_cost: Settings::default().market_creation_cost.0,
+            agents: vec![AgentConfig::Random {
+                count: 20,
+                noise: default_noise(),
+                activity: default_activity(),
+                edge: default_edge(),
+                bet_fraction: default_bet_fraction(),
+            }],
+        }
+    }
+}
+
+enum Strategy {
+    Random {
+        /// Belief for each simulated market
+        beliefs: Vec<f64>,
+        activity: f64,
+        edge: f64,
+        bet_fraction: f64,
+    },
+    Scripted(Vec<ScriptedAction>),
+}
+
+struct Agent {
+    id: u64,
+    strategy: Strategy,
+}
+
+struct SimulatedMarket {
+    id: MarketId,
+    creator: u64,
+    true_probability: f64,
+    outcome: ShareKind,
+}
+
+#[derive(Default)]
+struct Stats {
+    trades: usize,
+    rejected_trades: usize,
+    volume: f64,
+    total_probability_change: f64,
+}
+
+fn load_economy(state: &Option<PathBuf>) -> Result<Economy> {
+    match state {
+        None => Ok(Economy::new()),
+        Some(path) => {
+            let economy = crate::storage::read_economy(path)?;
+            Ok(economy.map_users(|user| user.get()))
+        }
+    }
+}
+
+impl AgentConfig {
+    fn count(&self) -> usize {
+        match self {
+            AgentConfig::Random { count, .. } => *count,
+            AgentConfig::Scripted { .. } => 1,
+        }
+    }
+
+    /// Check the parameters random agents draw with, which would otherwise panic mid-run
+    fn validate(&self) -> Result<()> {
+        if let AgentConfig::Random {
+            noise,
+            activity,
+            edge,
+            bet_fraction,
+            ..
+        } = self
+        {
+            for (name, value) in [("activity", activity), ("bet_fraction", bet_fraction)] {
+                ensure!(
+                    (0.0..=1.0).contains(value),
+                    "{name} must be between 0 and 1, not {value}"
+                );
+            }
+            for (name, value) in [("noise", noise), ("edge", edge)] {
+                ensure!(
+                    value.is_finite() && !value.is_sign_negative(),
+                    "{name} can't be negative, not {value}"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SimulationConfig {
+    fn validate(&self) -> Result<()> {
+        ensure!(self.markets > 0, "simulation needs at least one market");
+        for (index, agent) in self.agents.iter().enumerate() {
+            agent
+                .validate()
+                .with_context(|| format!("invalid agent {index}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Pick a user ID for every agent. Existing users of a loaded economy become agents first, so
+/// their balances are used.
+fn agent_ids(config: &SimulationConfig, economy: &Economy) -> Vec<u64> {
+    let existing_users = economy
+        .balances()
+        .into_iter()
+        .map(|(user, _)| user)
+        .collect::<Vec<u64>>();
+    let new_users = (1..).filter(|id| !existing_users.contains(id));
+    existing_users
+        .iter()
+        .copied()
+        .chain(new_users)
+        .take(config.agents.iter().map(AgentConfig::count).sum())
+        .collect()
+}
+
+fn create_agents(
+    config: &SimulationConfig,
+    ids: &[u64],
+    markets: &[SimulatedMarket],
+    rng: &mut StdRng,
+) -> Vec<Agent> {
+    let mut ids = ids.iter().copied();
+    let mut agents = Vec::new();
+    for agent_config in &config.agents {
+        match agent_config {
+            AgentConfig::Random {
+                count,
+                noise,
+                activity,
+                edge,
+                bet_fraction,
+            } => {
+                for _ in 0..*count {
+                    let beliefs = markets
+                        .iter()
+                        .map(|market| {
+                            let belief =
+                                market.true_probability + noise * rng.gen_range(-1.0..=1.0);
+                            belief.clamp(0.01, 0.99)
+                        })
+                        .collect();
+                    agents.push(Agent {
+                        id: ids.next().expect("ran out of user IDs"),
+                        strategy: Strategy::Random {
+                            beliefs,
+                            activity: *activity,
+                            edge: *edge,
+                            bet_fraction: *bet_fraction,
+                        },
+                    });
+                }
+            }
+            AgentConfig::Scripted { actions } => agents.push(Agent {
+                id: ids.next().expect("ran out of user IDs"),
+                strategy: Strategy::Scripted(actions.clone()),
+            }),
+        }
+    }
+    agents
+}
+
+fn create_markets(
+    economy: &mut Economy,
+    num_markets: usize,
+    creators: &[u64],
+    rng: &mut StdRng,
+) -> Result<Vec<SimulatedMarket>> {
+    (0..num_markets)
+        .map(|i| {
+            let creator = creators[i % creators.len()];
+            let true_probability = rng.gen_range(0.05..0.95);
+            let outcome = if rng.gen_bool(true_probability) {
+                ShareKind::Yes
+            } else {
+                ShareKind::No
+            };
+            let (new_economy, id) = economy
+                .create_market(
+                    creator,
+                    format!("Simulated market {i}"),
+                    format!("True probability {:.0}%", true_probability * 100.0),
+                    None,
+                    0.5,
+                    Money(0.0),
+                    None,
+                    Some(GUILD),
+                )
+                .with_context(|| format!("agent {creator} failed creating market {i}"))?;
+            *economy = new_economy;
+            Ok(SimulatedMarket {
+                id,
+                creator,
+                true_probability,
+                outcome,
+            })
+        })
+        .collect()
+}
+
+fn buy(
+    economy: &mut Economy,
+    stats: &mut Stats,
+    user: u64,
+    market: MarketId,
+    amount: Money,
+    share_kind: ShareKind,
+) {
+    let old_probability = economy.market(market).map(|market| market.probability());
+    match economy.buy(user, market, amount, share_kind) {
+        Ok((new_economy, _)) => {
+            *economy = new_economy;
+            stats.record(economy, market, old_probability, amount);
+        }
+        Err(_) => stats.rejected_trades += 1,
+    }
+}
+
+fn sell(
+    economy: &mut Economy,
+    stats: &mut Stats,
+    user: u64,
+    market: MarketId,
+    amount: Option<ShareQuantity>,
+) {
+    let old_probability = economy.market(market).map(|market| market.probability());
+    match economy.sell(user, market, amount) {
+        Ok((new_economy, _, sale_price)) => {
+            *economy = new_economy;
+            stats.record(economy, market, old_probability, sale_price);
+        }
+        Err(_) => stats.rejected_trades += 1,
+    }
+}
+
+impl Stats {
+    fn record(
+        &mut self,
+        economy: &Economy,
+        market: MarketId,
+        old_probability: Result<u8>,
+        money: Money,
+    ) {
+        self.trades += 1;
+        self.volume += money.0;
+        if let (Ok(old), Ok(market)) = (old_probability, economy.market(market)) {
+            self.total_probability_change += f64::from(old.abs_diff(market.probability()));
+        }
+    }
+}
+
+fn run_round(
+    economy: &mut Economy,
+    stats: &mut Stats,
+    agents: &[Agent],
+    markets: &[SimulatedMarket],
+    round: usize,
+    rng: &mut StdRng,
+) {
+    for agent in agents {
+        match &agent.strategy {
+            Strategy::Random {
+                beliefs,
+                activity,
+                edge,
+                bet_fraction,
+            } => {
+                if !rng.gen_bool(*activity) {
+                    continue;
+                }
+                let index = rng.gen_range(0..markets.len());
+                let market_id = markets[index].id;
+                let Ok(market) = economy.market(market_id) else {
+                    continue;
+                };
+                let market_probability = f64::from(market.probability()) / 100.0;
+                let belief = beliefs[index];
+                let share_kind = if belief > market_probability + edge {
+                    ShareKind::Yes
+                } else if belief < market_probability - edge {
+                    ShareKind::No
+                } else {
+                    continue;
+                };
+                // Buying is only possible while holding no shares of the other kind
+                if let Some(held) = market.num_user_shares.get(&agent.id) {
+                    if held.kind != share_kind {
+                        sell(economy, stats, agent.id, market_id, None);
+                    }
+                }
+                let amount = Money(economy.balance(agent.id, Some(GUILD)).0 * bet_fraction);
+                buy(economy, stats, agent.id, market_id, amount, share_kind);
+            }
+            Strategy::Scripted(actions) => {
+                for action in actions.iter().filter(|action| action.round == round) {
+                    let Some(market) = markets.get(action.market) else {
+                        stats.rejected_trades += 1;
+                        continue;
+                    };
+                    match action.trade {
+                        ScriptedTrade::Buy { amount, share_kind } => buy(
+                            economy,
+                            stats,
+                            agent.id,
+                            market.id,
+                            Money(amount),
+                            share_kind,
+                        ),
+                        ScriptedTrade::Sell { amount } => sell(
+                            economy,
+                            stats,
+                            agent.id,
+                            market.id,
+                            amount.map(ShareQuantity),
+                        ),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+fn gini(sorted: &[f64]) -> f64 {
+    let n = sorted.len() as f64;
+    let total: f64 = sorted.iter().sum();
+    if total == 0.0 {
+        return 0.0;
+    }
+    let weighted: f64 = sorted
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (2.0 * (i as f64 + 1.0) - n - 1.0) * x)
+        .sum();
+    weighted / (n * total)
+}
+
+fn report_markets(economy: &Economy, markets: &[SimulatedMarket]) {
+    let mut brier = 0.0;
+    let mut error = 0.0;
+    let mut depth = 0.0;
+    for market in markets {
+        let Ok(state) = economy.market(market.id) else {
+            continue;
+        };
+        let probability = f64::from(state.probability()) / 100.0;
+        let outcome = match market.outcome {
+            ShareKind::Yes => 1.0,
+            ShareKind::No => 0.0,
+        };
+        brier += (probability - outcome).powi(2);
+        error += (probability - market.true_probability).abs();
+        let (y, n) = state.pool();
+        depth += (y * n).0.sqrt();
+    }
+    let count = markets.len() as f64;
+    println!("Market accuracy");
+    println!("  Brier score          {:.4}", brier / count);
+    println!("  Brier score at 50%   0.2500");
+    println!("  Mean absolute error  {:.1}%", error / count * 100.0);
+    println!("Liquidity");
+    println!("  Mean pool depth      {:.2} (√(YES × NO))", depth / count);
+}
+
+fn report_wealth(economy: &Economy, agents: &[Agent]) {
+    let mut wealth = agents
+        .iter()
+        .map(|agent| economy.balance(agent.id, Some(GUILD)).0)
+        .collect::<Vec<f64>>();
+    wealth.sort_by(|a, b| a.partial_cmp(b).expect("failed comparing balances"));
+    let total: f64 = wealth.iter().sum();
+    println!("Wealth distribution ({} agents)", wealth.len());
+    println!("  Total    {}", Money(total));
+    println!("  Mean     {}", Money(total / wealth.len() as f64));
+    println!("  Min      {}", Money(wealth[0]));
+    println!("  10th     {}", Money(percentile(&wealth, 0.1)));
+    println!("  Median   {}", Money(percentile(&wealth, 0.5)));
+    println!("  90th     {}", Money(percentile(&wealth, 0.9)));
+    println!("  Max      {}", Money(wealth[wealth.len() - 1]));
+    println!("  Gini     {:.3}", gini(&wealth));
+}
+
+/// Run a simulation described by the JSON file at `config_path`, or the default simulation, and
+/// print a report to stdout
+pub fn run(config_path: Option<&str>) -> Result<()> {
+    let config: SimulationConfig = match config_path {
+        None => SimulationConfig::default(),
+        Some(path) => {
+            let file = File::open(path)
+                .with_context(|| format!("failed opening simulation config {path}"))?;
+            serde_json::from_reader(file)
+                .with_context(|| format!("failed parsing simulation config {path}"))?
+        }
+    };
+    config.validate()?;
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut economy = load_economy(&config.state)?
+        .set_settings(
+            GUILD,
+            Settings {
+                start_balance: Money(config.start_balance),
+                market_creation_cost: Money(config.market_creation_cost),
+                ..Settings::default()
+            },
+        )
+        .context("invalid simulation settings")?;
+
+    let ids = agent_ids(&config, &economy);
+    ensure!(!ids.is_empty(), "simulation needs at least one agent");
+    let markets = create_markets(&mut economy, config.markets, &ids, &mut rng)?;
+    let agents = create_agents(&config, &ids, &markets, &mut rng);
+
+    let mut stats = Stats::default();
+    for round in 0..config.rounds {
+        run_round(&mut economy, &mut stats, &agents, &markets, round, &mut rng);
+    }
+
+    println!(
+        "Simulated {} agents trading in {} markets for {} rounds",
+        agents.len(),
+        markets.len(),
+        config.rounds
+    );
+    report_markets(&economy, &markets);
+    println!("  Trades               {}", stats.trades);
+    println!("  Rejected trades      {}", stats.rejected_trades);
+    println!("  Volume               {}", Money(stats.volume));
+    if stats.trades > 0 {
+        println!(
+            "  Mean price impact    {:.2}%",
+            stats.total_probability_change / stats.trades as f64
+        );
+    }
+
+    for market in &markets {
+        let outcome = match market.outcome {
+            ShareKind::Yes => ResolveOutcome::Yes,
+            ShareKind::No => ResolveOutcome::No,
+        };
+        let (new_economy, _) = economy.resolve_market(market.creator, market.id, outcome)?;
+        economy = new_economy;
+    }
+    report_wealth(&economy, &agents);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> SimulationConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(SimulationConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_chances_outside_zero_to_one() {
+        for agent in [
+            r#"{ "kind": "random", "count": 1, "activity": 1.5 }"#,
+            r#"{ "kind": "random", "count": 1, "activity": -0.1 }"#,
+            r#"{ "kind": "random", "count": 1, "bet_fraction": 2.0 }"#,
+            r#"{ "kind": "random", "count": 1, "noise": -0.1 }"#,
+        ] {
+            let config = parse(&format!(r#"{{ "agents": [{agent}] }}"#));
+            assert!(config.validate().is_err(), "{agent}");
+        }
+    }
+
+    #[test]
+    fn rejects_no_markets() {
+        assert!(parse(r#"{ "markets": 0 }"#).validate().is_err());
+    }
+
+    #[test]
+    fn scripted_trades_move_the_market() {
+        let config = parse(
+            r#"{ "markets": 1, "rounds": 2, "agents": [
+                { "kind": "scripted", "actions": [
+                    { "round": 0, "market": 0, "action": "buy", "amount": 100, "share_kind": "Yes" },
+                    { "round": 1, "market": 0, "action": "sell" }
+                ] }
+            ] }"#,
+        );
+        config.validate().unwrap();
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let mut economy = Economy::new();
+        let ids = agent_ids(&config, &economy);
+        let markets = create_markets(&mut economy, config.markets, &ids, &mut rng).unwrap();
+        let agents = create_agents(&config, &ids, &markets, &mut rng);
+        let mut stats = Stats::default();
+
+        run_round(&mut economy, &mut stats, &agents, &markets, 0, &mut rng);
+        assert!(economy.market(markets[0].id).unwrap().probability() > 50);
+        run_round(&mut economy, &mut stats, &agents, &markets, 1, &mut rng);
+        assert_eq!(economy.market(markets[0].id).unwrap().probability(), 50);
+        assert_eq!(stats.trades, 2);
+        assert_eq!(stats.rejected_trades, 0);
+    }
+}
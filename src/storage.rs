@@ -0,0 +1,98 @@
+mod json;
+mod saver;
+mod schema;
+mod sqlite;
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use crate::{
+    config::{Backend, StateConfig},
+    Economy,
+};
+
+pub use json::{read_economy, BackupPolicy, StateFile};
+pub use saver::Saver;
+pub use sqlite::SqliteStorage;
+
+/// Somewhere the economy is persisted between bot restarts
+pub trait Storage: Send {
+    /// Load the saved economy, or a fresh one if nothing was saved yet
+    fn load(&mut self) -> Result<Economy>;
+
+    /// Persist `economy`, replacing whatever was saved before
+    fn save(&mut self, economy: &Economy) -> Result<()>;
+
+    /// Human-readable location of the storage, for log messages
+    fn describe(&self) -> String;
+}
+
+/// Open the storage backend chosen in the config
+pub fn open(config: &StateConfig) -> Result<Box<dyn Storage>> {
+    match config.backend {
+        Backend::Json => Ok(Box::new(StateFile::new(
+            &config.path,
+            config.backup_policy.clone(),
+        ))),
+        Backend::Sqlite => Ok(Box::new(SqliteStorage::open(&config.path)?)),
+    }
+}
+
+/// Copy the economy in a JSON state file into a new SQLite database
+pub fn import_json_into_sqlite(json_path: &str, sqlite_path: &str) -> Result<()> {
+    // Unlike loading the bot's own state, a missing file is an error here: importing an empty
+    // economy would leave the database refusing the import with the right path
+    let economy = read_economy(Path::new(json_path))
+        .with_context(|| format!("failed loading {json_path}"))?;
+    let mut sqlite = SqliteStorage::open(sqlite_path)?;
+    sqlite.import(&economy)?;
+    tracing::info!(
+        "imported {} users and {} markets from {json_path} into {sqlite_path}",
+        economy.user_money.len(),
+        economy.markets.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Money;
+    use poise::serenity_prelude::UserId;
+
+    #[test]
+    fn imports_a_json_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_path = dir.path().join("state.json");
+        let sqlite_path = dir.path().join("state.sqlite");
+        let economy = Economy::new()
+            .tip(UserId::new(1), UserId::new(2), Money(10.0), None)
+            .unwrap();
+        StateFile::new(&json_path, BackupPolicy::default())
+            .save(&economy)
+            .unwrap();
+
+        import_json_into_sqlite(json_path.to_str().unwrap(), sqlite_path.to_str().unwrap())
+            .unwrap();
+        let imported = SqliteStorage::open(&sqlite_path).unwrap().load().unwrap();
+        assert_eq!(
+            serde_json::to_value(&imported).unwrap(),
+            serde_json::to_value(&economy).unwrap()
+        );
+    }
+
+    #[test]
+    fn missing_json_files_leave_the_database_importable() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_path = dir.path().join("state.json");
+        let sqlite_path = dir.path().join("state.sqlite");
+        let sqlite_path = sqlite_path.to_str().unwrap();
+        let typo = dir.path().join("sate.json");
+        assert!(import_json_into_sqlite(typo.to_str().unwrap(), sqlite_path).is_err());
+
+        StateFile::new(&json_path, BackupPolicy::default())
+            .save(&Economy::new())
+            .unwrap();
+        import_json_into_sqlite(json_path.to_str().unwrap(), sqlite_path).unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::{schema, Storage};
+use crate::Economy;
+
+const BACKUP_PREFIX: &str = "state-";
+const BACKUP_SUFFIX: &str = ".json";
+const BACKUP_TIME_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+/// How many old copies of the state file to keep, and how often to make them
+#[derive(Clone)]
+pub struct BackupPolicy {
+    /// Number of backups to keep. Zero disables backups.
+    pub count: usize,
+    /// Minimum time between backups, so a burst of commands doesn't rotate out every older copy
+    pub interval: Duration,
+}
+
+impl Default for BackupPolicy {
+    fn default() -> Self {
+        Self {
+            count: 10,
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// The economy's JSON file on disk, along with its rotating backups
+#[derive(Clone)]
+pub struct StateFile {
+    path: PathBuf,
+    backup_dir: PathBuf,
+    backup_policy: BackupPolicy,
+}
+
+impl StateFile {
+    pub fn new(path: impl Into<PathBuf>, backup_policy: BackupPolicy) -> Self {
+        let path = path.into();
+        let backup_dir = path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join("backups");
+        Self {
+            path,
+            backup_dir,
+            backup_policy,
+        }
+    }
+
+    /// Paths of all backups, oldest first
+    fn backups(&self) -> Result<Vec<PathBuf>> {
+        let entries = match fs::read_dir(&self.backup_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("failed listing backups in {}", self.backup_dir.display())
+                })
+            }
+        };
+        let mut backups = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| backup_time(path).is_some())
+            .collect::<Vec<PathBuf>>();
+        // The timestamp format sorts lexicographically in time order
+        backups.sort();
+        Ok(backups)
+    }
+
+    /// Copy the current state file into the backup directory and delete the oldest backups
+    fn backup(&self) -> Result<()> {
+        if self.backup_policy.count == 0 || !self.path.exists() {
+            return Ok(());
+        }
+        let mut backups = self.backups()?;
+        let now = Utc::now();
+        let newest = backups.last().and_then(|path| backup_time(path));
+        if newest.is_some_and(|newest| {
+            (now - newest).to_std().unwrap_or_default() < self.backup_policy.interval
+        }) {
+            return Ok(());
+        }
+        // A corrupt state file would rotate out the good backups the economy can be restored from
+        if let Err(e) = read_economy(&self.path) {
+            tracing::warn!("not backing up unreadable state: {e:#}");
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.backup_dir)
+            .with_context(|| format!("failed creating {}", self.backup_dir.display()))?;
+        let backup_path = self.backup_dir.join(format!(
+            "{BACKUP_PREFIX}{}{BACKUP_SUFFIX}",
+            now.format(BACKUP_TIME_FORMAT)
+        ));
+        // A hard link is free and the state file is only ever replaced, never modified in place
+        if fs::hard_link(&self.path, &backup_path).is_err() {
+            fs::copy(&self.path, &backup_path)
+                .with_context(|| format!("failed copying state to {}", backup_path.display()))?;
+        }
+        sync_dir(&backup_path)?;
+        tracing::info!("backed up state to {}", backup_path.display());
+        backups.push(backup_path);
+
+        let excess = backups.len().saturating_sub(self.backup_policy.count);
+        for old_backup in &backups[..excess] {
+            fs::remove_file(old_backup)
+                .with_context(|| format!("failed removing old backup {}", old_backup.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for StateFile {
+    /// Load the economy, falling back to the newest backup that can be read if the state file is
+    /// missing or corrupt. A fresh economy is only used when there is nothing on disk at all, so a
+    /// bad state file never gets silently overwritten.
+    fn load(&mut self) -> Result<Economy> {
+        let backups = self.backups()?;
+        let state_error = match read_economy(&self.path) {
+            Ok(economy) => return Ok(economy),
+            Err(e) if is_not_found(&e) && backups.is_empty() => {
+                tracing::info!(
+                    "no state file at {}, starting a fresh economy",
+                    self.path.display()
+                );
+                return Ok(Economy::new());
+            }
+            Err(e) => e,
+        };
+        tracing::error!(
+            "failed loading {}: {state_error:#}, trying backups",
+            self.path.display()
+        );
+        for backup in backups.iter().rev() {
+            match read_economy(backup) {
+                Ok(economy) => {
+                    tracing::warn!("restored economy from backup {}", backup.display());
+                    return Ok(economy);
+                }
+                Err(e) => tracing::error!("failed loading backup: {e:#}"),
+            }
+        }
+        bail!(
+            "no valid state in {} or its {} backups in {}",
+            self.path.display(),
+            backups.len(),
+            self.backup_dir.display()
+        )
+    }
+
+    /// Atomically replace the state file with `economy`, first backing up the previous state if
+    /// the newest backup is old enough and the previous state can be read
+    fn save(&mut self, economy: &Economy) -> Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("failed creating {}", tmp_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        schema::to_writer(&mut writer, economy)
+            .with_context(|| format!("failed writing economy to {}", tmp_path.display()))?;
+        writer
+            .into_inner()
+            .context("failed flushing state")?
+            .sync_all()
+            .with_context(|| format!("failed syncing {}", tmp_path.display()))?;
+
+        if let Err(e) = self.backup() {
+            tracing::error!("failed backing up state: {e:#}");
+        }
+
+        fs::rename(&tmp_path, &self.path).with_context(|| {
+            format!(
+                "failed renaming {} to {}",
+                tmp_path.display(),
+                self.path.display()
+            )
+        })?;
+        sync_dir(&self.path)
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+/// Read an economy from a JSON state file of any schema version
+pub fn read_economy(path: &Path) -> Result<Economy> {
+    let file = File::open(path).with_context(|| format!("failed opening {}", path.display()))?;
+    schema::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("failed parsing {}", path.display()))
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+fn backup_time(path: &Path) -> Option<DateTime<Utc>> {
+    let name = path.file_name()?.to_str()?;
+    let timestamp = name
+        .strip_prefix(BACKUP_PREFIX)?
+        .strip_suffix(BACKUP_SUFFIX)?;
+    chrono::NaiveDateTime::parse_from_str(timestamp, BACKUP_TIME_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// Sync the directory containing `path`, so a rename or new file in it survives a crash
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("failed syncing directory {}", dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Money;
+    use poise::serenity_prelude::UserId;
+
+    fn economy_with_balance(balance: f64) -> Economy {
+        Economy::new()
+            .tip(
+                UserId::new(1),
+                UserId::new(2),
+                Money(1000.0 - balance),
+                None,
+            )
+            .unwrap()
+    }
+
+    fn balance(economy: &Economy) -> Money {
+        economy.balance(UserId::new(1), None)
+    }
+
+    fn every_save() -> BackupPolicy {
+        BackupPolicy {
+            count: 2,
+            interval: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn starts_fresh_without_a_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = StateFile::new(dir.path().join("state.json"), every_save());
+        assert!(storage.load().unwrap().balances().is_empty());
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = StateFile::new(dir.path().join("state.json"), every_save());
+        storage.save(&economy_with_balance(900.0)).unwrap();
+        assert!(balance(&storage.load().unwrap()) == Money(900.0));
+        assert!(!dir.path().join("state.json.tmp").exists());
+    }
+
+    #[test]
+    fn falls_back_to_the_newest_readable_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut storage = StateFile::new(&path, every_save());
+        storage.save(&economy_with_balance(900.0)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        // Backs up the first save
+        storage.save(&economy_with_balance(800.0)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        // Backs up the second
+        storage.save(&economy_with_balance(700.0)).unwrap();
+
+        fs::write(&path, "{ not json").unwrap();
+        assert!(balance(&storage.load().unwrap()) == Money(800.0));
+
+        let newest = storage.backups().unwrap().pop().unwrap();
+        fs::write(newest, "{ not json").unwrap();
+        assert!(balance(&storage.load().unwrap()) == Money(900.0));
+    }
+
+    #[test]
+    fn refuses_to_start_fresh_over_a_corrupt_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(&path, "{ not json").unwrap();
+        assert!(StateFile::new(&path, every_save()).load().is_err());
+    }
+
+    #[test]
+    fn never_backs_up_a_corrupt_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut storage = StateFile::new(&path, every_save());
+        storage.save(&economy_with_balance(900.0)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        storage.save(&economy_with_balance(800.0)).unwrap();
+        let backups = storage.backups().unwrap();
+
+        // Restarting over a corrupt state file restores the backup and saves without backing up
+        fs::write(&path, "{ not json").unwrap();
+        let economy = storage.load().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        storage.save(&economy).unwrap();
+        assert_eq!(storage.backups().unwrap(), backups);
+
+        // Once the state can be read again, it is backed up again
+        std::thread::sleep(Duration::from_millis(5));
+        storage.save(&economy_with_balance(700.0)).unwrap();
+        let newest = storage.backups().unwrap().pop().unwrap();
+        assert!(balance(&read_economy(&newest).unwrap()) == Money(900.0));
+    }
+
+    #[test]
+    fn keeps_only_the_newest_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = StateFile::new(dir.path().join("state.json"), every_save());
+        for balance in [900.0, 800.0, 700.0, 600.0, 500.0] {
+            storage.save(&economy_with_balance(balance)).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let backups = storage.backups().unwrap();
+        assert_eq!(backups.len(), 2);
+        assert!(balance(&read_economy(&backups[1]).unwrap()) == Money(600.0));
+    }
+
+    #[test]
+    fn waits_the_interval_between_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = StateFile::new(dir.path().join("state.json"), BackupPolicy::default());
+        for balance in [900.0, 800.0, 700.0] {
+            storage.save(&economy_with_balance(balance)).unwrap();
+        }
+        assert_eq!(storage.backups().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,262 @@
+use anyhow::Result;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use super::Storage;
+use crate::{metrics::Metrics, Economy};
+
+/// How long to wait after a change before saving, so a burst of commands is saved once
+const SAVE_DEBOUNCE: Duration = Duration::from_secs(1);
+/// How long to wait before retrying a failed save, which doubles after each failure in a row up
+/// to `MAX_RETRY_DELAY`
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Handle to a background task that persists snapshots of the economy off the command path.
+/// Snapshots are cheap because the economy is made of persistent `im` maps.
+#[derive(Clone)]
+pub struct Saver {
+    snapshots: Arc<watch::Sender<Economy>>,
+    flush_requests: mpsc::Sender<oneshot::Sender<Result<String>>>,
+}
+
+impl Saver {
+    /// Start the background task, which owns `storage`. `economy` is the state that was just
+    /// loaded from it. How long each save takes is recorded in `metrics`.
+    pub fn spawn(storage: Box<dyn Storage>, economy: Economy, metrics: Metrics) -> Self {
+        let (snapshots, receiver) = watch::channel(economy.clone());
+        let (flush_requests, flush_receiver) = mpsc::channel(1);
+        tokio::spawn(run(storage, economy, receiver, flush_receiver, metrics));
+        Self {
+            snapshots: Arc::new(snapshots),
+            flush_requests,
+        }
+    }
+
+    /// Queue `economy` to be saved, unless it is the same as the last queued snapshot
+    pub fn submit(&self, economy: &Economy) {
+        self.snapshots.send_if_modified(|latest| {
+            if latest.ptr_eq(economy) {
+                false
+            } else {
+                *latest = economy.clone();
+                true
+            }
+        });
+    }
+
+    /// Save the latest snapshot now if it hasn't been saved, and wait until it is written.
+    /// Returns a summary of what was saved where.
+    pub async fn flush(&self) -> Result<String> {
+        let (reply, done) = oneshot::channel();
+        self.flush_requests
+            .send(reply)
+            .await
+            .map_err(|_| anyhow::anyhow!("saving task stopped"))?;
+        done.await?
+    }
+}
+
+async fn run(
+    mut storage: Box<dyn Storage>,
+    mut saved: Economy,
+    mut snapshots: watch::Receiver<Economy>,
+    mut flush_requests: mpsc::Receiver<oneshot::Sender<Result<String>>>,
+    metrics: Metrics,
+) {
+    // Until a failed save is retried, the snapshot it failed on has already been seen, so no
+    // change would trigger another attempt
+    let mut retry_delay = None;
+    loop {
+        let retry = async {
+            match retry_delay {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => std::future::pending().await,
+            }
+        };
+        let succeeded;
+        tokio::select! {
+            changed = snapshots.changed() => {
+                if changed.is_ok() {
+                    tokio::time::sleep(SAVE_DEBOUNCE).await;
+                }
+                let result;
+                (storage, result) = save_latest(storage, &mut saved, &mut snapshots, &metrics).await;
+                if changed.is_err() {
+                    return;
+                }
+                succeeded = result.is_ok();
+            }
+            () = retry => {
+                let result;
+                (storage, result) = save_latest(storage, &mut saved, &mut snapshots, &metrics).await;
+                succeeded = result.is_ok();
+            }
+            Some(reply) = flush_requests.recv() => {
+                let result;
+                (storage, result) = save_latest(storage, &mut saved, &mut snapshots, &metrics).await;
+                succeeded = result.is_ok();
+                let _ = reply.send(result.map(|()| {
+                    format!(
+                        "{} users and {} markets to {}",
+                        saved.balances().len(),
+                        saved.list_markets().count(),
+                        storage.describe()
+                    )
+                }));
+            }
+        }
+        retry_delay = if succeeded {
+            None
+        } else {
+            Some(retry_delay.map_or(RETRY_DELAY, |delay: Duration| {
+                (delay * 2).min(MAX_RETRY_DELAY)
+            }))
+        };
+    }
+}
+
+/// Save the newest snapshot if it differs from `saved`. Returns the storage, which is moved onto
+/// a blocking thread while writing, along with the error if saving failed.
+async fn save_latest(
+    mut storage: Box<dyn Storage>,
+    saved: &mut Economy,
+    snapshots: &mut watch::Receiver<Economy>,
+    metrics: &Metrics,
+) -> (Box<dyn Storage>, Result<()>) {
+    let economy = snapshots.borrow_and_update().clone();
+    if economy.ptr_eq(saved) {
+        return (storage, Ok(()));
+    }
+    let (storage, result, economy) = tokio::task::spawn_blocking(move || {
+        let start = std::time::Instant::now();
+        let result = storage.save(&economy).map(|()| start.elapsed());
+        (storage, result, economy)
+    })
+    .await
+    .expect("saving task panicked");
+    let result = match result {
+        Ok(elapsed) => {
+            tracing::debug!("saved {} in {elapsed:?}", storage.describe());
+            metrics.record_save(elapsed);
+            *saved = economy;
+            Ok(())
+        }
+        Err(e) => {
+            tracing::error!("failed saving {}: {e:#}", storage.describe());
+            metrics.record_save_error();
+            Err(e)
+        }
+    };
+    (storage, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{money::Money, shared_economy::SharedEconomy};
+    use poise::serenity_prelude::UserId;
+    use std::sync::Mutex;
+
+    /// Storage that fails its first `failures` saves and records the rest
+    struct FlakyStorage {
+        failures: usize,
+        saved: Arc<Mutex<Vec<Economy>>>,
+    }
+
+    impl Storage for FlakyStorage {
+        fn load(&mut self) -> Result<Economy> {
+            Ok(Economy::new())
+        }
+
+        fn save(&mut self, economy: &Economy) -> Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                anyhow::bail!("disk full");
+            }
+            self.saved.lock().unwrap().push(economy.clone());
+            Ok(())
+        }
+
+        fn describe(&self) -> String {
+            "flaky storage".into()
+        }
+    }
+
+    fn spawn(failures: usize) -> (Saver, Arc<Mutex<Vec<Economy>>>) {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let storage = FlakyStorage {
+            failures,
+            saved: saved.clone(),
+        };
+        let saver = Saver::spawn(Box::new(storage), Economy::new(), Metrics::default());
+        (saver, saved)
+    }
+
+    fn changed_economy() -> Economy {
+        Economy::new()
+            .tip(UserId::new(1), UserId::new(2), Money(1.0), None)
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn saves_once_after_a_burst_of_changes() {
+        let (saver, saved) = spawn(0);
+        let economy = changed_economy();
+        saver.submit(&economy);
+        saver.submit(
+            &economy
+                .tip(UserId::new(1), UserId::new(2), Money(1.0), None)
+                .unwrap(),
+        );
+        tokio::time::sleep(SAVE_DEBOUNCE * 2).await;
+        assert_eq!(saved.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_failed_saves_without_new_changes() {
+        let (saver, saved) = spawn(2);
+        saver.submit(&changed_economy());
+        tokio::time::sleep(SAVE_DEBOUNCE + RETRY_DELAY).await;
+        assert!(saved.lock().unwrap().is_empty());
+        // The second retry waits twice as long, and succeeds
+        tokio::time::sleep(RETRY_DELAY * 3).await;
+        assert_eq!(saved.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flush_saves_immediately() {
+        let (saver, saved) = spawn(0);
+        saver.submit(&changed_economy());
+        saver.flush().await.unwrap();
+        assert_eq!(saved.lock().unwrap().len(), 1);
+        // Nothing changed since, so there is nothing more to save
+        tokio::time::sleep(SAVE_DEBOUNCE * 2).await;
+        assert_eq!(saved.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn saves_every_committed_update() {
+        let (saver, saved) = spawn(0);
+        let shared = SharedEconomy::new(Economy::new(), Some(saver.clone()));
+        let update = shared
+            .update(|economy| {
+                Ok((
+                    economy.tip(UserId::new(1), UserId::new(2), Money(1.0), None)?,
+                    (),
+                ))
+            })
+            .unwrap();
+        // Failed updates change nothing, so there's nothing more to save
+        assert!(shared
+            .update(|economy| Ok((
+                economy.tip(UserId::new(1), UserId::new(2), Money(5000.0), None)?,
+                ()
+            )))
+            .is_err());
+        saver.flush().await.unwrap();
+        let saved = saved.lock().unwrap();
+        assert_eq!(saved.len(), 1);
+        assert!(saved[0].ptr_eq(&update.after));
+    }
+}
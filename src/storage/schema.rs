@@ -0,0 +1,277 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::Economy;
+
+/// Version of the JSON document the economy is saved as. Bump this and add a migration to
+/// `MIGRATIONS` whenever the serialized form of the economy changes.
+pub const CURRENT_VERSION: u64 = 11;
+
+/// Migrations between versions, where `MIGRATIONS[i]` upgrades a version `i` document to version
+/// `i + 1`
+const MIGRATIONS: [fn(Value) -> Result<Value>; CURRENT_VERSION as usize] = [
+    wrap_economy,
+    add_close_notices,
+    add_resolution_dm_opt_outs,
+    add_watchlists,
+    add_alerts,
+    add_guild_settings,
+    add_preferences,
+    add_api_tokens,
+    add_resolved_markets,
+    add_subsidies,
+    add_templates,
+];
+
+#[derive(Serialize)]
+struct Document<'a> {
+    version: u64,
+    economy: &'a Economy,
+}
+
+/// Serialize the economy as a document of the current version
+pub fn to_writer(writer: impl std::io::Write, economy: &Economy) -> Result<()> {
+    let document = Document {
+        version: CURRENT_VERSION,
+        economy,
+    };
+    serde_json::to_writer(writer, &document).context("failed serializing economy")
+}
+
+/// Deserialize an economy from a document of any version, migrating it to the current version
+pub fn from_reader(reader: impl std::io::Read) -> Result<Economy> {
+    let document: Value = serde_json::from_reader(reader).context("invalid JSON")?;
+    let mut document = document;
+    let version = version(&document)?;
+    if version > CURRENT_VERSION {
+        bail!("state has version {version}, but this bot only understands up to version {CURRENT_VERSION}");
+    }
+    for (from_version, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        document = migration(document)
+            .with_context(|| format!("failed migrating state from version {from_version}"))?;
+    }
+    if version < CURRENT_VERSION {
+        tracing::info!("migrated state from version {version} to {CURRENT_VERSION}");
+    }
+    let Value::Object(mut document) = document else {
+        bail!("state is not a JSON object");
+    };
+    let economy = document.remove("economy").context("state has no economy")?;
+    serde_json::from_value(economy).context("failed deserializing economy")
+}
+
+fn version(document: &Value) -> Result<u64> {
+    match document.get("version") {
+        // Before versioning, the economy itself was the whole document
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .with_context(|| format!("invalid state version {version}")),
+    }
+}
+
+/// Version 1 wraps the economy in a document with a version number
+fn wrap_economy(economy: Value) -> Result<Value> {
+    Ok(json!({ "version": 1, "economy": economy }))
+}
+
+/// Version 2 records the channel each market was created in and when its creator was last told
+/// to resolve it after it closed. Older markets have no channel, so their notices are sent to the
+/// creator directly.
+fn add_close_notices(mut document: Value) -> Result<Value> {
+    for market in markets_mut(&mut document)? {
+        market.insert("channel".into(), Value::Null);
+        market.insert("last_close_notice".into(), Value::Null);
+    }
+    document["version"] = json!(2);
+    Ok(document)
+}
+
+/// Version 3 records who opted out of direct messages when markets resolve, which was nobody
+fn add_resolution_dm_opt_outs(mut document: Value) -> Result<Value> {
+    economy_mut(&mut document)?.insert("resolution_dm_opt_outs".into(), json!([]));
+    document["version"] = json!(3);
+    Ok(document)
+}
+
+/// Version 4 adds watchlists, which start out empty
+fn add_watchlists(mut document: Value) -> Result<Value> {
+    economy_mut(&mut document)?.insert("watchlists".into(), json!({}));
+    document["version"] = json!(4);
+    Ok(document)
+}
+
+/// Version 5 adds probability alerts, which start out empty
+fn add_alerts(mut document: Value) -> Result<Value> {
+    let economy = economy_mut(&mut document)?;
+    economy.insert("next_alert_id".into(), json!(0));
+    economy.insert("alerts".into(), json!({}));
+    document["version"] = json!(5);
+    Ok(document)
+}
+
+/// Version 6 lets servers change the economy's settings. Until then, every market cost $50 to
+/// create and belonged to no server in particular.
+fn add_guild_settings(mut document: Value) -> Result<Value> {
+    economy_mut(&mut document)?.insert("guild_settings".into(), json!({}));
+    for market in markets_mut(&mut document)? {
+        market.insert("guild".into(), Value::Null);
+        market.insert("creation_cost".into(), json!(50.0));
+    }
+    document["version"] = json!(6);
+    Ok(document)
+}
+
+/// Version 7 replaces the list of users who turned off direct messages about resolved markets
+/// with preferences for each user, which start out with those messages still off
+fn add_preferences(mut document: Value) -> Result<Value> {
+    let economy = economy_mut(&mut document)?;
+    let opt_outs = economy
+        .remove("resolution_dm_opt_outs")
+        .context("state has no resolution_dm_opt_outs")?;
+    let Value::Array(opt_outs) = opt_outs else {
+        bail!("resolution_dm_opt_outs is not a list");
+    };
+    let mut preferences = serde_json::Map::new();
+    for user in opt_outs {
+        let user = match user {
+            Value::String(user) => user,
+            Value::Number(user) => user.to_string(),
+            user => bail!("invalid user {user} in resolution_dm_opt_outs"),
+        };
+        preferences.insert(
+            user,
+            json!({
+                "time_zone": null,
+                "date_dialect": "Us",
+                "resolution_dms": false,
+                "watchlist_dms": true,
+                "decimal_places": 2,
+            }),
+        );
+    }
+    economy.insert("preferences".into(), Value::Object(preferences));
+    document["version"] = json!(7);
+    Ok(document)
+}
+
+/// Version 8 adds API tokens, which nobody has yet
+fn add_api_tokens(mut document: Value) -> Result<Value> {
+    economy_mut(&mut document)?.insert("api_tokens".into(), json!({}));
+    document["version"] = json!(8);
+    Ok(document)
+}
+
+/// Version 9 keeps resolved markets and their trades. Markets that resolved before then are gone.
+fn add_resolved_markets(mut document: Value) -> Result<Value> {
+    economy_mut(&mut document)?.insert("resolved_markets".into(), json!({}));
+    document["version"] = json!(9);
+    Ok(document)
+}
+
+/// Version 10 records subsidies added to markets' pools, which no market had yet
+fn add_subsidies(mut document: Value) -> Result<Value> {
+    for market in markets_mut(&mut document)? {
+        market.insert("subsidies".into(), json!([]));
+    }
+    document["version"] = json!(10);
+    Ok(document)
+}
+
+/// Version 11 adds recurring market templates and server treasuries, and records which markets a
+/// treasury paid for, which none had yet
+fn add_templates(mut document: Value) -> Result<Value> {
+    for market in markets_mut(&mut document)? {
+        market.insert("treasury".into(), Value::Null);
+    }
+    let economy = economy_mut(&mut document)?;
+    economy.insert("next_template_id".into(), json!(0));
+    economy.insert("templates".into(), json!({}));
+    economy.insert("treasuries".into(), json!({}));
+    document["version"] = json!(11);
+    Ok(document)
+}
+
+/// The economy in a versioned document
+fn economy_mut(document: &mut Value) -> Result<&mut serde_json::Map<String, Value>> {
+    document
+        .get_mut("economy")
+        .and_then(Value::as_object_mut)
+        .context("state has no economy")
+}
+
+/// Every market in a versioned document, to migrate one field at a time
+fn markets_mut(document: &mut Value) -> Result<Vec<&mut serde_json::Map<String, Value>>> {
+    let markets = document
+        .pointer_mut("/economy/markets")
+        .and_then(Value::as_object_mut)
+        .context("state has no markets")?;
+    markets
+        .values_mut()
+        .map(|market| {
+            market
+                .as_object_mut()
+                .context("market is not a JSON object")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Money;
+    use poise::serenity_prelude::UserId;
+
+    /// Saved states from each historical version, all holding the same economy
+    const FIXTURES: [&str; CURRENT_VERSION as usize + 1] = [
+        include_str!("../../tests/fixtures/state_v0.json"),
+        include_str!("../../tests/fixtures/state_v1.json"),
+        include_str!("../../tests/fixtures/state_v2.json"),
+        include_str!("../../tests/fixtures/state_v3.json"),
+        include_str!("../../tests/fixtures/state_v4.json"),
+        include_str!("../../tests/fixtures/state_v5.json"),
+        include_str!("../../tests/fixtures/state_v6.json"),
+        include_str!("../../tests/fixtures/state_v7.json"),
+        include_str!("../../tests/fixtures/state_v8.json"),
+        include_str!("../../tests/fixtures/state_v9.json"),
+        include_str!("../../tests/fixtures/state_v10.json"),
+        include_str!("../../tests/fixtures/state_v11.json"),
+    ];
+
+    #[test]
+    fn loads_every_version() {
+        for (version, fixture) in FIXTURES.iter().enumerate() {
+            let economy = from_reader(fixture.as_bytes())
+                .unwrap_or_else(|e| panic!("failed loading version {version}: {e:#}"));
+            let market = economy.market(0).unwrap();
+            assert_eq!(
+                market.question, "Will it rain tomorrow?",
+                "version {version}"
+            );
+            assert_eq!(market.transaction_history.len(), 1, "version {version}");
+            assert_eq!(market.probability(), 59, "version {version}");
+            assert!(economy.market(1).is_err(), "version {version}");
+            assert!(
+                economy.balance(UserId::new(200), None) == Money(990.0),
+                "version {version}"
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips_current_version() {
+        let economy = from_reader(FIXTURES[CURRENT_VERSION as usize].as_bytes()).unwrap();
+        let mut saved = Vec::new();
+        to_writer(&mut saved, &economy).unwrap();
+        let saved: Value = serde_json::from_slice(&saved).unwrap();
+        let fixture: Value = serde_json::from_str(FIXTURES[CURRENT_VERSION as usize]).unwrap();
+        assert_eq!(saved, fixture);
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let document = json!({ "version": CURRENT_VERSION + 1, "economy": {} });
+        assert!(from_reader(document.to_string().as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,1340 @@
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use im::{
+    ordmap::{DiffItem, OrdMap},
+    ordset::{self, OrdSet},
+};
+use poise::serenity_prelude::{ChannelId, GuildId, UserId};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::path::{Path, PathBuf};
+
+use super::Storage;
+use crate::{
+    money::Money,
+    prediction_market::{
+        Alert, AlertDirection, AlertId, ApiToken, DateDialect, Market, MarketId, Preferences,
+        Recurrence, ResolveOutcome, ResolvedMarket, Settings, ShareKind, ShareKindAndQuantity,
+        Subsidy, Template, TemplateId, TemplateInstance, TemplatePayer, TransactionInfo,
+        TransactionKind,
+    },
+    share_quantity::ShareQuantity,
+    Economy,
+};
+
+/// Migrations between database schema versions, where `MIGRATIONS[i]` upgrades a version `i`
+/// database to version `i + 1`. The schema version is kept in SQLite's `user_version`.
+const MIGRATIONS: [&str; 11] = [
+    "
+    CREATE TABLE meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+    CREATE TABLE users (
+        user_id INTEGER PRIMARY KEY,
+        balance REAL NOT NULL
+    );
+    CREATE TABLE markets (
+        market_id INTEGER PRIMARY KEY,
+        creator INTEGER NOT NULL,
+        question TEXT NOT NULL,
+        description TEXT NOT NULL,
+        yes_pool REAL NOT NULL,
+        no_pool REAL NOT NULL,
+        close_timestamp INTEGER,
+        creation_time TEXT NOT NULL
+    );
+    CREATE TABLE positions (
+        market_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        share_kind TEXT NOT NULL,
+        quantity REAL NOT NULL,
+        PRIMARY KEY (market_id, user_id)
+    );
+    CREATE TABLE transactions (
+        market_id INTEGER NOT NULL,
+        seq INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        share_kind TEXT NOT NULL,
+        quantity REAL NOT NULL,
+        money REAL NOT NULL,
+        new_probability INTEGER NOT NULL,
+        time TEXT NOT NULL,
+        PRIMARY KEY (market_id, seq)
+    );
+",
+    "
+    ALTER TABLE markets ADD COLUMN channel INTEGER;
+    ALTER TABLE markets ADD COLUMN last_close_notice TEXT;
+",
+    "
+    CREATE TABLE resolution_dm_opt_outs (
+        user_id INTEGER PRIMARY KEY
+    );
+",
+    "
+    CREATE TABLE watchlists (
+        user_id INTEGER NOT NULL,
+        market_id INTEGER NOT NULL,
+        PRIMARY KEY (user_id, market_id)
+    );
+",
+    "
+    CREATE TABLE alerts (
+        alert_id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        market_id INTEGER NOT NULL,
+        direction TEXT NOT NULL,
+        threshold INTEGER NOT NULL,
+        channel INTEGER
+    );
+",
+    "
+    CREATE TABLE guild_settings (
+        guild_id INTEGER PRIMARY KEY,
+        start_balance REAL NOT NULL,
+        market_creation_cost REAL NOT NULL,
+        default_time_zone TEXT NOT NULL,
+        big_move_points INTEGER NOT NULL
+    );
+    ALTER TABLE markets ADD COLUMN guild INTEGER;
+    ALTER TABLE markets ADD COLUMN creation_cost REAL NOT NULL DEFAULT 50.0;
+",
+    "
+    CREATE TABLE preferences (
+        user_id INTEGER PRIMARY KEY,
+        time_zone TEXT,
+        date_dialect TEXT NOT NULL,
+        resolution_dms INTEGER NOT NULL,
+        watchlist_dms INTEGER NOT NULL,
+        decimal_places INTEGER NOT NULL
+    );
+    INSERT INTO preferences
+        SELECT user_id, NULL, 'us', 0, 1, 2 FROM resolution_dm_opt_outs;
+    DROP TABLE resolution_dm_opt_outs;
+",
+    "
+    CREATE TABLE api_tokens (
+        user_id INTEGER PRIMARY KEY,
+        hash TEXT NOT NULL,
+        created TEXT NOT NULL
+    );
+",
+    "
+    CREATE TABLE resolved_markets (
+        market_id INTEGER PRIMARY KEY,
+        creator INTEGER NOT NULL,
+        question TEXT NOT NULL,
+        description TEXT NOT NULL,
+        close_timestamp INTEGER,
+        creation_time TEXT NOT NULL,
+        probability INTEGER NOT NULL,
+        outcome TEXT NOT NULL
+    );
+",
+    "
+    CREATE TABLE subsidies (
+        market_id INTEGER NOT NULL,
+        seq INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        money REAL NOT NULL,
+        leftover_kind TEXT NOT NULL,
+        leftover_quantity REAL NOT NULL,
+        time TEXT NOT NULL,
+        PRIMARY KEY (market_id, seq)
+    );
+",
+    "
+    ALTER TABLE markets ADD COLUMN treasury INTEGER;
+    CREATE TABLE treasuries (
+        guild_id INTEGER PRIMARY KEY,
+        balance REAL NOT NULL
+    );
+    CREATE TABLE templates (
+        template_id INTEGER PRIMARY KEY,
+        creator INTEGER NOT NULL,
+        question TEXT NOT NULL,
+        description TEXT NOT NULL,
+        recurrence TEXT NOT NULL,
+        time_zone TEXT NOT NULL,
+        payer TEXT NOT NULL,
+        next_open TEXT NOT NULL,
+        next_close TEXT NOT NULL,
+        channel INTEGER NOT NULL,
+        guild INTEGER NOT NULL
+    );
+    CREATE TABLE template_instances (
+        template_id INTEGER NOT NULL,
+        seq INTEGER NOT NULL,
+        market_id INTEGER NOT NULL,
+        question TEXT NOT NULL,
+        close_time TEXT NOT NULL,
+        outcome TEXT,
+        PRIMARY KEY (template_id, seq)
+    );
+",
+];
+
+/// Economy stored in an SQLite database, one row per user, market, position and transaction.
+/// Saving only writes the rows that changed since the last load or save.
+pub struct SqliteStorage {
+    path: PathBuf,
+    connection: Connection,
+    /// Economy as it is in the database, to diff against when saving
+    saved: Option<Economy>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut connection = Connection::open(&path)
+            .with_context(|| format!("failed opening SQLite database {}", path.display()))?;
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .context("failed enabling write-ahead logging")?;
+        migrate(&mut connection)
+            .with_context(|| format!("failed migrating database {}", path.display()))?;
+        Ok(Self {
+            path,
+            connection,
+            saved: None,
+        })
+    }
+
+    /// Write `economy` into a database that doesn't have one yet
+    pub fn import(&mut self, economy: &Economy) -> Result<()> {
+        ensure!(
+            self.load_next_market_id()?.is_none(),
+            "database {} already contains an economy",
+            self.path.display()
+        );
+        self.save(economy)
+    }
+
+    fn load_next_market_id(&self) -> Result<Option<MarketId>> {
+        self.load_meta("next_market_id")
+    }
+
+    fn load_meta(&self, key: &str) -> Result<Option<u64>> {
+        self.connection
+            .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()
+            .with_context(|| format!("failed reading {key}"))
+    }
+
+    fn load_economy(&self) -> Result<Economy> {
+        let Some(next_market_id) = self.load_next_market_id()? else {
+            return Ok(Economy::new());
+        };
+
+        let mut user_money = OrdMap::new();
+        let mut statement = self
+            .connection
+            .prepare("SELECT user_id, balance FROM users")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            user_money.insert(to_user(row.get(0)?)?, Money(row.get(1)?));
+        }
+
+        let mut markets = OrdMap::new();
+        let mut statement = self.connection.prepare(
+            "SELECT market_id, creator, question, description, yes_pool, no_pool, \
+             close_timestamp, creation_time, channel, last_close_notice, guild, creation_cost, \
+             treasury FROM markets",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: MarketId = row.get(0)?;
+            let market = Market {
+                id,
+                creator: to_user(row.get(1)?)?,
+                question: row.get(2)?,
+                description: row.get(3)?,
+                y: ShareQuantity(row.get(4)?),
+                n: ShareQuantity(row.get(5)?),
+                num_user_shares: OrdMap::new(),
+                transaction_history: Vec::new(),
+                subsidies: Vec::new(),
+                close_timestamp: row.get(6)?,
+                creation_time: row.get(7)?,
+                channel: row.get::<_, Option<i64>>(8)?.map(to_channel).transpose()?,
+                last_close_notice: row.get(9)?,
+                guild: row.get::<_, Option<i64>>(10)?.map(to_guild).transpose()?,
+                creation_cost: Money(row.get(11)?),
+                treasury: row.get::<_, Option<i64>>(12)?.map(to_guild).transpose()?,
+            };
+            markets.insert(id, market);
+        }
+
+        // Their trades are in the transactions table like those of unresolved markets
+        let mut resolved_markets = OrdMap::new();
+        let mut statement = self.connection.prepare(
+            "SELECT market_id, creator, question, description, close_timestamp, creation_time, \
+             probability, outcome FROM resolved_markets",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: MarketId = row.get(0)?;
+            let market = ResolvedMarket {
+                id,
+                creator: to_user(row.get(1)?)?,
+                question: row.get(2)?,
+                description: row.get(3)?,
+                close_timestamp: row.get(4)?,
+                creation_time: row.get(5)?,
+                probability: row.get(6)?,
+                outcome: to_resolve_outcome(&row.get::<_, String>(7)?)?,
+                transaction_history: Vec::new(),
+            };
+            resolved_markets.insert(id, market);
+        }
+
+        let mut statement = self
+            .connection
+            .prepare("SELECT market_id, user_id, share_kind, quantity FROM positions")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let market_id: MarketId = row.get(0)?;
+            let market = markets
+                .get_mut(&market_id)
+                .with_context(|| format!("position in missing market {market_id}"))?;
+            market.num_user_shares.insert(
+                to_user(row.get(1)?)?,
+                ShareKindAndQuantity {
+                    kind: to_share_kind(&row.get::<_, String>(2)?)?,
+                    quantity: ShareQuantity(row.get(3)?),
+                },
+            );
+        }
+
+        let mut statement = self.connection.prepare(
+            "SELECT market_id, user_id, kind, share_kind, quantity, money, new_probability, time \
+             FROM transactions ORDER BY market_id, seq",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let market_id: MarketId = row.get(0)?;
+            let transaction_history = match markets.get_mut(&market_id) {
+                Some(market) => &mut market.transaction_history,
+                None => {
+                    &mut resolved_markets
+                        .get_mut(&market_id)
+                        .with_context(|| format!("transaction in missing market {market_id}"))?
+                        .transaction_history
+                }
+            };
+            transaction_history.push(TransactionInfo {
+                user: to_user(row.get(1)?)?,
+                kind: to_transaction_kind(&row.get::<_, String>(2)?)?,
+                shares: ShareKindAndQuantity {
+                    kind: to_share_kind(&row.get::<_, String>(3)?)?,
+                    quantity: ShareQuantity(row.get(4)?),
+                },
+                money: Money(row.get(5)?),
+                new_probability: row.get(6)?,
+                time: row.get::<_, DateTime<Utc>>(7)?,
+            });
+        }
+
+        let mut statement = self.connection.prepare(
+            "SELECT market_id, user_id, money, leftover_kind, leftover_quantity, time \
+             FROM subsidies ORDER BY market_id, seq",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let market_id: MarketId = row.get(0)?;
+            let market = markets
+                .get_mut(&market_id)
+                .with_context(|| format!("subsidy in missing market {market_id}"))?;
+            market.subsidies.push(Subsidy {
+                user: to_user(row.get(1)?)?,
+                money: Money(row.get(2)?),
+                leftover: ShareKindAndQuantity {
+                    kind: to_share_kind(&row.get::<_, String>(3)?)?,
+                    quantity: ShareQuantity(row.get(4)?),
+                },
+                time: row.get::<_, DateTime<Utc>>(5)?,
+            });
+        }
+
+        let mut preferences = OrdMap::new();
+        let mut statement = self.connection.prepare(
+            "SELECT user_id, time_zone, date_dialect, resolution_dms, watchlist_dms, \
+             decimal_places FROM preferences",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let user_preferences = Preferences {
+                time_zone: row.get(1)?,
+                date_dialect: to_date_dialect(&row.get::<_, String>(2)?)?,
+                resolution_dms: row.get(3)?,
+                watchlist_dms: row.get(4)?,
+                decimal_places: row.get(5)?,
+            };
+            preferences.insert(to_user(row.get(0)?)?, user_preferences);
+        }
+
+        let mut api_tokens = OrdMap::new();
+        let mut statement = self
+            .connection
+            .prepare("SELECT user_id, hash, created FROM api_tokens")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let token = ApiToken {
+                hash: row.get(1)?,
+                created: row.get::<_, DateTime<Utc>>(2)?,
+            };
+            api_tokens.insert(to_user(row.get(0)?)?, token);
+        }
+
+        let mut watchlists = OrdMap::<UserId, OrdSet<MarketId>>::new();
+        let mut statement = self
+            .connection
+            .prepare("SELECT user_id, market_id FROM watchlists")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            watchlists
+                .entry(to_user(row.get(0)?)?)
+                .or_default()
+                .insert(row.get(1)?);
+        }
+
+        let mut alerts = OrdMap::new();
+        let mut statement = self.connection.prepare(
+            "SELECT alert_id, user_id, market_id, direction, threshold, channel FROM alerts",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: AlertId = row.get(0)?;
+            let alert = Alert {
+                id,
+                user: to_user(row.get(1)?)?,
+                market_id: row.get(2)?,
+                direction: to_alert_direction(&row.get::<_, String>(3)?)?,
+                threshold: row.get(4)?,
+                channel: row.get::<_, Option<i64>>(5)?.map(to_channel).transpose()?,
+            };
+            alerts.insert(id, alert);
+        }
+
+        let mut guild_settings = OrdMap::new();
+        let mut statement = self.connection.prepare(
+            "SELECT guild_id, start_balance, market_creation_cost, default_time_zone, \
+             big_move_points FROM guild_settings",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let settings = Settings {
+                start_balance: Money(row.get(1)?),
+                market_creation_cost: Money(row.get(2)?),
+                default_time_zone: row.get(3)?,
+                big_move_points: row.get(4)?,
+            };
+            guild_settings.insert(to_guild(row.get(0)?)?, settings);
+        }
+
+        let mut templates = OrdMap::new();
+        let mut statement = self.connection.prepare(
+            "SELECT template_id, creator, question, description, recurrence, time_zone, payer, \
+             next_open, next_close, channel, guild FROM templates",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: TemplateId = row.get(0)?;
+            let template = Template {
+                id,
+                creator: to_user(row.get(1)?)?,
+                question: row.get(2)?,
+                description: row.get(3)?,
+                recurrence: to_recurrence(&row.get::<_, String>(4)?)?,
+                time_zone: row.get(5)?,
+                payer: to_template_payer(&row.get::<_, String>(6)?)?,
+                next_open: row.get::<_, DateTime<Utc>>(7)?,
+                next_close: row.get::<_, DateTime<Utc>>(8)?,
+                channel: to_channel(row.get(9)?)?,
+                guild: to_guild(row.get(10)?)?,
+                instances: Vec::new(),
+            };
+            templates.insert(id, template);
+        }
+
+        let mut statement = self.connection.prepare(
+            "SELECT template_id, market_id, question, close_time, outcome \
+             FROM template_instances ORDER BY template_id, seq",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let template_id: TemplateId = row.get(0)?;
+            let template = templates
+                .get_mut(&template_id)
+                .with_context(|| format!("instance of missing template {template_id}"))?;
+            template.instances.push(TemplateInstance {
+                market_id: row.get(1)?,
+                question: row.get(2)?,
+                close_time: row.get::<_, DateTime<Utc>>(3)?,
+                outcome: row
+                    .get::<_, Option<String>>(4)?
+                    .as_deref()
+                    .map(to_resolve_outcome)
+                    .transpose()?,
+            });
+        }
+
+        let mut treasuries = OrdMap::new();
+        let mut statement = self
+            .connection
+            .prepare("SELECT guild_id, balance FROM treasuries")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            treasuries.insert(to_guild(row.get(0)?)?, Money(row.get(1)?));
+        }
+
+        Ok(Economy {
+            next_market_id,
+            user_money,
+            markets,
+            resolved_markets,
+            preferences,
+            api_tokens,
+            watchlists,
+            // Databases from before alerts don't have this yet
+            next_alert_id: self.load_meta("next_alert_id")?.unwrap_or(0),
+            alerts,
+            guild_settings,
+            // Databases from before templates don't have this yet
+            next_template_id: self.load_meta("next_template_id")?.unwrap_or(0),
+            templates,
+            treasuries,
+            default_settings: Settings::default(),
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&mut self) -> Result<Economy> {
+        let economy = self
+            .load_economy()
+            .with_context(|| format!("failed loading economy from {}", self.path.display()))?;
+        self.saved = Some(economy.clone());
+        Ok(economy)
+    }
+
+    fn save(&mut self, economy: &Economy) -> Result<()> {
+        let tx = self.connection.transaction()?;
+        let empty = Economy::new();
+        let saved = self.saved.as_ref().unwrap_or(&empty);
+
+        if self.saved.is_none() || saved.next_market_id != economy.next_market_id {
+            tx.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('next_market_id', ?1)",
+                [economy.next_market_id],
+            )?;
+        }
+        if self.saved.is_none() || saved.next_alert_id != economy.next_alert_id {
+            tx.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('next_alert_id', ?1)",
+                [economy.next_alert_id],
+            )?;
+        }
+        if self.saved.is_none() || saved.next_template_id != economy.next_template_id {
+            tx.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('next_template_id', ?1)",
+                [economy.next_template_id],
+            )?;
+        }
+
+        for item in saved.user_money.diff(&economy.user_money) {
+            match item {
+                DiffItem::Add(user, money)
+                | DiffItem::Update {
+                    new: (user, money), ..
+                } => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO users (user_id, balance) VALUES (?1, ?2)",
+                        params![from_user(*user), money.0],
+                    )?;
+                }
+                DiffItem::Remove(user, _) => {
+                    tx.execute("DELETE FROM users WHERE user_id = ?1", [from_user(*user)])?;
+                }
+            }
+        }
+
+        for item in saved.markets.diff(&economy.markets) {
+            match item {
+                DiffItem::Add(_, market) => {
+                    write_market(&tx, market)?;
+                    write_positions(&tx, market.id, &OrdMap::new(), &market.num_user_shares)?;
+                    write_transactions(&tx, market.id, 0, &market.transaction_history)?;
+                    write_subsidies(&tx, market.id, 0, &market.subsidies)?;
+                }
+                DiffItem::Update {
+                    old: (_, old),
+                    new: (_, new),
+                } => {
+                    write_market(&tx, new)?;
+                    write_positions(&tx, new.id, &old.num_user_shares, &new.num_user_shares)?;
+                    // Transaction history is append-only, so only the new tail needs writing
+                    let old_len = old.transaction_history.len();
+                    if new.transaction_history.get(..old_len) == Some(&old.transaction_history) {
+                        write_transactions(
+                            &tx,
+                            new.id,
+                            old_len,
+                            &new.transaction_history[old_len..],
+                        )?;
+                    } else {
+                        tx.execute("DELETE FROM transactions WHERE market_id = ?1", [new.id])?;
+                        write_transactions(&tx, new.id, 0, &new.transaction_history)?;
+                    }
+                    // So are subsidies
+                    let old_len = old.subsidies.len();
+                    if new.subsidies.get(..old_len) == Some(&old.subsidies) {
+                        write_subsidies(&tx, new.id, old_len, &new.subsidies[old_len..])?;
+                    } else {
+                        tx.execute("DELETE FROM subsidies WHERE market_id = ?1", [new.id])?;
+                        write_subsidies(&tx, new.id, 0, &new.subsidies)?;
+                    }
+                }
+                DiffItem::Remove(market_id, _) => {
+                    for table in ["markets", "positions", "transactions", "subsidies"] {
+                        tx.execute(
+                            &format!("DELETE FROM {table} WHERE market_id = ?1"),
+                            [market_id],
+                        )?;
+                    }
+                }
+            }
+        }
+
+        // Removing a resolved market's row above deleted its trades too, so they're written again
+        for item in saved.resolved_markets.diff(&economy.resolved_markets) {
+            match item {
+                DiffItem::Add(_, market)
+                | DiffItem::Update {
+                    new: (_, market), ..
+                } => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO resolved_markets (market_id, creator, question, \
+                         description, close_timestamp, creation_time, probability, outcome) \
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                        params![
+                            market.id,
+                            from_user(market.creator),
+                            market.question,
+                            market.description,
+                            market.close_timestamp,
+                            market.creation_time,
+                            market.probability,
+                            market.outcome.to_string()
+                        ],
+                    )?;
+                    tx.execute("DELETE FROM transactions WHERE market_id = ?1", [market.id])?;
+                    write_transactions(&tx, market.id, 0, &market.transaction_history)?;
+                }
+                DiffItem::Remove(market_id, _) => {
+                    for table in ["resolved_markets", "transactions"] {
+                        tx.execute(
+                            &format!("DELETE FROM {table} WHERE market_id = ?1"),
+                            [market_id],
+                        )?;
+                    }
+                }
+            }
+        }
+
+        for item in saved.preferences.diff(&economy.preferences) {
+            match item {
+                DiffItem::Add(user, preferences)
+                | DiffItem::Update {
+                    new: (user, preferences),
+                    ..
+                } => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO preferences (user_id, time_zone, date_dialect, \
+                         resolution_dms, watchlist_dms, decimal_places) \
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        params![
+                            from_user(*user),
+                            preferences.time_zone,
+                            from_date_dialect(preferences.date_dialect),
+                            preferences.resolution_dms,
+                            preferences.watchlist_dms,
+                            preferences.decimal_places,
+                        ],
+                    )?;
+                }
+                DiffItem::Remove(user, _) => {
+                    tx.execute(
+                        "DELETE FROM preferences WHERE user_id = ?1",
+                        [from_user(*user)],
+                    )?;
+                }
+            }
+        }
+
+        for item in saved.api_tokens.diff(&economy.api_tokens) {
+            match item {
+                DiffItem::Add(user, token)
+                | DiffItem::Update {
+                    new: (user, token), ..
+                } => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO api_tokens (user_id, hash, created) \
+                         VALUES (?1, ?2, ?3)",
+                        params![from_user(*user), token.hash, token.created],
+                    )?;
+                }
+                DiffItem::Remove(user, _) => {
+                    tx.execute(
+                        "DELETE FROM api_tokens WHERE user_id = ?1",
+                        [from_user(*user)],
+                    )?;
+                }
+            }
+        }
+
+        for item in saved.alerts.diff(&economy.alerts) {
+            match item {
+                DiffItem::Add(_, alert)
+                | DiffItem::Update {
+                    new: (_, alert), ..
+                } => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO alerts (alert_id, user_id, market_id, direction, \
+                         threshold, channel) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        params![
+                            alert.id,
+                            from_user(alert.user),
+                            alert.market_id,
+                            alert.direction.to_string(),
+                            alert.threshold,
+                            alert.channel.map(from_channel),
+                        ],
+                    )?;
+                }
+                DiffItem::Remove(alert_id, _) => {
+                    tx.execute("DELETE FROM alerts WHERE alert_id = ?1", [alert_id])?;
+                }
+            }
+        }
+
+        for item in saved.guild_settings.diff(&economy.guild_settings) {
+            match item {
+                DiffItem::Add(guild, settings)
+                | DiffItem::Update {
+                    new: (guild, settings),
+                    ..
+                } => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO guild_settings (guild_id, start_balance, \
+                         market_creation_cost, default_time_zone, big_move_points) \
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![
+                            from_guild(*guild),
+                            settings.start_balance.0,
+                            settings.market_creation_cost.0,
+                            settings.default_time_zone,
+                            settings.big_move_points,
+                        ],
+                    )?;
+                }
+                DiffItem::Remove(guild, _) => {
+                    tx.execute(
+                        "DELETE FROM guild_settings WHERE guild_id = ?1",
+                        [from_guild(*guild)],
+                    )?;
+                }
+            }
+        }
+
+        for item in saved.templates.diff(&economy.templates) {
+            match item {
+                DiffItem::Add(_, template) => {
+                    write_template(&tx, template)?;
+                    write_template_instances(&tx, template.id, &[], &template.instances)?;
+                }
+                DiffItem::Update {
+                    old: (_, old),
+                    new: (_, new),
+                } => {
+                    write_template(&tx, new)?;
+                    write_template_instances(&tx, new.id, &old.instances, &new.instances)?;
+                }
+                DiffItem::Remove(template_id, _) => {
+                    for table in ["templates", "template_instances"] {
+                        tx.execute(
+                            &format!("DELETE FROM {table} WHERE template_id = ?1"),
+                            [template_id],
+                        )?;
+                    }
+                }
+            }
+        }
+
+        for item in saved.treasuries.diff(&economy.treasuries) {
+            match item {
+                DiffItem::Add(guild, balance)
+                | DiffItem::Update {
+                    new: (guild, balance),
+                    ..
+                } => {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO treasuries (guild_id, balance) VALUES (?1, ?2)",
+                        params![from_guild(*guild), balance.0],
+                    )?;
+                }
+                DiffItem::Remove(guild, _) => {
+                    tx.execute(
+                        "DELETE FROM treasuries WHERE guild_id = ?1",
+                        [from_guild(*guild)],
+                    )?;
+                }
+            }
+        }
+
+        for item in saved.watchlists.diff(&economy.watchlists) {
+            let (user, old, new) = match item {
+                DiffItem::Add(user, new) => (user, None, Some(new)),
+                DiffItem::Update {
+                    old: (user, old),
+                    new: (_, new),
+                } => (user, Some(old), Some(new)),
+                DiffItem::Remove(user, old) => (user, Some(old), None),
+            };
+            write_watchlist(
+                &tx,
+                *user,
+                old.unwrap_or(&OrdSet::new()),
+                new.unwrap_or(&OrdSet::new()),
+            )?;
+        }
+
+        tx.commit()
+            .with_context(|| format!("failed committing to {}", self.path.display()))?;
+        self.saved = Some(economy.clone());
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    ensure!(
+        version <= MIGRATIONS.len(),
+        "database has schema version {version}, but this bot only understands up to version {}",
+        MIGRATIONS.len()
+    );
+    for (from_version, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("failed migrating from version {from_version}"))?;
+        tx.pragma_update(None, "user_version", from_version + 1)?;
+        tx.commit()?;
+        tracing::info!(
+            "migrated database schema from version {from_version} to {}",
+            from_version + 1
+        );
+    }
+    Ok(())
+}
+
+fn write_market(tx: &Transaction, market: &Market<UserId>) -> Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO markets (market_id, creator, question, description, yes_pool, \
+         no_pool, close_timestamp, creation_time, channel, last_close_notice, guild, \
+         creation_cost, treasury) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        params![
+            market.id,
+            from_user(market.creator),
+            market.question,
+            market.description,
+            market.y.0,
+            market.n.0,
+            market.close_timestamp,
+            market.creation_time,
+            market.channel.map(from_channel),
+            market.last_close_notice,
+            market.guild.map(from_guild),
+            market.creation_cost.0,
+            market.treasury.map(from_guild),
+        ],
+    )?;
+    Ok(())
+}
+
+fn write_positions(
+    tx: &Transaction,
+    market_id: MarketId,
+    old: &OrdMap<UserId, ShareKindAndQuantity>,
+    new: &OrdMap<UserId, ShareKindAndQuantity>,
+) -> Result<()> {
+    for item in old.diff(new) {
+        match item {
+            DiffItem::Add(user, shares)
+            | DiffItem::Update {
+                new: (user, shares),
+                ..
+            } => {
+                tx.execute(
+                    "INSERT OR REPLACE INTO positions (market_id, user_id, share_kind, quantity) \
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        market_id,
+                        from_user(*user),
+                        shares.kind.to_string(),
+                        shares.quantity.0
+                    ],
+                )?;
+            }
+            DiffItem::Remove(user, _) => {
+                tx.execute(
+                    "DELETE FROM positions WHERE market_id = ?1 AND user_id = ?2",
+                    params![market_id, from_user(*user)],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_transactions(
+    tx: &Transaction,
+    market_id: MarketId,
+    first_seq: usize,
+    transactions: &[TransactionInfo<UserId>],
+) -> Result<()> {
+    let mut statement = tx.prepare_cached(
+        "INSERT INTO transactions (market_id, seq, user_id, kind, share_kind, quantity, money, \
+         new_probability, time) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+    )?;
+    for (seq, transaction) in (first_seq..).zip(transactions) {
+        statement.execute(params![
+            market_id,
+            seq,
+            from_user(transaction.user),
+            transaction.kind.to_string(),
+            transaction.shares.kind.to_string(),
+            transaction.shares.quantity.0,
+            transaction.money.0,
+            transaction.new_probability,
+            transaction.time,
+        ])?;
+    }
+    Ok(())
+}
+
+fn write_subsidies(
+    tx: &Transaction,
+    market_id: MarketId,
+    first_seq: usize,
+    subsidies: &[Subsidy<UserId>],
+) -> Result<()> {
+    let mut statement = tx.prepare_cached(
+        "INSERT INTO subsidies (market_id, seq, user_id, money, leftover_kind, \
+         leftover_quantity, time) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    for (seq, subsidy) in (first_seq..).zip(subsidies) {
+        statement.execute(params![
+            market_id,
+            seq,
+            from_user(subsidy.user),
+            subsidy.money.0,
+            subsidy.leftover.kind.to_string(),
+            subsidy.leftover.quantity.0,
+            subsidy.time,
+        ])?;
+    }
+    Ok(())
+}
+
+fn write_template(tx: &Transaction, template: &Template<UserId>) -> Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO templates (template_id, creator, question, description, \
+         recurrence, time_zone, payer, next_open, next_close, channel, guild) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            template.id,
+            from_user(template.creator),
+            template.question,
+            template.description,
+            template.recurrence.to_string(),
+            template.time_zone,
+            from_template_payer(template.payer),
+            template.next_open,
+            template.next_close,
+            from_channel(template.channel),
+            from_guild(template.guild),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Instances are only ever appended or given an outcome, so rows from the first one that changed
+/// onwards are rewritten
+fn write_template_instances(
+    tx: &Transaction,
+    template_id: TemplateId,
+    old: &[TemplateInstance],
+    new: &[TemplateInstance],
+) -> Result<()> {
+    let unchanged = old
+        .iter()
+        .zip(new)
+        .take_while(|(old, new)| old == new)
+        .count();
+    if unchanged < old.len() {
+        tx.execute(
+            "DELETE FROM template_instances WHERE template_id = ?1 AND seq >= ?2",
+            params![template_id, unchanged],
+        )?;
+    }
+    let mut statement = tx.prepare_cached(
+        "INSERT INTO template_instances (template_id, seq, market_id, question, close_time, \
+         outcome) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for (seq, instance) in new.iter().enumerate().skip(unchanged) {
+        statement.execute(params![
+            template_id,
+            seq,
+            instance.market_id,
+            instance.question,
+            instance.close_time,
+            instance.outcome.map(|outcome| outcome.to_string()),
+        ])?;
+    }
+    Ok(())
+}
+
+fn write_watchlist(
+    tx: &Transaction,
+    user: UserId,
+    old: &OrdSet<MarketId>,
+    new: &OrdSet<MarketId>,
+) -> Result<()> {
+    for item in old.diff(new) {
+        match item {
+            ordset::DiffItem::Add(market_id) | ordset::DiffItem::Update { new: market_id, .. } => {
+                tx.execute(
+                    "INSERT OR REPLACE INTO watchlists (user_id, market_id) VALUES (?1, ?2)",
+                    params![from_user(user), market_id],
+                )?;
+            }
+            ordset::DiffItem::Remove(market_id) => {
+                tx.execute(
+                    "DELETE FROM watchlists WHERE user_id = ?1 AND market_id = ?2",
+                    params![from_user(user), market_id],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn from_user(user: UserId) -> i64 {
+    user.get() as i64
+}
+
+fn to_user(id: i64) -> Result<UserId> {
+    ensure!(id > 0, "invalid user ID {id}");
+    Ok(UserId::new(id as u64))
+}
+
+fn from_channel(channel: ChannelId) -> i64 {
+    channel.get() as i64
+}
+
+fn to_channel(id: i64) -> Result<ChannelId> {
+    ensure!(id > 0, "invalid channel ID {id}");
+    Ok(ChannelId::new(id as u64))
+}
+
+fn from_guild(guild: GuildId) -> i64 {
+    guild.get() as i64
+}
+
+fn to_guild(id: i64) -> Result<GuildId> {
+    ensure!(id > 0, "invalid guild ID {id}");
+    Ok(GuildId::new(id as u64))
+}
+
+fn to_share_kind(s: &str) -> Result<ShareKind> {
+    match s {
+        "YES" => Ok(ShareKind::Yes),
+        "NO" => Ok(ShareKind::No),
+        _ => bail!("invalid share kind {s:?}"),
+    }
+}
+
+fn to_alert_direction(s: &str) -> Result<AlertDirection> {
+    match s {
+        "above" => Ok(AlertDirection::Above),
+        "below" => Ok(AlertDirection::Below),
+        _ => bail!("invalid alert direction {s:?}"),
+    }
+}
+
+fn to_recurrence(s: &str) -> Result<Recurrence> {
+    match s {
+        "daily" => Ok(Recurrence::Daily),
+        "weekly" => Ok(Recurrence::Weekly),
+        "monthly" => Ok(Recurrence::Monthly),
+        _ => bail!("invalid recurrence {s:?}"),
+    }
+}
+
+fn from_template_payer(payer: TemplatePayer) -> &'static str {
+    match payer {
+        TemplatePayer::Creator => "creator",
+        TemplatePayer::Treasury => "treasury",
+    }
+}
+
+fn to_template_payer(s: &str) -> Result<TemplatePayer> {
+    match s {
+        "creator" => Ok(TemplatePayer::Creator),
+        "treasury" => Ok(TemplatePayer::Treasury),
+        _ => bail!("invalid template payer {s:?}"),
+    }
+}
+
+fn to_resolve_outcome(s: &str) -> Result<ResolveOutcome> {
+    match s {
+        "YES" => Ok(ResolveOutcome::Yes),
+        "NO" => Ok(ResolveOutcome::No),
+        "UNDO" => Ok(ResolveOutcome::Undo),
+        _ => bail!("invalid outcome {s:?}"),
+    }
+}
+
+fn from_date_dialect(dialect: DateDialect) -> &'static str {
+    match dialect {
+        DateDialect::Us => "us",
+        DateDialect::Uk => "uk",
+    }
+}
+
+fn to_date_dialect(s: &str) -> Result<DateDialect> {
+    match s {
+        "us" => Ok(DateDialect::Us),
+        "uk" => Ok(DateDialect::Uk),
+        _ => bail!("invalid date dialect {s:?}"),
+    }
+}
+
+fn to_transaction_kind(s: &str) -> Result<TransactionKind> {
+    match s {
+        "BUY" => Ok(TransactionKind::Buy),
+        "SELL" => Ok(TransactionKind::Sell),
+        _ => bail!("invalid transaction kind {s:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prediction_market::ResolveOutcome;
+
+    const ALICE: UserId = UserId::new(1);
+    const BOB: UserId = UserId::new(2);
+    const GUILD: GuildId = GuildId::new(3);
+
+    fn json(economy: &Economy) -> serde_json::Value {
+        serde_json::to_value(economy).unwrap()
+    }
+
+    /// Save `economy` through `storage`, then check a fresh connection loads the same economy
+    fn save_and_reload(storage: &mut SqliteStorage, economy: &Economy) {
+        storage.save(economy).unwrap();
+        let loaded = SqliteStorage::open(&storage.path).unwrap().load().unwrap();
+        assert_eq!(json(&loaded), json(economy));
+    }
+
+    fn market(economy: &Economy, question: &str) -> (Economy, MarketId) {
+        economy
+            .create_market(
+                ALICE,
+                question.into(),
+                "Description".into(),
+                Some(2_000_000_000),
+                0.3,
+                Money(0.0),
+                Some(ChannelId::new(4)),
+                Some(GUILD),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trips_every_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = SqliteStorage::open(dir.path().join("state.sqlite")).unwrap();
+        assert_eq!(json(&storage.load().unwrap()), json(&Economy::new()));
+
+        let (economy, first) = market(&Economy::new(), "First?");
+        let (economy, second) = market(&economy, "Second?");
+        let (economy, _) = economy
+            .buy(BOB, first, Money(20.0), ShareKind::Yes)
+            .unwrap();
+        let economy = economy.watch(BOB, second).unwrap();
+        let (economy, _) = economy
+            .create_alert(BOB, second, AlertDirection::Above, 60, None)
+            .unwrap();
+        let economy = economy
+            .set_preferences(
+                BOB,
+                Preferences {
+                    time_zone: Some("Europe/London".into()),
+                    decimal_places: 4,
+                    ..Preferences::default()
+                },
+            )
+            .unwrap();
+        let economy = economy.set_api_token(
+            BOB,
+            ApiToken {
+                hash: "abc".into(),
+                created: Utc::now(),
+            },
+        );
+        let economy = economy
+            .set_settings(
+                GUILD,
+                Settings {
+                    big_move_points: 5,
+                    ..Settings::default()
+                },
+            )
+            .unwrap();
+        save_and_reload(&mut storage, &economy);
+
+        // Updates append to the transaction history and change positions in place
+        let (economy, _) = economy.buy(BOB, first, Money(5.0), ShareKind::Yes).unwrap();
+        let (economy, _, _) = economy.sell(BOB, first, None).unwrap();
+        let (economy, _) = economy
+            .buy(ALICE, second, Money(5.0), ShareKind::No)
+            .unwrap();
+        save_and_reload(&mut storage, &economy);
+
+        let economy = economy.subsidize(BOB, first, Money(25.0)).unwrap();
+        let economy = economy.subsidize(ALICE, first, Money(10.0)).unwrap();
+        save_and_reload(&mut storage, &economy);
+
+        // Resolving removes the market along with its watchers and alerts, but keeps its trades
+        let (economy, _) = economy
+            .resolve_market(ALICE, second, ResolveOutcome::Yes)
+            .unwrap();
+        let economy = economy.revoke_api_token(&BOB).unwrap();
+        save_and_reload(&mut storage, &economy);
+
+        // Including those of markets that resolved before they were ever saved
+        let (economy, third) = market(&economy, "Third?");
+        let (economy, _) = economy.buy(BOB, third, Money(5.0), ShareKind::No).unwrap();
+        let (economy, _) = economy
+            .resolve_market(ALICE, third, ResolveOutcome::Undo)
+            .unwrap();
+        save_and_reload(&mut storage, &economy);
+        assert_eq!(economy.list_resolved_markets().count(), 2);
+    }
+
+    #[test]
+    fn round_trips_templates_and_treasuries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = SqliteStorage::open(dir.path().join("state.sqlite")).unwrap();
+        let first_close = Utc::now() + chrono::Duration::days(3);
+        let economy = Economy::new()
+            .deposit_to_treasury(ALICE, GUILD, Money(200.0))
+            .unwrap();
+        let (economy, weekly) = economy
+            .create_template(
+                ALICE,
+                "Rain on {date}?".into(),
+                "Number {number}".into(),
+                Recurrence::Weekly,
+                "Europe/London".into(),
+                TemplatePayer::Treasury,
+                first_close,
+                ChannelId::new(4),
+                GUILD,
+            )
+            .unwrap();
+        let (economy, monthly) = economy
+            .create_template(
+                BOB,
+                "Snow?".into(),
+                String::new(),
+                Recurrence::Monthly,
+                "US/Eastern".into(),
+                TemplatePayer::Creator,
+                first_close,
+                ChannelId::new(4),
+                GUILD,
+            )
+            .unwrap();
+        save_and_reload(&mut storage, &economy);
+
+        // New instances are appended, and resolving one rewrites it
+        let (economy, first) = economy.create_template_market(weekly, first_close).unwrap();
+        let (economy, second) = economy
+            .create_template_market(weekly, first_close + chrono::Duration::weeks(1))
+            .unwrap();
+        let (economy, _) = economy
+            .create_template_market(monthly, first_close)
+            .unwrap();
+        save_and_reload(&mut storage, &economy);
+        let (economy, _) = economy
+            .resolve_market(ALICE, first.unwrap(), ResolveOutcome::Yes)
+            .unwrap();
+        let (economy, _) = economy
+            .resolve_market(ALICE, second.unwrap(), ResolveOutcome::Undo)
+            .unwrap();
+        let economy = economy
+            .withdraw_from_treasury(BOB, GUILD, Money(30.0))
+            .unwrap();
+        save_and_reload(&mut storage, &economy);
+
+        let economy = economy.delete_template(BOB, monthly, false).unwrap();
+        save_and_reload(&mut storage, &economy);
+        assert_eq!(economy.list_templates().count(), 1);
+    }
+
+    #[test]
+    fn saves_diffs_against_what_it_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.sqlite");
+        let (economy, market_id) = market(&Economy::new(), "Question?");
+        SqliteStorage::open(&path).unwrap().save(&economy).unwrap();
+
+        let mut storage = SqliteStorage::open(&path).unwrap();
+        let economy = storage.load().unwrap();
+        let (economy, _) = economy
+            .buy(BOB, market_id, Money(10.0), ShareKind::Yes)
+            .unwrap();
+        save_and_reload(&mut storage, &economy);
+    }
+
+    #[test]
+    fn import_refuses_a_database_with_an_economy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = SqliteStorage::open(dir.path().join("state.sqlite")).unwrap();
+        let (economy, _) = market(&Economy::new(), "Question?");
+        storage.import(&economy).unwrap();
+        assert!(storage.import(&economy).is_err());
+    }
+
+    #[test]
+    fn refuses_newer_schema_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.sqlite");
+        Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(SqliteStorage::open(&path).is_err());
+    }
+}